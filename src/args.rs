@@ -0,0 +1,427 @@
+use std::path::PathBuf;
+
+use crate::gandi_client::parse_ttl;
+use crate::ipv6_prefix::Ipv6Prefix;
+use crate::item::ascii_name;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Check and update the dynamic items, the default.
+    Update,
+    // Update, and update again whenever an address or the default route of
+    // the host changes, on Linux.
+    Watch,
+    // Exit with 0 if the last successful run is recent enough, 1 otherwise.
+    Healthcheck,
+    // Rewrite all AAAA records of the zone within the old prefix to the new
+    // prefix, keeping the host suffixes.
+    RewritePrefix {
+        old: Ipv6Prefix,
+        new: Ipv6Prefix,
+    },
+    // Stop updating the given dynamic items until they are resumed.
+    Pause {
+        items: Vec<String>,
+    },
+    Resume {
+        items: Vec<String>,
+    },
+    // Show the last successful run and paused items.
+    Status,
+    // Restore a local backup, optionally restricted to some rrsets given as
+    // `NAME` or `NAME/TYPE`.
+    Restore {
+        file: PathBuf,
+        selection: Vec<String>,
+    },
+    // Restore a snapshot taken by Gandi, optionally restricted to some
+    // rrsets given as `NAME` or `NAME/TYPE`.
+    RestoreSnapshot {
+        id: String,
+        selection: Vec<String>,
+    },
+    // Write the records of the zone to a BIND-style zone file.
+    Export {
+        file: PathBuf,
+    },
+    // Create and update rrsets from a BIND-style zone file, optionally
+    // restricted to some rrsets given as `NAME` or `NAME/TYPE`.
+    Import {
+        file: PathBuf,
+        selection: Vec<String>,
+    },
+    // Print the domains the credential has access to.
+    Domains,
+    // Set the TTL of the rrsets of the dynamic items, keeping their values.
+    SetTtl {
+        ttl: u64,
+    },
+    // Print the rrsets of the zones, optionally restricted to some rrsets
+    // given as `NAME` or `NAME/TYPE`.
+    List {
+        selection: Vec<String>,
+    },
+    // Delete rrsets given as `NAME` or `NAME/TYPE`, for example of
+    // decommissioned hosts.
+    Delete {
+        selection: Vec<String>,
+    },
+}
+
+// Not derived, `#[default]` on enum variants needs a newer toolchain.
+#[allow(clippy::derivable_impls)]
+impl Default for Command {
+    fn default() -> Self {
+        Command::Update
+    }
+}
+
+impl Command {
+    fn from_parts(name: &str, operands: &[String]) -> Result<Self, String> {
+        let (command, arity) = match name {
+            "update" => (Command::Update, 0),
+            "watch" => (Command::Watch, 0),
+            "healthcheck" => (Command::Healthcheck, 0),
+            "status" => (Command::Status, 0),
+            "domains" => (Command::Domains, 0),
+            "restore" => {
+                let (file, selection) = operands
+                    .split_first()
+                    .ok_or("restore expects a BACKUP_FILE")?;
+                let command = Command::Restore {
+                    file: PathBuf::from(file),
+                    selection: selection.to_vec(),
+                };
+                (command, operands.len())
+            }
+            "set-ttl" => {
+                let ttl = operands.first().ok_or("set-ttl expects SECONDS")?;
+                (
+                    Command::SetTtl {
+                        ttl: parse_ttl(ttl)?,
+                    },
+                    1,
+                )
+            }
+            "list" => {
+                let command = Command::List {
+                    selection: operands.to_vec(),
+                };
+                (command, operands.len())
+            }
+            "delete" => {
+                if operands.is_empty() {
+                    return Err("delete expects at least one NAME".into());
+                }
+                let command = Command::Delete {
+                    selection: operands.to_vec(),
+                };
+                (command, operands.len())
+            }
+            "export" => {
+                let file = operands.first().ok_or("export expects a ZONE_FILE")?;
+                let command = Command::Export {
+                    file: PathBuf::from(file),
+                };
+                (command, 1)
+            }
+            "import" => {
+                let (file, selection) =
+                    operands.split_first().ok_or("import expects a ZONE_FILE")?;
+                let command = Command::Import {
+                    file: PathBuf::from(file),
+                    selection: selection.to_vec(),
+                };
+                (command, operands.len())
+            }
+            "restore-snapshot" => {
+                let (id, selection) = operands
+                    .split_first()
+                    .ok_or("restore-snapshot expects a SNAPSHOT_ID")?;
+                let command = Command::RestoreSnapshot {
+                    id: id.clone(),
+                    selection: selection.to_vec(),
+                };
+                (command, operands.len())
+            }
+            "pause" | "resume" => {
+                let items: Vec<String> = operands.iter().flat_map(|o| split_items(o)).collect();
+                if items.is_empty() {
+                    return Err(format!("{} expects at least one ITEM", name));
+                }
+                let command = if name == "pause" {
+                    Command::Pause { items }
+                } else {
+                    Command::Resume { items }
+                };
+                (command, operands.len())
+            }
+            "rewrite-prefix" => {
+                if operands.len() != 2 {
+                    return Err("rewrite-prefix expects OLD_PREFIX and NEW_PREFIX".into());
+                }
+                let old: Ipv6Prefix = operands[0].parse()?;
+                let new: Ipv6Prefix = operands[1].parse()?;
+                if old.prefix_len() != new.prefix_len() {
+                    return Err(format!("Prefixes {} and {} differ in length", old, new));
+                }
+                (Command::RewritePrefix { old, new }, 2)
+            }
+            _ => return Err(format!("Unknown command '{}'", name)),
+        };
+        if let Some(operand) = operands.get(arity) {
+            return Err(format!("Unexpected argument '{}'", operand));
+        }
+        Ok(command)
+    }
+}
+
+// Command line arguments. Configuration is read from the environment,
+// arguments only adjust what a single run does.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    // Only process these dynamic items, if not empty.
+    pub only: Vec<String>,
+    // Do not process these dynamic items.
+    pub skip: Vec<String>,
+    // Use the Gandi sandbox environment instead of production.
+    pub sandbox: bool,
+    // Apply changes of commands which otherwise only show a plan.
+    pub yes: bool,
+    // Replace address rrsets even if they have several values.
+    pub force_replace: bool,
+    // Run from a network hook, with the hook's arguments instead of a
+    // command.
+    pub dispatcher: Option<Vec<String>>,
+}
+
+pub static USAGE: &str =
+    "Usage: gandi-dns-update [--sandbox] [--yes] [--force-replace] [--only ITEM,...] [--skip ITEM,...] [COMMAND]
+       gandi-dns-update --dispatcher [INTERFACE ACTION]
+
+Commands:
+    update          Check and update the dynamic items (default)
+    watch           Update, and again whenever the addresses of the host change
+    healthcheck     Exit with 1 if the last successful run is too long ago
+    status          Show the last successful run and paused items
+    pause ITEM...   Stop updating the given items until resumed
+    resume ITEM...  Resume updating the given items
+    restore BACKUP_FILE [NAME[/TYPE]...]
+                    Show how to restore a backup, apply it with --yes
+    restore-snapshot SNAPSHOT_ID [NAME[/TYPE]...]
+                    Show how to restore a Gandi snapshot, apply it with --yes
+    export ZONE_FILE
+                    Write the records of the zone to a BIND-style zone file
+    import ZONE_FILE [NAME[/TYPE]...]
+                    Show how to import a zone file, apply it with --yes
+    domains         Print the domains the Gandi credential has access to
+    list [NAME[/TYPE]...]
+                    Print the records of the zones as known to Gandi
+    delete NAME[/TYPE]...
+                    Show which rrsets would be deleted, delete them with --yes
+    rewrite-prefix OLD_PREFIX NEW_PREFIX
+                    Move all AAAA records within OLD_PREFIX to NEW_PREFIX
+    set-ttl SECONDS Show how to set the TTL of the items, apply it with --yes";
+
+// Item names are compared in the IDNA encoded form of `DynamicItem`, items
+// of other zones than the first without trailing dot.
+fn split_items(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().trim_end_matches('.'))
+        .filter(|s| !s.is_empty())
+        .map(|s| ascii_name(s).unwrap_or_else(|_| s.to_string()))
+        .collect()
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut positional = vec![];
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg);
+                continue;
+            }
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("Missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "--only" => parsed.only.extend(split_items(&value()?)),
+                "--skip" => parsed.skip.extend(split_items(&value()?)),
+                "--sandbox" if inline_value.is_none() => parsed.sandbox = true,
+                "--yes" if inline_value.is_none() => parsed.yes = true,
+                "--force-replace" if inline_value.is_none() => parsed.force_replace = true,
+                "--dispatcher" if inline_value.is_none() => parsed.dispatcher = Some(vec![]),
+                _ => return Err(format!("Unknown argument '{}'", flag)),
+            }
+        }
+        if let Some(hook_args) = &mut parsed.dispatcher {
+            *hook_args = positional;
+        } else if let Some((name, operands)) = positional.split_first() {
+            parsed.command = Command::from_parts(name, operands)?;
+        }
+        Ok(parsed)
+    }
+
+    // Whether the dynamic item with the given name is to be processed.
+    pub fn selects(&self, item: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|i| i == item))
+            && !self.skip.iter().any(|i| i == item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, Command};
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn args_parse_ok() {
+        let actual = parse(&[
+            "--only",
+            "home,vpn",
+            "--sandbox",
+            "--skip=office",
+            "--force-replace",
+        ])
+        .unwrap();
+        let expected = Args {
+            command: Command::Update,
+            only: vec!["home".into(), "vpn".into()],
+            skip: vec!["office".into()],
+            sandbox: true,
+            yes: false,
+            force_replace: true,
+            dispatcher: None,
+        };
+        assert_eq!(expected, actual);
+        assert!(parse(&["--only"]).is_err());
+        assert!(parse(&["--foo"]).is_err());
+        assert!(parse(&["--sandbox=yes"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_healthcheck() {
+        let actual = parse(&["healthcheck"]).unwrap();
+        assert_eq!(Command::Healthcheck, actual.command);
+        assert!(parse(&["healthcheck", "now"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_rewrite_prefix() {
+        let actual = parse(&["rewrite-prefix", "2001:db8:1::/48", "2001:db8:2::/48"]).unwrap();
+        let expected = Command::RewritePrefix {
+            old: "2001:db8:1::/48".parse().unwrap(),
+            new: "2001:db8:2::/48".parse().unwrap(),
+        };
+        assert_eq!(expected, actual.command);
+        assert!(parse(&["rewrite-prefix", "2001:db8:1::/48", "2001:db8:2::/56"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_pause() {
+        let actual = parse(&["pause", "home,vpn", "nas"]).unwrap();
+        let items = vec!["home".into(), "vpn".into(), "nas".into()];
+        assert_eq!(Command::Pause { items }, actual.command);
+        assert!(parse(&["resume"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_restore() {
+        let actual = parse(&["--yes", "restore", "backup.json", "home/A"]).unwrap();
+        let expected = Command::Restore {
+            file: "backup.json".into(),
+            selection: vec!["home/A".into()],
+        };
+        assert_eq!(expected, actual.command);
+        assert!(actual.yes);
+        assert!(parse(&["restore"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_restore_snapshot() {
+        let actual = parse(&["restore-snapshot", "1e7ab8a3"]).unwrap();
+        let expected = Command::RestoreSnapshot {
+            id: "1e7ab8a3".into(),
+            selection: vec![],
+        };
+        assert_eq!(expected, actual.command);
+        assert!(parse(&["restore-snapshot"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_export() {
+        let actual = parse(&["export", "zone.txt"]).unwrap();
+        let expected = Command::Export {
+            file: "zone.txt".into(),
+        };
+        assert_eq!(expected, actual.command);
+        assert!(parse(&["export"]).is_err());
+        assert!(parse(&["export", "a", "b"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_import() {
+        let actual = parse(&["import", "zone.txt", "home"]).unwrap();
+        let expected = Command::Import {
+            file: "zone.txt".into(),
+            selection: vec!["home".into()],
+        };
+        assert_eq!(expected, actual.command);
+        assert!(parse(&["import"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_delete() {
+        let actual = parse(&["delete", "old-nas", "home/AAAA"]).unwrap();
+        let expected = Command::Delete {
+            selection: vec!["old-nas".into(), "home/AAAA".into()],
+        };
+        assert_eq!(expected, actual.command);
+        assert!(parse(&["delete"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_set_ttl() {
+        let actual = parse(&["set-ttl", "3600"]).unwrap();
+        assert_eq!(Command::SetTtl { ttl: 3600 }, actual.command);
+        assert!(parse(&["set-ttl", "60"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_listings() {
+        assert_eq!(Command::Domains, parse(&["domains"]).unwrap().command);
+        let actual = parse(&["list"]).unwrap();
+        let selection = vec![];
+        assert_eq!(Command::List { selection }, actual.command);
+        assert!(parse(&["foo"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_dispatcher() {
+        let actual = parse(&["--dispatcher", "eth0", "up"]).unwrap();
+        assert_eq!(Command::Update, actual.command);
+        assert_eq!(Some(vec!["eth0".into(), "up".into()]), actual.dispatcher);
+    }
+
+    #[test]
+    fn args_select_items() {
+        let args = parse(&["--only", "home,vpn", "--skip", "vpn"]).unwrap();
+        assert!(args.selects("home"));
+        assert!(!args.selects("vpn"));
+        assert!(!args.selects("office"));
+        assert!(Args::default().selects("office"));
+    }
+}
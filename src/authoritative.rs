@@ -0,0 +1,267 @@
+use futures::future::join_all;
+use log::{debug, trace, warn};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+
+use trust_dns_resolver::config::{NameServerConfig, ResolverConfig};
+use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
+use trust_dns_resolver::proto::xfer::DnsRequestOptions;
+
+use crate::fqdn::Fqdn;
+use crate::transport::{DnsTransport, DnssecMode};
+use crate::{
+    dns_lookup, dns_lookup_values, ipv4_of_record, resolver, uncached_resolver_opts, AppError,
+    Resolver,
+};
+
+// Authoritative name servers of the zone, by name and IPv4 address. Name
+// servers whose address cannot be looked up are skipped, as long as at
+// least one remains.
+pub async fn name_servers(
+    bootstrap: &Resolver,
+    zone: &Fqdn,
+) -> Result<Vec<(String, Ipv4Addr)>, AppError> {
+    let lookup = bootstrap
+        .lookup(zone.as_dns(), RecordType::NS, DnsRequestOptions::default())
+        .await?;
+    let mut servers = vec![];
+    for record in lookup.record_iter() {
+        trace!("Domain {} NS record {:?}", zone, record);
+        let name = match record.rdata() {
+            RData::NS(name) => name.to_utf8(),
+            _ => continue,
+        };
+        match dns_lookup(bootstrap, name.clone(), RecordType::A).await {
+            Ok(a) => match ipv4_of_record(&a) {
+                Some(ip) => {
+                    debug!("Domain {} NS {} has IP {}", zone, name, ip);
+                    servers.push((name, ip));
+                }
+                None => warn!("Domain {} NS {} has no IPv4 address", zone, name),
+            },
+            Err(e) => warn!("Cannot look up domain {} NS {}: {}", zone, name, e),
+        }
+    }
+    if servers.is_empty() {
+        return Err(AppError::new(&format!(
+            "No reachable name server found for domain {}",
+            zone
+        )));
+    }
+    Ok(servers)
+}
+
+// The value of the zone in the cache, discovered when first needed, so that
+// the name servers are discovered once per run and shared by all items.
+pub async fn per_zone<'a, T, F, Fut>(
+    cache: &'a mut Vec<(Fqdn, T)>,
+    zone: &Fqdn,
+    discover: F,
+) -> Result<&'a T, AppError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let index = match cache.iter().position(|(z, _)| z == zone) {
+        Some(index) => index,
+        None => {
+            cache.push((zone.clone(), discover().await?));
+            cache.len() - 1
+        }
+    };
+    Ok(&cache[index].1)
+}
+
+// Resolver which queries the authoritative name servers of the zone
+// directly, failing over between them. Over TLS the name servers are
+// expected to present a certificate for their NS name. With DNS-over-HTTPS
+// or DNSSEC validation the bootstrap resolver is used instead, its answers
+// may be cached. Validation needs the chain of trust, which authoritative
+// name servers do not answer for.
+pub async fn zone_resolver(
+    bootstrap: &Resolver,
+    zone: &Fqdn,
+    transport: DnsTransport,
+    dnssec: DnssecMode,
+) -> Result<Resolver, AppError> {
+    if !transport.reaches_authoritative() || dnssec == DnssecMode::Strict {
+        debug!("Querying domain {} through the bootstrap resolver", zone);
+        return Ok(bootstrap.clone());
+    }
+    let servers = name_servers(bootstrap, zone).await?;
+    let config = zone_config(zone, &servers, transport)?;
+    Ok(resolver(config, uncached_resolver_opts())?)
+}
+
+// Configuration of a resolver failing over between the name servers of the
+// zone, in their order.
+fn zone_config(
+    zone: &Fqdn,
+    servers: &[(String, Ipv4Addr)],
+    transport: DnsTransport,
+) -> Result<ResolverConfig, AppError> {
+    let name_servers: Vec<NameServerConfig> = servers
+        .iter()
+        .flat_map(|(name, ip)| transport.name_servers(IpAddr::V4(*ip), name))
+        .collect();
+    let domain = Name::from_ascii(zone.as_dns())
+        .map_err(|e| AppError::new(&format!("Invalid domain {}: {}", zone, e)))?;
+    Ok(ResolverConfig::from_parts(
+        Some(domain),
+        vec![],
+        name_servers,
+    ))
+}
+
+// Resolver querying only the given name server.
+pub fn server_resolver(ns: (&str, Ipv4Addr), transport: DnsTransport) -> Option<Resolver> {
+    let ns_configs = transport.name_servers(IpAddr::V4(ns.1), ns.0);
+    let config = ResolverConfig::from_parts(None, vec![], ns_configs);
+    resolver(config, uncached_resolver_opts()).ok()
+}
+
+// Values of `name` as answered by each name server, sorted. Name servers
+// which do not answer are left out.
+pub async fn answers(
+    servers: &[(String, Ipv4Addr)],
+    transport: DnsTransport,
+    name: &str,
+    rr_type: RecordType,
+) -> Vec<(String, Vec<String>)> {
+    let answers = join_all(servers.iter().map(|(ns, ip)| async move {
+        let resolver = server_resolver((ns, *ip), transport)?;
+        match dns_lookup_values(&resolver, name.into(), rr_type).await {
+            Ok(mut values) => {
+                values.sort();
+                Some((ns.clone(), values))
+            }
+            Err(e) => {
+                warn!("Name server {} did not answer for {}: {}", ns, name, e);
+                None
+            }
+        }
+    }))
+    .await;
+    answers.into_iter().flatten().collect()
+}
+
+// The most common answer, the first one of equally common answers.
+pub fn consensus(answers: &[(String, Vec<String>)]) -> Vec<String> {
+    let count = |values: &Vec<String>| answers.iter().filter(|(_, v)| v == values).count();
+    let mut best: Option<(&Vec<String>, usize)> = None;
+    for (_, values) in answers {
+        let n = count(values);
+        if best.filter(|(_, m)| *m >= n).is_none() {
+            best = Some((values, n));
+        }
+    }
+    best.map(|(values, _)| values.clone()).unwrap_or_default()
+}
+
+// Whether enough name servers answer differently than the sorted `desired`
+// values to update: at least `quorum` of them, or all that answered if
+// fewer did.
+pub fn quorum_disagrees(
+    answers: &[(String, Vec<String>)],
+    desired: &[String],
+    quorum: usize,
+) -> bool {
+    let disagreeing = answers.iter().filter(|(_, v)| v != desired).count();
+    disagreeing > 0 && disagreeing >= quorum.min(answers.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{consensus, per_zone, quorum_disagrees, zone_config};
+    use crate::fqdn::Fqdn;
+    use crate::transport::DnsTransport;
+    use crate::AppError;
+    use std::cell::Cell;
+    use std::net::SocketAddr;
+    use trust_dns_resolver::config::Protocol;
+
+    fn answers(values: &[&str]) -> Vec<(String, Vec<String>)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("ns{}.example.", i), vec![v.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn quorum_of_answers() {
+        let lagging = answers(&["192.0.2.2", "192.0.2.1", "192.0.2.2"]);
+        assert_eq!(vec!["192.0.2.2".to_string()], consensus(&lagging));
+        let desired = vec!["192.0.2.2".to_string()];
+        assert!(quorum_disagrees(&lagging, &desired, 1));
+        assert!(!quorum_disagrees(&lagging, &desired, 2));
+        let stale = answers(&["192.0.2.1", "192.0.2.1"]);
+        assert!(quorum_disagrees(&stale, &desired, 3));
+        assert!(!quorum_disagrees(&answers(&["192.0.2.2"]), &desired, 1));
+        assert!(consensus(&[]).is_empty());
+    }
+
+    #[test]
+    fn zone_config_fails_over_between_name_servers() {
+        let zone: Fqdn = "example.com".parse().unwrap();
+        let servers = vec![
+            ("ns1.example.net.".to_string(), "192.0.2.1".parse().unwrap()),
+            ("ns2.example.net.".to_string(), "192.0.2.2".parse().unwrap()),
+        ];
+        let config = zone_config(&zone, &servers, DnsTransport::Udp).unwrap();
+        let name_servers: Vec<(Protocol, SocketAddr)> = config
+            .name_servers()
+            .iter()
+            .map(|n| (n.protocol, n.socket_addr))
+            .collect();
+        let ns1: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let ns2: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        assert_eq!(
+            vec![
+                (Protocol::Udp, ns1),
+                (Protocol::Tcp, ns1),
+                (Protocol::Udp, ns2),
+                (Protocol::Tcp, ns2)
+            ],
+            name_servers
+        );
+        assert_eq!("example.com.", config.domain().unwrap().to_string());
+
+        let config = zone_config(&zone, &servers[1..], DnsTransport::Tls).unwrap();
+        let tls = &config.name_servers()[0];
+        assert_eq!(Some("ns2.example.net".to_string()), tls.tls_dns_name);
+        assert_eq!(853, tls.socket_addr.port());
+    }
+
+    #[tokio::test]
+    async fn per_zone_discovers_once() {
+        let discoveries = Cell::new(0);
+        let discover = |value: &'static str| {
+            discoveries.set(discoveries.get() + 1);
+            async move { Ok::<_, AppError>(value) }
+        };
+        let com: Fqdn = "example.com".parse().unwrap();
+        let net: Fqdn = "example.net".parse().unwrap();
+        let mut cache = vec![];
+        assert_eq!(
+            &"a",
+            per_zone(&mut cache, &com, || discover("a")).await.unwrap()
+        );
+        assert_eq!(
+            &"a",
+            per_zone(&mut cache, &com, || discover("b")).await.unwrap()
+        );
+        assert_eq!(
+            &"c",
+            per_zone(&mut cache, &net, || discover("c")).await.unwrap()
+        );
+        assert_eq!(2, discoveries.get());
+
+        let org: Fqdn = "example.org".parse().unwrap();
+        let failed = per_zone(&mut cache, &org, || async {
+            Err::<&str, _>(AppError::new("No reachable name server"))
+        });
+        assert!(failed.await.is_err());
+        assert_eq!(2, cache.len());
+    }
+}
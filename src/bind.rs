@@ -0,0 +1,95 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use if_addrs::get_if_addrs;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::AppError;
+
+// The address or interface queries of the IP sources leave from, so that
+// the address of the intended uplink of a multi-homed host is looked up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bind {
+    // `BIND_ADDRESS`, which applies to queries of its family.
+    Address(IpAddr),
+    // `BIND_INTERFACE`, whose address of the family is used.
+    Interface(String),
+}
+
+fn unspecified(family: RecordType) -> IpAddr {
+    if family == RecordType::AAAA {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+fn is_of_family(ip: &IpAddr, family: RecordType) -> bool {
+    ip.is_ipv6() == (family == RecordType::AAAA)
+}
+
+// The first address of the family, which can be routed beyond the link.
+fn routable_address(addresses: &[IpAddr], family: RecordType) -> Option<IpAddr> {
+    addresses.iter().copied().find(|ip| {
+        let link_local = match ip {
+            IpAddr::V4(ip) => ip.is_link_local(),
+            IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+        };
+        is_of_family(ip, family) && !link_local && !ip.is_loopback()
+    })
+}
+
+// The local address to bind sockets of `family` A or AAAA to, the
+// unspecified address of the family if not bound.
+pub fn local_address(bind: Option<&Bind>, family: RecordType) -> Result<IpAddr, AppError> {
+    match bind {
+        Some(Bind::Address(ip)) if is_of_family(ip, family) => Ok(*ip),
+        Some(Bind::Interface(name)) => {
+            let addresses: Vec<IpAddr> = get_if_addrs()
+                .map_err(|e| AppError::new(&format!("Cannot list network interfaces: {}", e)))?
+                .into_iter()
+                .filter(|i| i.name == *name)
+                .map(|i| i.ip())
+                .collect();
+            routable_address(&addresses, family).ok_or_else(|| {
+                AppError::new(&format!(
+                    "Network interface {} has no {} address to bind to",
+                    name, family
+                ))
+            })
+        }
+        _ => Ok(unspecified(family)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{local_address, routable_address, Bind};
+    use std::net::IpAddr;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    #[test]
+    fn local_address_of_family() {
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let bind = Bind::Address(ip);
+        assert_eq!(ip, local_address(Some(&bind), RecordType::A).unwrap());
+        assert_eq!(
+            "::".parse::<IpAddr>().unwrap(),
+            local_address(Some(&bind), RecordType::AAAA).unwrap()
+        );
+        assert_eq!(
+            "0.0.0.0".parse::<IpAddr>().unwrap(),
+            local_address(None, RecordType::A).unwrap()
+        );
+
+        let addresses: Vec<IpAddr> = vec![
+            "fe80::1".parse().unwrap(),
+            "192.0.2.7".parse().unwrap(),
+            "2001:db8::7".parse().unwrap(),
+        ];
+        assert_eq!(
+            Some("2001:db8::7".parse().unwrap()),
+            routable_address(&addresses, RecordType::AAAA)
+        );
+        assert_eq!(None, routable_address(&addresses[..1], RecordType::AAAA));
+    }
+}
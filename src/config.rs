@@ -0,0 +1,1018 @@
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::bind::Bind;
+use crate::fqdn::Fqdn;
+use crate::gandi_client::{self, parse_ttl, GandiCredential};
+use crate::http_whoami::HttpService;
+use crate::ip_source::{self, IpSourceKind, IpStrategy};
+use crate::ipv6_prefix::Ipv6Prefix;
+use crate::item::{parse_record_type, DynamicItem};
+use crate::propagation::Poll;
+use crate::stun::StunServer;
+use crate::transport::{BootstrapResolver, DnsTransport, DnssecMode, ResolverServer};
+use crate::whoami::{WhoamiService, WhoamiStrategy};
+use crate::window::{UpdatePolicy, WindowMode};
+use crate::zone::{CheckMode, UpdateMode};
+
+// Three runs of a job scheduled every five minutes.
+static DEFAULT_HEALTHCHECK_MAX_AGE: u64 = 900;
+
+// Whoami services, with HTTP services as fallback for networks which
+// intercept DNS.
+static DEFAULT_IP_SOURCES: &str = "dns,http";
+
+static DEFAULT_IPV6_PREFIX_LEN: u8 = 64;
+
+static DEFAULT_BACKUP_RETENTION: usize = 30;
+
+static DEFAULT_GANDI_MAX_RETRIES: u32 = 3;
+
+static DEFAULT_PROPAGATION_INTERVAL: u64 = 2;
+
+// Network hooks often fire several events for one change.
+static DEFAULT_DISPATCHER_DEBOUNCE: u64 = 10;
+
+// Reads configuration env-vars, prefixed with the profile name if any.
+struct Env {
+    prefix: String,
+}
+
+impl Env {
+    fn name(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        env::var(self.name(key)).ok()
+    }
+
+    fn required(&self, key: &str) -> String {
+        let name = self.name(key);
+        env::var(&name).unwrap_or_else(|_| panic!("{} env-var is present", name))
+    }
+
+    fn parse<T>(&self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.var(key).map(|s| {
+            s.parse()
+                .unwrap_or_else(|e| panic!("Invalid {}: {}", self.name(key), e))
+        })
+    }
+
+    // Comma separated list, empty entries are ignored.
+    fn parse_list<T>(&self, key: &str, default: &str) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.var(key)
+            .unwrap_or_else(|| default.into())
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| {
+                s.parse()
+                    .unwrap_or_else(|e| panic!("Invalid {}: {}", self.name(key), e))
+            })
+            .collect()
+    }
+
+    // The list of sources `list_key`, or the source `first_key` followed by
+    // the other default sources.
+    fn ip_sources(
+        &self,
+        first_key: &str,
+        list_key: &str,
+        default: &[IpSourceKind],
+    ) -> Vec<IpSourceKind> {
+        let sources = match self.parse(first_key) {
+            Some(_) if self.var(list_key).is_some() => panic!(
+                "Configuration entries `{}` and `{}` are exclusive",
+                self.name(first_key),
+                self.name(list_key)
+            ),
+            Some(first) => ip_source::preferring(first, default),
+            None => match self.var(list_key) {
+                Some(_) => self.parse_list(list_key, ""),
+                None => default.to_vec(),
+            },
+        };
+        if sources.is_empty() {
+            panic!("{} must not be empty", self.name(list_key));
+        }
+        sources
+    }
+}
+
+#[derive(Debug)]
+pub struct AppConfig {
+    // Name of the profile, empty if profiles are not used.
+    pub profile: String,
+    pub gandi_credential: GandiCredential,
+    pub gandi_sharing_id: Option<String>,
+    // Retries of requests rate limited by Gandi.
+    pub gandi_max_retries: u32,
+    pub domain_ip: Option<Ipv4Addr>,
+    pub domain_ipv6: Option<Ipv6Addr>,
+    // The first zone of `DOMAIN_FQDN`, which names without trailing dot are
+    // relative to.
+    pub domain_fqdn: Fqdn,
+    // All zones of `DOMAIN_FQDN`, the first one included.
+    pub domain_zones: Vec<Fqdn>,
+    // Grouped by zone, in the order of `domain_zones`.
+    pub domain_dynamic_items: Vec<DynamicItem>,
+    // Length of the delegated prefix, which AAAA items with a host suffix
+    // take from the public IPv6 address.
+    pub ipv6_prefix_len: u8,
+    pub update_policy: UpdatePolicy,
+    pub update_mode: UpdateMode,
+    // TTL of updated rrsets in seconds, otherwise existing rrsets keep
+    // theirs.
+    pub domain_ttl: Option<u64>,
+    // TTL the rrset is lowered to while its change is pending outside of
+    // the update windows, restored to `domain_ttl` by the change.
+    pub prelower_ttl: Option<u64>,
+    // Whether the updates of a zone are sent in one request, which replaces
+    // the whole zone.
+    pub batch_updates: bool,
+    pub check_mode: CheckMode,
+    pub allow_create: bool,
+    // Number of name servers which have to answer differently to update.
+    pub ns_quorum: Option<usize>,
+    pub state_file: Option<PathBuf>,
+    // How long looked up IP addresses are reused by later runs.
+    pub ip_cache_ttl: Option<Duration>,
+    pub dead_mans_switch: Option<Duration>,
+    pub healthcheck_max_age: Duration,
+    pub whoami_services: Vec<WhoamiService>,
+    pub whoami_strategy: WhoamiStrategy,
+    // Asked in order, until one of them answers.
+    pub ip_sources: Vec<IpSourceKind>,
+    // Asked for the IPv6 address of AAAA records.
+    pub ip_sources_v6: Vec<IpSourceKind>,
+    pub ip_strategy: IpStrategy,
+    // Whether a looked up address may be private, for split-horizon DNS.
+    pub allow_private_ip: bool,
+    // Whether `interface:` sources may take temporary IPv6 addresses.
+    pub ipv6_temporary_addresses: bool,
+    pub http_ip_services: Vec<HttpService>,
+    // Gateway asked with NAT-PMP or PCP, the one of the default route if
+    // not given.
+    pub gateway: Option<Ipv4Addr>,
+    pub stun_servers: Vec<StunServer>,
+    // Local address or interface the IP sources query from.
+    pub bind: Option<Bind>,
+    pub gandi_sandbox: bool,
+    // Whether to use the deprecated `dns.api.gandi.net` endpoint.
+    pub gandi_legacy_api: bool,
+    // Base URL of Live DNS replacing the built-in ones, e.g. of a mock
+    // server or proxy.
+    pub gandi_api_base_url: Option<String>,
+    // Proxy of the requests to Gandi and the HTTP IP services, instead of
+    // the one of `HTTPS_PROXY` and `HTTP_PROXY`.
+    pub proxy_url: Option<String>,
+    pub owner_id: Option<String>,
+    pub ownership_gc_after: Option<Duration>,
+    pub ownership_gc_delete_data: bool,
+    pub backup_dir: Option<PathBuf>,
+    // Whether a Gandi snapshot of a zone is taken before its first change.
+    pub snapshot_before_update: bool,
+    pub backup_retention: usize,
+    pub propagation: Option<Poll>,
+    // Fail the run if an update did not propagate in time.
+    pub propagation_required: bool,
+    pub last_updated_record: Option<String>,
+    pub dispatcher_debounce: Duration,
+    pub resolver: BootstrapResolver,
+    pub resolver_servers: Vec<ResolverServer>,
+    pub dns_transport: DnsTransport,
+    // TLS name of the addresses of `RESOLVER_SERVERS` with DNS-over-TLS or
+    // DNS-over-HTTPS.
+    pub dns_tls_name: String,
+    pub dnssec: DnssecMode,
+}
+
+impl AppConfig {
+    // Reads one configuration per profile in `PROFILES`, with env-vars
+    // prefixed by the upper cased profile name, e.g. `ALICE_GANDI_PAT`.
+    // Without `PROFILES` a single configuration of plain env-vars is read.
+    pub fn from_env() -> Vec<Self> {
+        match env::var("PROFILES") {
+            Ok(profiles) => profiles
+                .split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+                .map(|p| Self::from_env_prefixed(p, &format!("{}_", p.to_uppercase())))
+                .collect(),
+            Err(_) => vec![Self::from_env_prefixed("", "")],
+        }
+    }
+
+    fn from_env_prefixed(profile: &str, prefix: &str) -> Self {
+        let env = Env {
+            prefix: prefix.into(),
+        };
+
+        let gandi_credential = match (env.var("GANDI_PAT"), env.var("GANDI_API_KEY")) {
+            (Some(_), Some(_)) => panic!(
+                "Configuration entries `{}` and `{}` are exclusive",
+                env.name("GANDI_PAT"),
+                env.name("GANDI_API_KEY")
+            ),
+            (Some(token), None) => GandiCredential::Pat(token),
+            (None, Some(key)) => GandiCredential::ApiKey(key),
+            (None, None) => panic!(
+                "{} or {} env-var is present",
+                env.name("GANDI_PAT"),
+                env.name("GANDI_API_KEY")
+            ),
+        };
+        let gandi_sharing_id = env
+            .var("GANDI_SHARING_ID")
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        let gandi_max_retries = env
+            .parse("GANDI_MAX_RETRIES")
+            .unwrap_or(DEFAULT_GANDI_MAX_RETRIES);
+        let domain_ip: Option<Ipv4Addr> = env.parse("DOMAIN_IP");
+        let domain_ipv6: Option<Ipv6Addr> = env.parse("DOMAIN_IPV6");
+        let record_types: Vec<RecordType> = env
+            .var("RECORD_TYPES")
+            .unwrap_or_else(|| "A".into())
+            .split(',')
+            .filter(|t| !t.trim().is_empty())
+            .map(|t| {
+                parse_record_type(t)
+                    .unwrap_or_else(|e| panic!("Invalid {}: {}", env.name("RECORD_TYPES"), e))
+            })
+            .collect();
+        env.required("DOMAIN_FQDN");
+        let domain_zones: Vec<Fqdn> = env.parse_list("DOMAIN_FQDN", "");
+        let domain_fqdn = domain_zones
+            .first()
+            .cloned()
+            .unwrap_or_else(|| panic!("{} must not be empty", env.name("DOMAIN_FQDN")));
+        let mut domain_dynamic_items: Vec<DynamicItem> = env
+            .required("DOMAIN_DYNAMIC_ITEMS")
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .flat_map(|s| {
+                DynamicItem::parse(s, &domain_zones, &record_types).unwrap_or_else(|e| {
+                    panic!("Invalid {}: {}", env.name("DOMAIN_DYNAMIC_ITEMS"), e)
+                })
+            })
+            .collect();
+        let ipv6_prefix_len: u8 = env
+            .parse("IPV6_PREFIX_LENGTH")
+            .unwrap_or(DEFAULT_IPV6_PREFIX_LEN);
+        if ipv6_prefix_len == 0 || ipv6_prefix_len > 128 {
+            panic!(
+                "{} must be between 1 and 128",
+                env.name("IPV6_PREFIX_LENGTH")
+            );
+        }
+        for item in &domain_dynamic_items {
+            let overlaps = item
+                .ipv6_suffix()
+                .filter(|s| !Ipv6Prefix::of(s, ipv6_prefix_len).contains(&Ipv6Addr::UNSPECIFIED))
+                .is_some();
+            if overlaps {
+                panic!(
+                    "Invalid {}: suffix of item {} overlaps the /{} prefix of {}",
+                    env.name("DOMAIN_DYNAMIC_ITEMS"),
+                    item,
+                    ipv6_prefix_len,
+                    env.name("IPV6_PREFIX_LENGTH")
+                );
+            }
+        }
+        // Stable, items of a zone keep their configured order.
+        domain_dynamic_items.sort_by_key(|i| domain_zones.iter().position(|z| *z == i.zone));
+
+        let windows = env.parse_list("UPDATE_WINDOWS", "");
+        let mode: WindowMode = env.parse("UPDATE_WINDOW_MODE").unwrap_or(WindowMode::Allow);
+        let update_policy = UpdatePolicy { mode, windows };
+        let update_mode = env.parse("UPDATE_MODE").unwrap_or(UpdateMode::Merge);
+        let batch_updates = env.parse("BATCH_UPDATES").unwrap_or(false);
+        let domain_ttl = env.var("DOMAIN_TTL").map(|s| {
+            parse_ttl(&s).unwrap_or_else(|e| panic!("Invalid {}: {}", env.name("DOMAIN_TTL"), e))
+        });
+        let prelower_ttl = env.var("PRELOWER_TTL").map(|s| {
+            parse_ttl(&s).unwrap_or_else(|e| panic!("Invalid {}: {}", env.name("PRELOWER_TTL"), e))
+        });
+        if let Some(ttl) = prelower_ttl {
+            for required in &["UPDATE_WINDOWS", "DOMAIN_TTL"] {
+                if env.var(required).filter(|s| !s.is_empty()).is_none() {
+                    panic!(
+                        "Configuration entry `{}` requires `{}`",
+                        env.name("PRELOWER_TTL"),
+                        env.name(required)
+                    );
+                }
+            }
+            if domain_ttl.filter(|domain_ttl| ttl < *domain_ttl).is_none() {
+                panic!(
+                    "Invalid {}: {} is not lower than `{}`",
+                    env.name("PRELOWER_TTL"),
+                    ttl,
+                    env.name("DOMAIN_TTL")
+                );
+            }
+        }
+        let check_mode = env.parse("CHECK_MODE").unwrap_or(CheckMode::Dns);
+        let allow_create = env.parse("ALLOW_CREATE").unwrap_or(false);
+
+        let state_file = env.var("STATE_FILE").map(PathBuf::from);
+        let dead_mans_switch = env.parse("DEAD_MANS_SWITCH_AFTER").map(Duration::from_secs);
+        if dead_mans_switch.is_some() && state_file.is_none() {
+            panic!(
+                "Configuration entry `{}` requires `{}`",
+                env.name("DEAD_MANS_SWITCH_AFTER"),
+                env.name("STATE_FILE")
+            );
+        }
+
+        let ip_cache_ttl = env.parse("IP_CACHE_TTL").map(Duration::from_secs);
+        if ip_cache_ttl.is_some() && state_file.is_none() {
+            panic!(
+                "Configuration entry `{}` requires `{}`",
+                env.name("IP_CACHE_TTL"),
+                env.name("STATE_FILE")
+            );
+        }
+
+        let healthcheck_max_age = Duration::from_secs(
+            env.parse("HEALTHCHECK_MAX_AGE")
+                .unwrap_or(DEFAULT_HEALTHCHECK_MAX_AGE),
+        );
+
+        let backup_dir = env.var("BACKUP_DIR").map(PathBuf::from);
+        let snapshot_before_update = env.parse("SNAPSHOT_BEFORE_UPDATE").unwrap_or(false);
+        let backup_retention = env
+            .parse("BACKUP_RETENTION")
+            .unwrap_or(DEFAULT_BACKUP_RETENTION);
+
+        let propagation_interval: u64 = env
+            .parse("PROPAGATION_INTERVAL")
+            .unwrap_or(DEFAULT_PROPAGATION_INTERVAL);
+        if propagation_interval == 0 {
+            panic!("{} must be positive", env.name("PROPAGATION_INTERVAL"));
+        }
+        let propagation = env.parse("PROPAGATION_TIMEOUT").map(|timeout| Poll {
+            interval: Duration::from_secs(propagation_interval),
+            timeout: Duration::from_secs(timeout),
+        });
+        let propagation_required = env.parse("PROPAGATION_REQUIRED").unwrap_or(false);
+        if propagation_required && propagation.is_none() {
+            panic!(
+                "Configuration entry `{}` requires `{}`",
+                env.name("PROPAGATION_REQUIRED"),
+                env.name("PROPAGATION_TIMEOUT")
+            );
+        }
+        let last_updated_record = env.var("LAST_UPDATED_RECORD").filter(|s| !s.is_empty());
+
+        let dispatcher_debounce = Duration::from_secs(
+            env.parse("DISPATCHER_DEBOUNCE")
+                .unwrap_or(DEFAULT_DISPATCHER_DEBOUNCE),
+        );
+
+        let resolver = env.parse("RESOLVER").unwrap_or(BootstrapResolver::Servers);
+        let resolver_servers: Vec<ResolverServer> = env.parse_list("RESOLVER_SERVERS", "google");
+        if resolver_servers.is_empty() {
+            panic!("{} must not be empty", env.name("RESOLVER_SERVERS"));
+        }
+        let dns_transport = env.parse("DNS_TRANSPORT").unwrap_or(DnsTransport::Udp);
+        if resolver == BootstrapResolver::System && dns_transport != DnsTransport::Udp {
+            panic!(
+                "Configuration entry `{}=system` requires `{}=udp`",
+                env.name("RESOLVER"),
+                env.name("DNS_TRANSPORT")
+            );
+        }
+        let dns_tls_name = env
+            .var("DNS_TLS_NAME")
+            .filter(|s| !s.is_empty())
+            .unwrap_or_default();
+        let needs_tls_name =
+            dns_transport != DnsTransport::Udp && resolver_servers.iter().any(|s| s.is_address());
+        if needs_tls_name && dns_tls_name.is_empty() {
+            panic!(
+                "Addresses in `{}` with `{}` require `{}`",
+                env.name("RESOLVER_SERVERS"),
+                env.name("DNS_TRANSPORT"),
+                env.name("DNS_TLS_NAME")
+            );
+        }
+        let dnssec = env.parse("DNSSEC").unwrap_or(DnssecMode::Off);
+
+        let ns_quorum: Option<usize> = env.parse("NS_QUORUM");
+        if ns_quorum == Some(0) {
+            panic!("{} must be positive", env.name("NS_QUORUM"));
+        }
+        let direct = check_mode == CheckMode::Dns
+            && dns_transport.reaches_authoritative()
+            && dnssec == DnssecMode::Off;
+        if ns_quorum.is_some() && !direct {
+            panic!(
+                "Configuration entry `{}` requires `{}=dns` and queries to the name servers, without `{}=doh` or `{}=strict`",
+                env.name("NS_QUORUM"),
+                env.name("CHECK_MODE"),
+                env.name("DNS_TRANSPORT"),
+                env.name("DNSSEC")
+            );
+        }
+
+        let whoami_services: Vec<WhoamiService> = env.parse_list("WHOAMI_SERVICES", "opendns");
+        let whoami_services = match env.parse("WHOAMI_SERVICE") {
+            Some(first) => ip_source::preferring(first, &whoami_services),
+            None => whoami_services,
+        };
+        let whoami_strategy = env
+            .parse("WHOAMI_STRATEGY")
+            .unwrap_or(WhoamiStrategy::Fallback);
+        let default_ip_sources: Vec<IpSourceKind> = DEFAULT_IP_SOURCES
+            .split(',')
+            .filter_map(|s| s.parse().ok())
+            .collect();
+        let ip_sources = env.ip_sources("IP_SOURCE", "IP_SOURCES", &default_ip_sources);
+        // The IPv6 address is looked up like the IPv4 address by default.
+        let ip_sources_v6 = env.ip_sources("IP_SOURCE_V6", "IP_SOURCES_V6", &ip_sources);
+        let has_aaaa = domain_dynamic_items
+            .iter()
+            .any(|i| i.record_type == RecordType::AAAA);
+        if has_aaaa && ip_sources_v6.contains(&IpSourceKind::Whoami(WhoamiService::Akamai)) {
+            panic!(
+                "Akamai in `{}` does not support IPv6",
+                env.name("IP_SOURCES_V6")
+            );
+        }
+        let ip_strategy = env.parse("IP_STRATEGY").unwrap_or(IpStrategy::Fallback);
+        // The IPv6 sources are only asked for AAAA items.
+        let too_few = if ip_sources.len() < 2 {
+            Some("IP_SOURCES")
+        } else if has_aaaa && ip_sources_v6.len() < 2 {
+            Some("IP_SOURCES_V6")
+        } else {
+            None
+        };
+        if let (IpStrategy::Majority, Some(sources)) = (ip_strategy, too_few) {
+            panic!(
+                "Configuration entry `{}=majority` requires at least two `{}`",
+                env.name("IP_STRATEGY"),
+                env.name(sources)
+            );
+        }
+        let allow_private_ip = env.parse("ALLOW_PRIVATE_IP").unwrap_or(false);
+        let ipv6_temporary_addresses = env.parse("IPV6_TEMPORARY_ADDRESSES").unwrap_or(false);
+        let http_ip_services: Vec<HttpService> =
+            env.parse_list("HTTP_IP_SERVICES", "ipify,icanhazip");
+        let uses_http = ip_sources
+            .iter()
+            .chain(&ip_sources_v6)
+            .any(|s| *s == IpSourceKind::Http);
+        if uses_http && http_ip_services.is_empty() {
+            panic!(
+                "Configuration entry `{}` requires `{}`",
+                env.name("IP_SOURCES"),
+                env.name("HTTP_IP_SERVICES")
+            );
+        }
+        let gateway = env.parse("GATEWAY");
+        let stun_servers: Vec<StunServer> = env.parse_list(
+            "STUN_SERVERS",
+            "stun.l.google.com:19302,stun.cloudflare.com",
+        );
+        let uses_stun = ip_sources
+            .iter()
+            .chain(&ip_sources_v6)
+            .any(|s| *s == IpSourceKind::Stun);
+        if uses_stun && stun_servers.is_empty() {
+            panic!(
+                "Configuration entry `{}` requires `{}`",
+                env.name("IP_SOURCES"),
+                env.name("STUN_SERVERS")
+            );
+        }
+        let bind = match (env.parse("BIND_ADDRESS"), env.var("BIND_INTERFACE")) {
+            (Some(_), Some(_)) => panic!(
+                "Configuration entries `{}` and `{}` are exclusive",
+                env.name("BIND_ADDRESS"),
+                env.name("BIND_INTERFACE")
+            ),
+            (Some(ip), None) => Some(Bind::Address(ip)),
+            (None, Some(name)) => Some(Bind::Interface(name)),
+            (None, None) => None,
+        };
+        let gandi_sandbox = env.parse("GANDI_SANDBOX").unwrap_or(false);
+        let gandi_legacy_api = env.parse("GANDI_LEGACY_API").unwrap_or(false);
+        if gandi_sandbox && gandi_legacy_api {
+            panic!(
+                "Configuration entries `{}` and `{}` are exclusive",
+                env.name("GANDI_SANDBOX"),
+                env.name("GANDI_LEGACY_API")
+            );
+        }
+        // The legacy endpoint does not know Personal Access Tokens.
+        if gandi_legacy_api && matches!(gandi_credential, GandiCredential::Pat(_)) {
+            panic!(
+                "Configuration entries `{}` and `{}` are exclusive",
+                env.name("GANDI_PAT"),
+                env.name("GANDI_LEGACY_API")
+            );
+        }
+        let proxy_url = env.var("PROXY_URL").filter(|s| !s.is_empty());
+        if let Some(url) = &proxy_url {
+            if let Err(e) = reqwest::Proxy::all(url.as_str()) {
+                panic!("Invalid {}: {}", env.name("PROXY_URL"), e);
+            }
+        }
+        let gandi_api_base_url = env.var("GANDI_API_BASE_URL").filter(|s| !s.is_empty());
+        if let Some(url) = &gandi_api_base_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                panic!(
+                    "Invalid {}: '{}' is no HTTP(S) URL",
+                    env.name("GANDI_API_BASE_URL"),
+                    url
+                );
+            }
+            for other in &["GANDI_SANDBOX", "GANDI_LEGACY_API"] {
+                if env.var(other).is_some() {
+                    panic!(
+                        "Configuration entries `{}` and `{}` are exclusive",
+                        env.name("GANDI_API_BASE_URL"),
+                        env.name(other)
+                    );
+                }
+            }
+        }
+        let owner_id = env.var("OWNER_ID").filter(|s| !s.is_empty());
+        if let Some(id) = &owner_id {
+            if id.contains(',') || id.contains('"') {
+                panic!("{} must not contain ',' or '\"'", env.name("OWNER_ID"));
+            }
+        }
+        let ownership_gc_after = env.parse("OWNERSHIP_GC_AFTER").map(Duration::from_secs);
+        if ownership_gc_after.is_some() && owner_id.is_none() {
+            panic!(
+                "Configuration entry `{}` requires `{}`",
+                env.name("OWNERSHIP_GC_AFTER"),
+                env.name("OWNER_ID")
+            );
+        }
+        let ownership_gc_delete_data = env.parse("OWNERSHIP_GC_DELETE_DATA").unwrap_or(false);
+
+        Self {
+            profile: profile.into(),
+            gandi_credential,
+            gandi_sharing_id,
+            gandi_max_retries,
+            domain_ip,
+            domain_ipv6,
+            domain_fqdn,
+            domain_zones,
+            domain_dynamic_items,
+            ipv6_prefix_len,
+            update_policy,
+            update_mode,
+            domain_ttl,
+            prelower_ttl,
+            batch_updates,
+            check_mode,
+            allow_create,
+            ns_quorum,
+            state_file,
+            ip_cache_ttl,
+            dead_mans_switch,
+            healthcheck_max_age,
+            whoami_services,
+            whoami_strategy,
+            ip_sources,
+            ip_sources_v6,
+            ip_strategy,
+            allow_private_ip,
+            ipv6_temporary_addresses,
+            http_ip_services,
+            gateway,
+            stun_servers,
+            bind,
+            gandi_sandbox,
+            gandi_legacy_api,
+            gandi_api_base_url,
+            proxy_url,
+            owner_id,
+            ownership_gc_after,
+            ownership_gc_delete_data,
+            backup_dir,
+            snapshot_before_update,
+            backup_retention,
+            propagation,
+            propagation_required,
+            last_updated_record,
+            dispatcher_debounce,
+            resolver,
+            resolver_servers,
+            dns_transport,
+            dns_tls_name,
+            dnssec,
+        }
+    }
+
+    // The first entry of a selection, such as `nas.other-domain.net/A`,
+    // which names a record of another zone than the first one, which the
+    // zone commands act on, and that zone.
+    pub fn other_zone_selection<'a>(&self, selection: &'a [String]) -> Option<(&'a str, &Fqdn)> {
+        selection.iter().find_map(|entry| {
+            let name = entry.split('/').next().unwrap_or_default();
+            let zone = self.domain_zones[1..].iter().find(|zone| {
+                let zone = zone.without_dot();
+                name == zone || name.ends_with(&format!(".{}", zone))
+            })?;
+            Some((entry.as_str(), zone))
+        })
+    }
+
+    // The address given for records of type A or AAAA, `DOMAIN_IP` or
+    // `DOMAIN_IPV6`, which is not looked up.
+    pub fn given_ip(&self, rr_type: RecordType) -> Option<IpAddr> {
+        match rr_type {
+            RecordType::AAAA => self.domain_ipv6.map(IpAddr::V6),
+            _ => self.domain_ip.map(IpAddr::V4),
+        }
+    }
+
+    // The sources of the address for records of type A or AAAA.
+    pub fn ip_sources_of(&self, rr_type: RecordType) -> &[IpSourceKind] {
+        match rr_type {
+            RecordType::AAAA => &self.ip_sources_v6,
+            _ => &self.ip_sources,
+        }
+    }
+
+    // The option `--sandbox` selects the sandbox environment like
+    // `GANDI_SANDBOX`, which is exclusive with other base URLs.
+    pub fn check_sandbox_option(&self, sandbox: bool) -> Result<(), String> {
+        let other = if !sandbox {
+            return Ok(());
+        } else if self.gandi_api_base_url.is_some() {
+            "GANDI_API_BASE_URL"
+        } else if self.gandi_legacy_api {
+            "GANDI_LEGACY_API"
+        } else {
+            return Ok(());
+        };
+        let prefix = match self.profile.as_str() {
+            "" => String::new(),
+            profile => format!("{}_", profile.to_uppercase()),
+        };
+        Err(format!(
+            "Option `--sandbox` and configuration entry `{}{}` are exclusive",
+            prefix, other
+        ))
+    }
+
+    // Base URL of the Gandi API, the sandbox one with `--sandbox`.
+    pub fn gandi_base_url(&self, sandbox: bool) -> &str {
+        if self.gandi_sandbox || sandbox {
+            gandi_client::GANDI_SANDBOX_LIVE_DNS_BASE_URL
+        } else if let Some(url) = &self.gandi_api_base_url {
+            url
+        } else if self.gandi_legacy_api {
+            gandi_client::GANDI_LEGACY_LIVE_DNS_BASE_URL
+        } else {
+            gandi_client::GANDI_LIVE_DNS_BASE_URL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AppConfig;
+    use crate::ip_source::IpSourceKind;
+    use crate::transport::{DnsTransport, DnssecMode};
+    use crate::zone::CheckMode;
+    use std::env;
+    use std::panic;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    // The configuration of the env-vars, which are set with a prefix of
+    // their own per test, as tests share the environment of the process.
+    // Required entries not given are set to defaults, the credential to a
+    // PAT unless an API key is given.
+    fn config(prefix: &str, vars: &[(&str, &str)]) -> AppConfig {
+        let given = |key: &str| vars.iter().any(|(k, _)| *k == key);
+        let credential = if given("GANDI_API_KEY") {
+            "GANDI_API_KEY"
+        } else {
+            "GANDI_PAT"
+        };
+        let defaults = [
+            (credential, "secret"),
+            ("DOMAIN_FQDN", "example.com"),
+            ("DOMAIN_DYNAMIC_ITEMS", "home"),
+        ];
+        let defaults = defaults.iter().filter(|(key, _)| !given(key));
+        for (key, value) in defaults.chain(vars) {
+            env::set_var(format!("{}{}", prefix, key), value);
+        }
+        AppConfig::from_env_prefixed("", prefix)
+    }
+
+    // The message of the panic of an invalid configuration.
+    fn config_error(prefix: &str, vars: &[(&str, &str)]) -> String {
+        match panic::catch_unwind(|| config(prefix, vars)) {
+            Ok(_) => panic!("Configuration of {} is valid", prefix),
+            Err(e) => e.downcast_ref::<String>().cloned().unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn majority_requires_two_sources_per_family() {
+        let ipv4_only = config(
+            "MAJORITY_IPV4_",
+            &[
+                ("IP_STRATEGY", "majority"),
+                ("IP_SOURCES", "dns,http"),
+                ("IP_SOURCES_V6", "http"),
+            ],
+        );
+        assert_eq!(2, ipv4_only.ip_sources.len());
+        assert_eq!(
+            "Configuration entry `MAJORITY_IPV6_IP_STRATEGY=majority` requires at least two `MAJORITY_IPV6_IP_SOURCES_V6`",
+            config_error(
+                "MAJORITY_IPV6_",
+                &[
+                    ("IP_STRATEGY", "majority"),
+                    ("IP_SOURCES", "dns,http"),
+                    ("IP_SOURCES_V6", "http"),
+                    ("RECORD_TYPES", "A,AAAA"),
+                ],
+            )
+        );
+        assert_eq!(
+            "Configuration entry `MAJORITY_FEW_IP_STRATEGY=majority` requires at least two `MAJORITY_FEW_IP_SOURCES`",
+            config_error(
+                "MAJORITY_FEW_",
+                &[("IP_STRATEGY", "majority"), ("IP_SOURCES", "http")],
+            )
+        );
+    }
+
+    #[test]
+    fn check_mode_api_excludes_ns_quorum() {
+        let api = config("CHECK_API_", &[("CHECK_MODE", "api")]);
+        assert_eq!(CheckMode::Api, api.check_mode);
+        assert_eq!(
+            CheckMode::Dns,
+            config("CHECK_DEFAULT_", &[("NS_QUORUM", "2")]).check_mode
+        );
+        let error = config_error(
+            "CHECK_QUORUM_",
+            &[("CHECK_MODE", "api"), ("NS_QUORUM", "2")],
+        );
+        assert!(error.starts_with("Configuration entry `CHECK_QUORUM_NS_QUORUM` requires"));
+    }
+
+    #[test]
+    fn dns_over_tls_requires_tls_name() {
+        let dot = config(
+            "DOT_NAME_",
+            &[
+                ("DNS_TRANSPORT", "dot"),
+                ("RESOLVER_SERVERS", "quad9,192.0.2.1"),
+                ("DNS_TLS_NAME", "resolver.example"),
+            ],
+        );
+        assert_eq!(DnsTransport::Tls, dot.dns_transport);
+        assert_eq!("resolver.example", dot.dns_tls_name);
+        config("DOT_PUBLIC_", &[("DNS_TRANSPORT", "dot")]);
+        assert_eq!(
+            "Addresses in `DOT_ADDRESS_RESOLVER_SERVERS` with `DOT_ADDRESS_DNS_TRANSPORT` require `DOT_ADDRESS_DNS_TLS_NAME`",
+            config_error(
+                "DOT_ADDRESS_",
+                &[("DNS_TRANSPORT", "dot"), ("RESOLVER_SERVERS", "192.0.2.1")],
+            )
+        );
+        assert_eq!(
+            "Configuration entry `DOT_SYSTEM_RESOLVER=system` requires `DOT_SYSTEM_DNS_TRANSPORT=udp`",
+            config_error(
+                "DOT_SYSTEM_",
+                &[("DNS_TRANSPORT", "dot"), ("RESOLVER", "system")],
+            )
+        );
+    }
+
+    #[test]
+    fn dns_over_https_excludes_ns_quorum() {
+        let doh = config("DOH_", &[("DNS_TRANSPORT", "doh")]);
+        assert_eq!(DnsTransport::Https, doh.dns_transport);
+        let error = config_error(
+            "DOH_QUORUM_",
+            &[("DNS_TRANSPORT", "doh"), ("NS_QUORUM", "2")],
+        );
+        assert!(error.starts_with("Configuration entry `DOH_QUORUM_NS_QUORUM` requires"));
+        config(
+            "DOT_QUORUM_",
+            &[("DNS_TRANSPORT", "dot"), ("NS_QUORUM", "2")],
+        );
+    }
+
+    #[test]
+    fn dnssec_strict_excludes_ns_quorum() {
+        assert_eq!(DnssecMode::Off, config("DNSSEC_OFF_", &[]).dnssec);
+        let strict = config("DNSSEC_STRICT_", &[("DNSSEC", "strict")]);
+        assert_eq!(DnssecMode::Strict, strict.dnssec);
+        let error = config_error(
+            "DNSSEC_QUORUM_",
+            &[("DNSSEC", "strict"), ("NS_QUORUM", "2")],
+        );
+        assert!(error.starts_with("Configuration entry `DNSSEC_QUORUM_NS_QUORUM` requires"));
+    }
+
+    #[test]
+    fn gandi_base_url_selects_environment() {
+        let live = config("BASE_URL_LIVE_", &[]);
+        assert_eq!(
+            "https://api.gandi.net/v5/livedns",
+            live.gandi_base_url(false)
+        );
+        assert_eq!(
+            "https://api.sandbox.gandi.net/v5/livedns",
+            live.gandi_base_url(true)
+        );
+        let sandbox = config("BASE_URL_SANDBOX_", &[("GANDI_SANDBOX", "true")]);
+        assert_eq!(
+            "https://api.sandbox.gandi.net/v5/livedns",
+            sandbox.gandi_base_url(false)
+        );
+        let legacy = config(
+            "BASE_URL_LEGACY_",
+            &[("GANDI_API_KEY", "key"), ("GANDI_LEGACY_API", "true")],
+        );
+        assert_eq!(
+            "https://dns.api.gandi.net/api/v5",
+            legacy.gandi_base_url(false)
+        );
+        let own = config(
+            "BASE_URL_OWN_",
+            &[("GANDI_API_BASE_URL", "http://127.0.0.1:8080/v5/livedns")],
+        );
+        assert_eq!(
+            "http://127.0.0.1:8080/v5/livedns",
+            own.gandi_base_url(false)
+        );
+        assert_eq!(Ok(()), own.check_sandbox_option(false));
+        assert_eq!(
+            Err(
+                "Option `--sandbox` and configuration entry `GANDI_API_BASE_URL` are exclusive"
+                    .into()
+            ),
+            own.check_sandbox_option(true)
+        );
+        assert!(legacy.check_sandbox_option(true).is_err());
+        assert_eq!(Ok(()), live.check_sandbox_option(true));
+        assert!(config_error(
+            "BASE_URL_BOTH_",
+            &[("GANDI_SANDBOX", "true"), ("GANDI_LEGACY_API", "true")],
+        )
+        .contains("are exclusive"));
+    }
+
+    #[test]
+    fn legacy_api_requires_api_key() {
+        assert_eq!(
+            "Configuration entries `LEGACY_PAT_GANDI_PAT` and `LEGACY_PAT_GANDI_LEGACY_API` are exclusive",
+            config_error("LEGACY_PAT_", &[("GANDI_LEGACY_API", "true")])
+        );
+        let legacy = config(
+            "LEGACY_KEY_",
+            &[("GANDI_API_KEY", "key"), ("GANDI_LEGACY_API", "true")],
+        );
+        assert!(legacy.gandi_legacy_api);
+    }
+
+    #[test]
+    fn profiles_read_prefixed_entries() {
+        // The only test reading `PROFILES`.
+        env::set_var("PROFILES", "alice, ,bob");
+        for (prefix, domain) in &[("ALICE_", "alice.example"), ("BOB_", "bob.example")] {
+            env::set_var(format!("{}GANDI_PAT", prefix), "secret");
+            env::set_var(format!("{}DOMAIN_FQDN", prefix), domain);
+            env::set_var(format!("{}DOMAIN_DYNAMIC_ITEMS", prefix), "home");
+        }
+        let configs = AppConfig::from_env();
+        let profiles: Vec<(&str, String)> = configs
+            .iter()
+            .map(|c| (c.profile.as_str(), c.domain_fqdn.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("alice", "alice.example.".to_string()),
+                ("bob", "bob.example.".to_string())
+            ],
+            profiles
+        );
+    }
+
+    #[test]
+    fn prelower_ttl_requires_windows_and_higher_domain_ttl() {
+        let prelower = config(
+            "PRELOWER_",
+            &[
+                ("PRELOWER_TTL", "300"),
+                ("DOMAIN_TTL", "3600"),
+                ("UPDATE_WINDOWS", "02:00-04:00"),
+            ],
+        );
+        assert_eq!(Some(300), prelower.prelower_ttl);
+        assert_eq!(None, config("PRELOWER_NONE_", &[]).prelower_ttl);
+        assert_eq!(
+            "Configuration entry `PRELOWER_WINDOWS_PRELOWER_TTL` requires `PRELOWER_WINDOWS_UPDATE_WINDOWS`",
+            config_error(
+                "PRELOWER_WINDOWS_",
+                &[("PRELOWER_TTL", "300"), ("DOMAIN_TTL", "3600")],
+            )
+        );
+        assert_eq!(
+            "Configuration entry `PRELOWER_TTL_PRELOWER_TTL` requires `PRELOWER_TTL_DOMAIN_TTL`",
+            config_error(
+                "PRELOWER_TTL_",
+                &[("PRELOWER_TTL", "300"), ("UPDATE_WINDOWS", "02:00-04:00")],
+            )
+        );
+        assert_eq!(
+            "Invalid PRELOWER_HIGH_PRELOWER_TTL: 3600 is not lower than `PRELOWER_HIGH_DOMAIN_TTL`",
+            config_error(
+                "PRELOWER_HIGH_",
+                &[
+                    ("PRELOWER_TTL", "3600"),
+                    ("DOMAIN_TTL", "3600"),
+                    ("UPDATE_WINDOWS", "02:00-04:00"),
+                ],
+            )
+        );
+    }
+
+    #[test]
+    fn selections_of_other_zones_are_found() {
+        let config = config(
+            "OTHER_ZONE_",
+            &[
+                ("DOMAIN_FQDN", "example.org,other-domain.net"),
+                ("DOMAIN_DYNAMIC_ITEMS", "home,nas.other-domain.net"),
+            ],
+        );
+        let selection =
+            |entries: &[&str]| -> Vec<String> { entries.iter().map(|e| e.to_string()).collect() };
+        assert_eq!(None, config.other_zone_selection(&[]));
+        assert_eq!(
+            None,
+            config.other_zone_selection(&selection(&["home/A", "www.other-domain"]))
+        );
+        let nas = selection(&["home", "nas.other-domain.net/AAAA"]);
+        let (entry, zone) = config.other_zone_selection(&nas).unwrap();
+        assert_eq!("nas.other-domain.net/AAAA", entry);
+        assert_eq!("other-domain.net.", zone.as_dns());
+        assert!(config
+            .other_zone_selection(&selection(&["other-domain.net"]))
+            .is_some());
+    }
+
+    #[test]
+    fn addresses_of_aaaa_records_are_ipv6() {
+        let config = config(
+            "AAAA_",
+            &[
+                ("RECORD_TYPES", "A,AAAA"),
+                ("DOMAIN_IPV6", "2001:db8::7"),
+                ("IP_SOURCES", "http"),
+                ("IP_SOURCES_V6", "interface:eth0"),
+            ],
+        );
+        let types: Vec<RecordType> = config
+            .domain_dynamic_items
+            .iter()
+            .map(|i| i.record_type)
+            .collect();
+        assert_eq!(vec![RecordType::A, RecordType::AAAA], types);
+        assert_eq!(
+            Some("2001:db8::7".parse().unwrap()),
+            config.given_ip(RecordType::AAAA)
+        );
+        assert_eq!(None, config.given_ip(RecordType::A));
+        assert_eq!(
+            &[IpSourceKind::Interface("eth0".into())],
+            config.ip_sources_of(RecordType::AAAA)
+        );
+        assert_eq!(&[IpSourceKind::Http], config.ip_sources_of(RecordType::A));
+    }
+}
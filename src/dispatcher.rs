@@ -0,0 +1,95 @@
+// Events of network hooks, which trigger a run in `--dispatcher` mode:
+// NetworkManager dispatcher scripts, dhclient hooks and ppp ip-up scripts.
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub interface: Option<String>,
+    // Lower cased action, e.g. `up` or `dhcp4-change`.
+    pub action: String,
+}
+
+// Actions after which the public IP address may have changed.
+static RELEVANT_ACTIONS: &[&str] = &[
+    // NetworkManager
+    "up",
+    "dhcp4-change",
+    "dhcp6-change",
+    "connectivity-change",
+    // dhclient
+    "bound",
+    "renew",
+    "rebind",
+    "reboot",
+    "bound6",
+    "renew6",
+    "rebind6",
+];
+
+impl Event {
+    // Reads the event from the hook arguments `INTERFACE ACTION` as passed
+    // by NetworkManager, or else from the environment of NetworkManager,
+    // dhclient or pppd, in this order.
+    pub fn from_hook<F>(operands: &[String], var: F) -> Result<Self, String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let (interface, action) = match operands {
+            [interface, action] => (Some(interface.clone()), Some(action.clone())),
+            [] => match var("NM_DISPATCHER_ACTION") {
+                Some(action) => (
+                    var("DEVICE_IP_IFACE").or_else(|| var("DEVICE_IFACE")),
+                    Some(action),
+                ),
+                None => match var("reason") {
+                    Some(reason) => (var("interface"), Some(reason)),
+                    None => (var("PPP_IFACE"), var("PPP_IFACE").map(|_| "up".into())),
+                },
+            },
+            _ => return Err("--dispatcher expects INTERFACE and ACTION or no arguments".into()),
+        };
+        let action = action.ok_or("No dispatcher event found in the arguments or environment")?;
+        Ok(Self {
+            interface: interface.filter(|i| !i.is_empty()),
+            action: action.to_lowercase(),
+        })
+    }
+
+    // Whether the event may have changed the public IP address.
+    pub fn is_relevant(&self) -> bool {
+        RELEVANT_ACTIONS.contains(&self.action.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+    use std::collections::HashMap;
+
+    fn from_hook(operands: &[&str], vars: &[(&str, &str)]) -> Result<Event, String> {
+        let operands: Vec<String> = operands.iter().map(|s| s.to_string()).collect();
+        let vars: HashMap<&str, &str> = vars.iter().cloned().collect();
+        Event::from_hook(&operands, |k| vars.get(k).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn event_of_hooks() {
+        let expected = Event {
+            interface: Some("eth0".into()),
+            action: "up".into(),
+        };
+        assert_eq!(expected, from_hook(&["eth0", "up"], &[]).unwrap());
+        let nm = from_hook(
+            &[],
+            &[("NM_DISPATCHER_ACTION", "up"), ("DEVICE_IFACE", "eth0")],
+        );
+        assert_eq!(expected, nm.unwrap());
+        let dhclient = from_hook(&[], &[("reason", "BOUND"), ("interface", "eth0")]).unwrap();
+        assert_eq!("bound", dhclient.action);
+        assert!(dhclient.is_relevant());
+        let ppp = from_hook(&[], &[("PPP_IFACE", "ppp0")]).unwrap();
+        assert_eq!(Some("ppp0".into()), ppp.interface);
+        assert!(ppp.is_relevant());
+        assert!(!from_hook(&["eth0", "down"], &[]).unwrap().is_relevant());
+        assert!(from_hook(&[], &[]).is_err());
+        assert!(from_hook(&["eth0"], &[]).is_err());
+    }
+}
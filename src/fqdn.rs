@@ -0,0 +1,67 @@
+use std::fmt;
+use std::str::FromStr;
+
+// A fully qualified domain name in canonical form: without surrounding
+// whitespace, IDNA encoded (which implies lower case) and ending with a dot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fqdn(String);
+
+impl Fqdn {
+    // Form used in DNS queries, ending with a dot.
+    pub fn as_dns(&self) -> &str {
+        &self.0
+    }
+
+    // Form used in Gandi live API requests, without the trailing dot.
+    pub fn without_dot(&self) -> &str {
+        self.0.trim_end_matches('.')
+    }
+}
+
+impl FromStr for Fqdn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim().trim_end_matches('.');
+        if trimmed.is_empty() {
+            return Err(format!("Invalid domain name '{}'", s));
+        }
+        let ascii = idna::domain_to_ascii(trimmed)
+            .map_err(|e| format!("Invalid domain name '{}': {:?}", s, e))?;
+        if ascii.split('.').any(|label| label.is_empty()) {
+            return Err(format!("Invalid domain name '{}': empty label", s));
+        }
+        Ok(Fqdn(format!("{}.", ascii)))
+    }
+}
+
+// Displayed with Unicode labels, as in `münchen.de.`, for log output.
+// Use `as_dns` to build names for DNS queries.
+impl fmt::Display for Fqdn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&idna::domain_to_unicode(&self.0).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fqdn;
+
+    #[test]
+    fn fqdn_normalizes_ok() {
+        let expected: Fqdn = "example.com.".parse().unwrap();
+        assert_eq!(expected, " Example.COM ".parse().unwrap());
+        assert_eq!("example.com.", expected.as_dns());
+        assert_eq!("example.com", expected.without_dot());
+        let idn: Fqdn = "münchen.de".parse().unwrap();
+        assert_eq!("xn--mnchen-3ya.de.", idn.as_dns());
+        assert_eq!("münchen.de.", idn.to_string());
+    }
+
+    #[test]
+    fn fqdn_rejects_invalid() {
+        assert!("".parse::<Fqdn>().is_err());
+        assert!(" . ".parse::<Fqdn>().is_err());
+        assert!("a..b".parse::<Fqdn>().is_err());
+    }
+}
@@ -1,24 +1,135 @@
-use log::{debug, info};
+use log::{debug, info, warn};
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::boxed::Box;
 use std::error::Error;
+use std::fmt;
 use std::time::Duration;
-use reqwest::header;
+use tokio::time::sleep;
 
-static GANDI_LIVE_DNS_BASE_URL: &str = "https://dns.api.gandi.net/api/v5";
+use crate::http::with_proxy;
+
+pub static GANDI_LIVE_DNS_BASE_URL: &str = "https://api.gandi.net/v5/livedns";
+// The deprecated endpoint, with the same paths and payloads.
+pub static GANDI_LEGACY_LIVE_DNS_BASE_URL: &str = "https://dns.api.gandi.net/api/v5";
+// Gandi's test environment, which requires a separate account and API key.
+pub static GANDI_SANDBOX_LIVE_DNS_BASE_URL: &str = "https://api.sandbox.gandi.net/v5/livedns";
+// Range of TTLs Gandi accepts, in seconds.
+pub static GANDI_MIN_TTL: u64 = 300;
+pub static GANDI_MAX_TTL: u64 = 2_592_000;
+// Longest wait for a retry after rate limiting which is honoured.
+static MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+// Number of items requested per page of listings.
+static GANDI_PAGE_SIZE: usize = 100;
 
 // Used for requests and responses of the Gandi live API V5.
 // For requests mostly (ttl, values) is used.
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-struct GandiRRSet {
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GandiRRSet {
     #[serde(rename = "rrset_type", skip_serializing_if = "Option::is_none")]
-    r#type: Option<String>,
+    pub r#type: Option<String>,
     #[serde(rename = "rrset_ttl")]
-    ttl: u64,
+    pub ttl: u64,
     #[serde(rename = "rrset_name", skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
+    pub name: Option<String>,
     #[serde(rename = "rrset_values")]
-    values: Vec<String>,
+    pub values: Vec<String>,
+}
+
+// A domain of the listing of Live DNS, only the name is of interest.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct GandiDomain {
+    pub fqdn: String,
+}
+
+// Response of a created snapshot of a domain, for example
+// {"message": "Snapshot created", "id": "<uuid>"}.
+#[derive(Debug, Deserialize, PartialEq)]
+struct GandiSnapshotCreated {
+    id: String,
+}
+
+// A snapshot of a domain with the rrsets at the time it was taken.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct GandiSnapshot {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub zone_data: Vec<GandiRRSet>,
+}
+
+// A single field related error of a Gandi API error response.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct GandiFieldError {
+    #[serde(default)]
+    location: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+// Error response of the Gandi live API V5, for example:
+// {"code": 400, "message": "...", "cause": "Bad Request", "object": "HTTPBadRequest",
+//  "errors": [{"location": "body", "name": "rrset_ttl", "description": "..."}]}
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct GandiApiError {
+    // HTTP status code of the response.
+    #[serde(skip)]
+    pub status: u16,
+    #[serde(default)]
+    code: Option<u16>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    cause: Option<String>,
+    #[serde(default)]
+    object: Option<String>,
+    #[serde(default)]
+    errors: Vec<GandiFieldError>,
+}
+
+impl GandiApiError {
+    // Falls back to the plain response text, if it is not a structured error.
+    fn from_response(status: u16, text: &str) -> Self {
+        let mut error = serde_json::from_str(text).unwrap_or_else(|_| GandiApiError {
+            status,
+            code: None,
+            message: Some(text.trim().to_string()).filter(|s| !s.is_empty()),
+            cause: None,
+            object: None,
+            errors: vec![],
+        });
+        error.status = status;
+        error
+    }
+}
+
+impl Error for GandiApiError {}
+
+impl fmt::Display for GandiApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gandi request failed with status {}", self.status)?;
+        if let Some(cause) = self.cause.as_ref().or(self.object.as_ref()) {
+            write!(f, " ({})", cause)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        for error in &self.errors {
+            let name = error.name.as_deref().unwrap_or("request");
+            let description = error.description.as_deref().unwrap_or("invalid");
+            match &error.location {
+                Some(location) => write!(f, "; {} in {}: {}", name, location, description)?,
+                None => write!(f, "; {}: {}", name, description)?,
+            }
+        }
+        if let Some(code) = self.code.filter(|code| *code != self.status) {
+            write!(f, " (code {})", code)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -32,79 +143,570 @@ impl From<Duration> for Ttl {
     }
 }
 
+// Credential of the Gandi API, which selects the authorization scheme.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GandiCredential {
+    // `GANDI_PAT`, a Personal Access Token sent as bearer token.
+    Pat(String),
+    // `GANDI_API_KEY`, the API key Gandi is phasing out.
+    ApiKey(String),
+}
+
 #[derive(Debug)]
 pub struct GandiClient {
-    api_key: String,
-    timeout: Duration,
+    credential: GandiCredential,
+    // Organization the domains belong to, `GANDI_SHARING_ID`.
+    sharing_id: Option<String>,
+    // Retries of rate limited requests.
+    max_retries: u32,
+    base_url: String,
+    // Shared by all requests, for connection pooling and TLS session reuse.
+    client: reqwest::Client,
 }
 
 impl GandiClient {
-    pub fn new(api_key: String, timeout: Duration) -> Self {
-        GandiClient { api_key, timeout }
+    pub fn new(
+        credential: GandiCredential,
+        sharing_id: Option<String>,
+        max_retries: u32,
+        base_url: &str,
+        timeout: Duration,
+        proxy: Option<&str>,
+    ) -> Self {
+        GandiClient {
+            credential,
+            sharing_id,
+            max_retries,
+            base_url: base_url.trim_end_matches('/').into(),
+            client: with_proxy(reqwest::Client::builder().timeout(timeout), proxy)
+                .and_then(|builder| builder.build())
+                .expect("HTTP client cannot be initialized"),
+        }
     }
 
-    pub async fn update_a_record(
+    // Authorizes the request, on behalf of the organization if configured.
+    // The legacy endpoint only accepts API keys, in the `X-Api-Key` header.
+    fn prepare(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = match &self.sharing_id {
+            Some(id) => request.query(&[("sharing_id", id)]),
+            None => request,
+        };
+        match &self.credential {
+            GandiCredential::Pat(token) => request.bearer_auth(token),
+            GandiCredential::ApiKey(key) if self.base_url == GANDI_LEGACY_LIVE_DNS_BASE_URL => {
+                request.header("X-Api-Key", key)
+            }
+            GandiCredential::ApiKey(key) => {
+                request.header(header::AUTHORIZATION, format!("Apikey {}", key))
+            }
+        }
+    }
+
+    // Sends the request, which is retried after rate limiting as told by
+    // the `Retry-After` header, up to `max_retries` times.
+    async fn send(
         &self,
-        domain: &str,
-        name: &str,
-        value: &str,
-        ttl: Ttl,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        // curl -X PUT -H "Content-Type: application/json" \
-        //   -H "X-Api-Key: $APIKEY" \
-        //   -d '{"rrset_ttl": 10800,
-        //        "rrset_values":["<VALUE>"]}' \
-        //   https://dns.api.gandi.net/api/v5/domains/<DOMAIN>/records/<NAME>/<TYPE>
+        request: RequestBuilder,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let request = self.prepare(request);
+        let mut retries = 0;
+        loop {
+            let attempt = request
+                .try_clone()
+                .ok_or("Gandi request cannot be retried")?;
+            let response = attempt.send().await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || retries >= self.max_retries {
+                return Ok(response);
+            }
+            retries += 1;
+            let delay = retry_after(
+                response
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok()),
+                retries,
+            );
+            warn!(
+                "Rate limited by Gandi, retrying in {} seconds ({}/{})",
+                delay.as_secs(),
+                retries,
+                self.max_retries
+            );
+            sleep(delay).await;
+        }
+    }
+
+    // Path of the domain, which must not end with '.'.
+    fn domain_uri(&self, domain: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
         if domain.ends_with('.') {
             return Err(From::from(
                 "Domain in Gandi live API request must not end with '.'",
             ));
         }
-        if name.contains('.') {
-            return Err(From::from("Record name must not contain '.'"));
+        Ok(format!("{}/domains/{}", self.base_url, domain))
+    }
+
+    // Path of a single rrset, validating its components.
+    fn record_uri(
+        &self,
+        domain: &str,
+        name: &str,
+        rr_type: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let domain_uri = self.domain_uri(domain)?;
+        if name.split('.').any(|label| label.is_empty()) {
+            return Err(From::from(format!(
+                "Record name '{}' must not contain empty labels",
+                name
+            )));
         }
 
-        let uri = format!(
-            "{}/domains/{}/records/{}/A",
-            GANDI_LIVE_DNS_BASE_URL, domain, name
-        );
+        Ok(format!(
+            "{}/records/{}/{}",
+            domain_uri,
+            encode_path_segment(name),
+            rr_type
+        ))
+    }
+
+    async fn error_of(response: reqwest::Response) -> Box<dyn Error + Send + Sync> {
+        let status = response.status().as_u16();
+        match response.text().await {
+            Ok(text) => Box::new(GandiApiError::from_response(status, &text)),
+            Err(e) => Box::new(e),
+        }
+    }
+
+    // Returns None if the rrset does not exist.
+    pub async fn get_record(
+        &self,
+        domain: &str,
+        name: &str,
+        rr_type: &str,
+    ) -> Result<Option<GandiRRSet>, Box<dyn Error + Send + Sync>> {
+        let uri = self.record_uri(domain, name, rr_type)?;
+
+        debug!("Getting {}", uri);
+
+        let response = self
+            .send(
+                self.client
+                    .get(&uri)
+                    .header(header::ACCEPT, "application/json"),
+            )
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(Self::error_of(response).await);
+        }
+
+        let text = response.text().await?;
+        Ok(Some(serde_json::from_str(&text)?))
+    }
+
+    pub async fn update_record(
+        &self,
+        domain: &str,
+        name: &str,
+        rr_type: &str,
+        values: Vec<String>,
+        ttl: Ttl,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // curl -X PUT -H "Content-Type: application/json" \
+        //   -H "Authorization: Bearer $PAT" \
+        //   -d '{"rrset_ttl": 10800,
+        //        "rrset_values":["<VALUE>"]}' \
+        //   https://api.gandi.net/v5/livedns/domains/<DOMAIN>/records/<NAME>/<TYPE>
+        let uri = self.record_uri(domain, name, rr_type)?;
 
         let request_body = GandiRRSet {
             r#type: None,
             ttl: ttl.secs,
             name: None,
-            values: vec![value.into()],
+            values,
         };
 
         let request_body = serde_json::to_string(&request_body)?;
 
         debug!("Posting to {}, body {}", uri, request_body);
 
-        let client = reqwest::Client::new();
-        let response = client.put(&uri)
-            .header(header::CONTENT_TYPE, "application/json")
-            .header("X-Api-Key", &self.api_key)
-            .timeout(self.timeout)
-            .body(request_body)
-            .send()
+        let response = self
+            .send(
+                self.client
+                    .put(&uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(request_body),
+            )
             .await?;
 
         if !response.status().is_success() {
-            let text = response.text().await?;
-            let msg = format!("Gandi request failed, response is: {}", text);
-            return Err(From::from(msg));
+            return Err(Self::error_of(response).await);
         } else {
             info!("Gandi update successful");
         }
 
         Ok(())
     }
+
+    pub async fn delete_record(
+        &self,
+        domain: &str,
+        name: &str,
+        rr_type: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let uri = self.record_uri(domain, name, rr_type)?;
+
+        debug!("Deleting {}", uri);
+
+        let response = self.send(self.client.delete(&uri)).await?;
+
+        if !response.status().is_success() {
+            return Err(Self::error_of(response).await);
+        }
+
+        Ok(())
+    }
+
+    // Collects all pages of a listing, following Gandi's `Total-Count`
+    // response header.
+    async fn get_all_pages<T: DeserializeOwned>(
+        &self,
+        uri: &str,
+    ) -> Result<Vec<T>, Box<dyn Error + Send + Sync>> {
+        let mut items: Vec<T> = vec![];
+        let mut page = 1;
+        loop {
+            debug!("Getting {} page {}", uri, page);
+
+            let response = self
+                .send(
+                    self.client
+                        .get(uri)
+                        .query(&[("page", page), ("per_page", GANDI_PAGE_SIZE)])
+                        .header(header::ACCEPT, "application/json"),
+                )
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(Self::error_of(response).await);
+            }
+
+            let total: Option<usize> = response
+                .headers()
+                .get("Total-Count")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            let text = response.text().await?;
+            let page_items: Vec<T> = serde_json::from_str(&text)?;
+            let page_len = page_items.len();
+            items.extend(page_items);
+
+            let complete = match total {
+                Some(total) => items.len() >= total,
+                None => page_len < GANDI_PAGE_SIZE,
+            };
+            if complete || page_len == 0 {
+                return Ok(items);
+            }
+            page += 1;
+        }
+    }
+
+    // Replaces all rrsets of a domain by the given ones, with name and type
+    // set, in a single request.
+    pub async fn replace_records(
+        &self,
+        domain: &str,
+        rrsets: &[GandiRRSet],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let uri = format!("{}/records", self.domain_uri(domain)?);
+        let request_body = serde_json::json!({ "items": rrsets }).to_string();
+
+        debug!("Putting {} rrsets to {}", rrsets.len(), uri);
+
+        let response = self
+            .send(
+                self.client
+                    .put(&uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(request_body),
+            )
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Self::error_of(response).await);
+        }
+
+        Ok(())
+    }
+
+    // All rrsets of a domain, with name and type set.
+    pub async fn list_records(
+        &self,
+        domain: &str,
+    ) -> Result<Vec<GandiRRSet>, Box<dyn Error + Send + Sync>> {
+        let uri = format!("{}/records", self.domain_uri(domain)?);
+        self.get_all_pages(&uri).await
+    }
+
+    // Checks that the credential is accepted and has access to the domain,
+    // with a precise error otherwise.
+    pub async fn check_domain(&self, domain: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let uri = self.domain_uri(domain)?;
+
+        debug!("Getting {}", uri);
+
+        let response = self
+            .send(
+                self.client
+                    .get(&uri)
+                    .header(header::ACCEPT, "application/json"),
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        match access_error(response.status(), domain) {
+            Some(e) => Err(From::from(e)),
+            None => Err(Self::error_of(response).await),
+        }
+    }
+
+    // The domains managed with Live DNS which the credential has access to.
+    pub async fn list_domains(&self) -> Result<Vec<GandiDomain>, Box<dyn Error + Send + Sync>> {
+        let uri = format!("{}/domains", self.base_url);
+        self.get_all_pages(&uri).await
+    }
+
+    // Takes a snapshot of the records of a domain, which Gandi can restore.
+    // Returns the id of the snapshot.
+    pub async fn create_snapshot(
+        &self,
+        domain: &str,
+        name: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let uri = format!("{}/snapshots", self.domain_uri(domain)?);
+        let request_body = serde_json::json!({ "name": name }).to_string();
+
+        debug!("Posting to {}, body {}", uri, request_body);
+
+        let response = self
+            .send(
+                self.client
+                    .post(&uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(request_body),
+            )
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Self::error_of(response).await);
+        }
+
+        let text = response.text().await?;
+        let created: GandiSnapshotCreated = serde_json::from_str(&text)?;
+        Ok(created.id)
+    }
+
+    pub async fn get_snapshot(
+        &self,
+        domain: &str,
+        id: &str,
+    ) -> Result<GandiSnapshot, Box<dyn Error + Send + Sync>> {
+        let uri = format!(
+            "{}/snapshots/{}",
+            self.domain_uri(domain)?,
+            encode_path_segment(id)
+        );
+
+        debug!("Getting {}", uri);
+
+        let response = self
+            .send(
+                self.client
+                    .get(&uri)
+                    .header(header::ACCEPT, "application/json"),
+            )
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(From::from(format!(
+                "Snapshot {} of domain {} not found",
+                id, domain
+            )));
+        }
+        if !response.status().is_success() {
+            return Err(Self::error_of(response).await);
+        }
+
+        let text = response.text().await?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    // Replaces only the previously published address of the rrset by
+    // `address`, keeping values managed elsewhere, see `merge_values`.
+    // Returns the written values.
+    pub async fn merge_record(
+        &self,
+        domain: &str,
+        name: &str,
+        rr_type: &str,
+        previous: Option<&str>,
+        address: &str,
+        ttl: Ttl,
+    ) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        // The rrset as stored at Gandi is authoritative for the values to
+        // keep.
+        let values = match self.get_record(domain, name, rr_type).await? {
+            Some(rrset) => merge_values(&rrset.values, previous, address),
+            None => vec![address.into()],
+        };
+        self.update_record(domain, name, rr_type, values.clone(), ttl)
+            .await?;
+        Ok(values)
+    }
+
+    pub async fn update_a_record(
+        &self,
+        domain: &str,
+        name: &str,
+        value: &str,
+        ttl: Ttl,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_record(domain, name, "A", vec![value.into()], ttl)
+            .await
+    }
+
+    pub async fn update_aaaa_record(
+        &self,
+        domain: &str,
+        name: &str,
+        value: &str,
+        ttl: Ttl,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_record(domain, name, "AAAA", vec![value.into()], ttl)
+            .await
+    }
+
+    // Values are given unquoted, see `quote_txt`.
+    pub async fn update_txt_record(
+        &self,
+        domain: &str,
+        name: &str,
+        values: &[String],
+        ttl: Ttl,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let values = values.iter().map(|v| quote_txt(v)).collect();
+        self.update_record(domain, name, "TXT", values, ttl).await
+    }
+}
+
+// Values of an rrset after replacing the previously published address by
+// `address`, keeping foreign values. Without a previous address a single
+// value is taken to be the own one.
+pub fn merge_values(values: &[String], previous: Option<&str>, address: &str) -> Vec<String> {
+    let previous = match (previous, values) {
+        (None, [only]) => Some(only.as_str()),
+        (previous, _) => previous,
+    };
+    let mut merged: Vec<String> = values
+        .iter()
+        .filter(|v| Some(v.as_str()) != previous && v.as_str() != address)
+        .cloned()
+        .collect();
+    merged.push(address.into());
+    merged
+}
+
+// Explanation of a response refusing access to the domain.
+fn access_error(status: StatusCode, domain: &str) -> Option<String> {
+    match status {
+        StatusCode::UNAUTHORIZED => Some(
+            "Gandi credential rejected, check GANDI_PAT or GANDI_API_KEY and whether it expired"
+                .into(),
+        ),
+        StatusCode::FORBIDDEN => Some(format!(
+            "Gandi credential lacks the permission to manage the records of domain {}",
+            domain
+        )),
+        StatusCode::NOT_FOUND => Some(format!(
+            "Domain {} is not accessible to the Gandi credential, check DOMAIN_FQDN and GANDI_SHARING_ID",
+            domain
+        )),
+        _ => None,
+    }
+}
+
+// Delay before the retry of a rate limited request, the seconds of the
+// `Retry-After` header up to `MAX_RETRY_AFTER`. Without them, for example
+// for an HTTP date, the delay doubles with each retry.
+fn retry_after(header: Option<&str>, retries: u32) -> Duration {
+    match header.and_then(|v| v.trim().parse().ok()) {
+        Some(secs) => Duration::from_secs(secs).min(MAX_RETRY_AFTER),
+        None => Duration::from_secs(1 << retries.min(6)),
+    }
+}
+
+// Parses a TTL in seconds within the range Gandi accepts.
+pub fn parse_ttl(s: &str) -> Result<u64, String> {
+    let secs: u64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid TTL '{}', expected seconds", s.trim()))?;
+    if secs < GANDI_MIN_TTL || secs > GANDI_MAX_TTL {
+        return Err(format!(
+            "TTL {} is out of the range {} to {} seconds accepted by Gandi",
+            secs, GANDI_MIN_TTL, GANDI_MAX_TTL
+        ));
+    }
+    Ok(secs)
+}
+
+// Percent-encodes everything but unreserved characters and '@', which
+// names the zone apex. Multi-label names such as `office.home` are kept
+// as a single path segment.
+fn encode_path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// TXT values are sent and returned in zone file syntax, i.e. quoted.
+pub fn quote_txt(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub fn unquote_txt(value: &str) -> String {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    value.replace("\\\"", "\"").replace("\\\\", "\\")
 }
 
 #[cfg(test)]
 mod tests {
-    use super::GandiRRSet;
+    use super::{
+        access_error, merge_values, parse_ttl, quote_txt, retry_after, unquote_txt, GandiApiError,
+        GandiClient, GandiCredential, GandiDomain, GandiRRSet, GandiSnapshot, GandiSnapshotCreated,
+        GANDI_LEGACY_LIVE_DNS_BASE_URL, GANDI_LIVE_DNS_BASE_URL, GANDI_SANDBOX_LIVE_DNS_BASE_URL,
+    };
+    use reqwest::StatusCode;
     use serde_json::json;
+    use std::time::Duration;
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
 
     #[test]
     fn rrset_request_serializes_ok() {
@@ -144,4 +746,213 @@ mod tests {
         };
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn domains_deserialize_ok() {
+        let text = r#"[{"fqdn": "example.com", "domain_href": "https://api/domains/example.com",
+                        "domain_records_href": "https://api/domains/example.com/records"}]"#;
+        let actual: Vec<GandiDomain> = serde_json::from_str(text).unwrap();
+        let expected = vec![GandiDomain {
+            fqdn: "example.com".into(),
+        }];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn snapshot_created_deserializes_ok() {
+        let text =
+            r#"{"message": "Snapshot created", "id": "1e7ab8a3-cc9d-4e2a-b3b6-0e3e8a4f6d21"}"#;
+        let actual: GandiSnapshotCreated = serde_json::from_str(text).unwrap();
+        assert_eq!("1e7ab8a3-cc9d-4e2a-b3b6-0e3e8a4f6d21", actual.id);
+    }
+
+    #[test]
+    fn snapshot_deserializes_ok() {
+        let text = r#"{"id": "1e7ab8a3", "name": "before", "created_at": "2021-01-01T00:00:00Z",
+                       "automatic": false,
+                       "zone_data": [{"rrset_name": "home", "rrset_type": "A", "rrset_ttl": 300,
+                                      "rrset_values": ["192.0.2.1"]}]}"#;
+        let actual: GandiSnapshot = serde_json::from_str(text).unwrap();
+        assert_eq!("1e7ab8a3", actual.id);
+        assert_eq!(Some("before".to_string()), actual.name);
+        assert_eq!(Some("home".to_string()), actual.zone_data[0].name);
+    }
+
+    #[test]
+    fn api_error_deserializes_ok() {
+        let text = r#"{"code": 400, "message": "Validation error", "cause": "Bad Request",
+                       "object": "HTTPBadRequest",
+                       "errors": [{"location": "body", "name": "rrset_ttl",
+                                   "description": "must be >= 300"}]}"#;
+        let actual = GandiApiError::from_response(400, text);
+        assert_eq!(
+            "Gandi request failed with status 400 (Bad Request): Validation error; rrset_ttl in body: must be >= 300",
+            actual.to_string()
+        );
+    }
+
+    #[test]
+    fn api_error_falls_back_to_text() {
+        let actual = GandiApiError::from_response(502, "Bad Gateway\n");
+        assert_eq!(
+            "Gandi request failed with status 502: Bad Gateway",
+            actual.to_string()
+        );
+    }
+
+    #[test]
+    fn domain_uri_rejects_trailing_dot() {
+        let client = GandiClient::new(
+            GandiCredential::ApiKey("key".into()),
+            None,
+            0,
+            "https://api",
+            Duration::from_secs(1),
+            None,
+        );
+        assert_eq!(
+            "https://api/domains/example.com",
+            client.domain_uri("example.com").unwrap()
+        );
+        assert!(client.domain_uri("example.com.").is_err());
+    }
+
+    #[test]
+    fn record_uri_accepts_multi_label_names() {
+        let client = GandiClient::new(
+            GandiCredential::ApiKey("key".into()),
+            None,
+            0,
+            "https://api",
+            Duration::from_secs(1),
+            None,
+        );
+        assert_eq!(
+            "https://api/domains/example.com/records/office.home/A",
+            client
+                .record_uri("example.com", "office.home", "A")
+                .unwrap()
+        );
+        assert_eq!(
+            "https://api/domains/example.com/records/%2A.home/A",
+            client.record_uri("example.com", "*.home", "A").unwrap()
+        );
+        assert!(client.record_uri("example.com", "home.", "A").is_err());
+        assert!(client.record_uri("example.com", "a..home", "A").is_err());
+        assert!(client.record_uri("example.com.", "home", "A").is_err());
+    }
+
+    #[test]
+    fn credential_selects_auth_scheme() {
+        // The name and value of the authorization header of the pair.
+        let authorization = |credential, base_url| {
+            let client =
+                GandiClient::new(credential, None, 0, base_url, Duration::from_secs(1), None);
+            let request = client
+                .prepare(client.client.get("https://api/domains"))
+                .build()
+                .unwrap();
+            let headers = request.headers();
+            assert_eq!(1, headers.len());
+            let (name, value) = headers.iter().next().unwrap();
+            (name.to_string(), value.to_str().unwrap().to_string())
+        };
+        let header = |name: &str, value: &str| (name.to_string(), value.to_string());
+        let pat = || GandiCredential::Pat("pat".into());
+        let key = || GandiCredential::ApiKey("key".into());
+        assert_eq!(
+            header("authorization", "Bearer pat"),
+            authorization(pat(), GANDI_LIVE_DNS_BASE_URL)
+        );
+        assert_eq!(
+            header("authorization", "Apikey key"),
+            authorization(key(), GANDI_LIVE_DNS_BASE_URL)
+        );
+        assert_eq!(
+            header("authorization", "Apikey key"),
+            authorization(key(), GANDI_SANDBOX_LIVE_DNS_BASE_URL)
+        );
+        assert_eq!(
+            header("x-api-key", "key"),
+            authorization(key(), GANDI_LEGACY_LIVE_DNS_BASE_URL)
+        );
+    }
+
+    #[test]
+    fn sharing_id_is_added_to_requests() {
+        let client = GandiClient::new(
+            GandiCredential::Pat("pat".into()),
+            Some("7c1ebc5e-3e2d-4b7c-9b5c-0f7b1d0e6a42".into()),
+            0,
+            "https://api",
+            Duration::from_secs(1),
+            None,
+        );
+        let request = client
+            .prepare(client.client.get("https://api/domains"))
+            .query(&[("page", 2)])
+            .build()
+            .unwrap();
+        assert_eq!(
+            "https://api/domains?sharing_id=7c1ebc5e-3e2d-4b7c-9b5c-0f7b1d0e6a42&page=2",
+            request.url().as_str()
+        );
+    }
+
+    #[test]
+    fn merge_keeps_foreign_values() {
+        let current = values(&["192.0.2.1", "198.51.100.1"]);
+        assert_eq!(
+            values(&["198.51.100.1", "192.0.2.2"]),
+            merge_values(&current, Some("192.0.2.1"), "192.0.2.2")
+        );
+        assert_eq!(
+            values(&["192.0.2.1", "198.51.100.1", "192.0.2.2"]),
+            merge_values(&current, None, "192.0.2.2")
+        );
+        assert_eq!(
+            values(&["192.0.2.2"]),
+            merge_values(&values(&["192.0.2.1"]), None, "192.0.2.2")
+        );
+        assert_eq!(
+            values(&["198.51.100.1", "192.0.2.1"]),
+            merge_values(&current, Some("192.0.2.1"), "192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn access_errors_are_explained() {
+        let error = |status| access_error(status, "example.com").unwrap_or_default();
+        assert!(error(StatusCode::UNAUTHORIZED).contains("rejected"));
+        assert!(error(StatusCode::FORBIDDEN).contains("permission"));
+        assert!(error(StatusCode::NOT_FOUND).contains("not accessible"));
+        assert_eq!(None, access_error(StatusCode::BAD_GATEWAY, "example.com"));
+    }
+
+    #[test]
+    fn retry_after_is_bounded() {
+        assert_eq!(Duration::from_secs(7), retry_after(Some("7"), 1));
+        assert_eq!(Duration::from_secs(60), retry_after(Some("3600"), 1));
+        let date = Some("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(Duration::from_secs(2), retry_after(date, 1));
+        assert_eq!(Duration::from_secs(8), retry_after(None, 3));
+    }
+
+    #[test]
+    fn ttl_within_gandi_range() {
+        assert_eq!(Ok(300), parse_ttl("300"));
+        assert_eq!(Ok(2592000), parse_ttl(" 2592000 "));
+        assert!(parse_ttl("299").is_err());
+        assert!(parse_ttl("2592001").is_err());
+        assert!(parse_ttl("5m").is_err());
+    }
+
+    #[test]
+    fn txt_values_quote_roundtrip() {
+        let input = r#"heritage=gandi-dns-update "x" \ y"#;
+        let quoted = quote_txt(input);
+        assert_eq!(r#""heritage=gandi-dns-update \"x\" \\ y""#, quoted);
+        assert_eq!(input, unquote_txt(&quoted));
+        assert_eq!("plain", unquote_txt("plain"));
+    }
 }
@@ -0,0 +1,11 @@
+// Sends the requests of the client via the proxy if given, otherwise via
+// the one of the `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` env-vars.
+pub fn with_proxy(
+    builder: reqwest::ClientBuilder,
+    proxy: Option<&str>,
+) -> Result<reqwest::ClientBuilder, reqwest::Error> {
+    match proxy {
+        Some(url) => Ok(builder.proxy(reqwest::Proxy::all(url)?)),
+        None => Ok(builder),
+    }
+}
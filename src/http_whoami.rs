@@ -0,0 +1,236 @@
+use log::{debug, warn};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::bind::{local_address, Bind};
+use crate::http::with_proxy;
+use crate::ip_source::IpSource;
+use crate::{AppError, HTTP_TIMEOUT};
+
+// Longest plausible response, an IPv6 address with some whitespace.
+static MAX_RESPONSE_LEN: usize = 64;
+// Longest JSON response, which may contain more than the address.
+static MAX_JSON_RESPONSE_LEN: usize = 64 * 1024;
+
+// HTTP based services which answer with the public IP address of the
+// client, for networks which intercept DNS and thereby the whoami services.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpService {
+    // `api.ipify.org` and `api6.ipify.org`.
+    Ipify,
+    // `ipv4.icanhazip.com` and `ipv6.icanhazip.com`.
+    Icanhazip,
+}
+
+impl FromStr for HttpService {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "ipify" => Ok(HttpService::Ipify),
+            "icanhazip" => Ok(HttpService::Icanhazip),
+            other => Err(format!(
+                "Unknown HTTP IP service '{}', expected ipify or icanhazip",
+                other
+            )),
+        }
+    }
+}
+
+// The address of the response body, which has to be a single address of
+// the family and nothing else.
+pub fn ip_of_response(body: &[u8], family: RecordType) -> Result<IpAddr, String> {
+    if body.len() > MAX_RESPONSE_LEN {
+        return Err(format!("Response of {} bytes is too long", body.len()));
+    }
+    let text = std::str::from_utf8(body).map_err(|_| "Response is not text".to_string())?;
+    let ip: IpAddr = text
+        .trim()
+        .parse()
+        .map_err(|_| format!("Response '{}' is not an IP address", text.trim()))?;
+    match (ip, family) {
+        (IpAddr::V4(_), RecordType::A) | (IpAddr::V6(_), RecordType::AAAA) => Ok(ip),
+        _ => Err(format!("Response {} is not an {} address", ip, family)),
+    }
+}
+
+// The address of the field of a JSON response, a path of names separated
+// by dots such as `ip` or `client.address`.
+fn ip_of_json_response(body: &[u8], field: &str, family: RecordType) -> Result<IpAddr, String> {
+    if body.len() > MAX_JSON_RESPONSE_LEN {
+        return Err(format!("Response of {} bytes is too long", body.len()));
+    }
+    let json: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("Response is not JSON: {}", e))?;
+    let value = field
+        .split('.')
+        .try_fold(&json, |value, name| value.get(name))
+        .ok_or_else(|| format!("Response has no field '{}'", field))?;
+    let text = value
+        .as_str()
+        .ok_or_else(|| format!("Field '{}' is not a string", field))?;
+    ip_of_response(text.as_bytes(), family)
+}
+
+// Requests the URL from the local address of the family, as the services
+// answer with the address the request came from. Behind a proxy, that is
+// the address of the proxy.
+async fn fetch(url: &str, local_address: IpAddr, proxy: Option<&str>) -> Result<Vec<u8>, AppError> {
+    let http_err = |e: reqwest::Error| AppError::new(&format!("HTTP request failed: {}", e));
+    let builder = reqwest::Client::builder()
+        .local_address(local_address)
+        .timeout(HTTP_TIMEOUT);
+    let client = with_proxy(builder, proxy)
+        .and_then(|builder| builder.build())
+        .map_err(http_err)?;
+    let response = client.get(url).send().await.map_err(http_err)?;
+    if !response.status().is_success() {
+        return Err(AppError::new(&format!(
+            "HTTP request failed with status {}",
+            response.status()
+        )));
+    }
+    let body = response.bytes().await.map_err(http_err)?;
+    Ok(body.to_vec())
+}
+
+// Looks up the address with an own service, such as
+// `https://example.net/ip?format=json#ip`. The response is the address in
+// plain text, or JSON with the address in the field named by the fragment.
+async fn lookup_url(
+    url: &str,
+    family: RecordType,
+    local: IpAddr,
+    proxy: Option<&str>,
+) -> Result<IpAddr, AppError> {
+    let (url, field) = match url.split_once('#') {
+        Some((url, field)) => (url, Some(field)),
+        None => (url, None),
+    };
+    let body = fetch(url, local, proxy).await?;
+    match field {
+        Some(field) => ip_of_json_response(&body, field, family),
+        None => ip_of_response(&body, family),
+    }
+    .map_err(|e| AppError::new(&e))
+}
+
+impl HttpService {
+    fn url(self, family: RecordType) -> &'static str {
+        match (self, family == RecordType::AAAA) {
+            (HttpService::Ipify, false) => "https://api.ipify.org/",
+            (HttpService::Ipify, true) => "https://api6.ipify.org/",
+            (HttpService::Icanhazip, false) => "https://ipv4.icanhazip.com/",
+            (HttpService::Icanhazip, true) => "https://ipv6.icanhazip.com/",
+        }
+    }
+
+    // Looks up the public IPv4 address for `family` A, the IPv6 address
+    // for AAAA.
+    async fn lookup(
+        self,
+        family: RecordType,
+        local: IpAddr,
+        proxy: Option<&str>,
+    ) -> Result<IpAddr, AppError> {
+        let body = fetch(self.url(family), local, proxy).await?;
+        ip_of_response(&body, family).map_err(|e| AppError::new(&e))
+    }
+}
+
+// An own service, see `lookup_url`.
+pub struct UrlService<'a> {
+    pub url: &'a str,
+    pub bind: Option<&'a Bind>,
+    pub proxy: Option<&'a str>,
+}
+
+#[async_trait]
+impl IpSource for UrlService<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        let local = local_address(self.bind, family)?;
+        lookup_url(self.url, family, local, self.proxy).await
+    }
+}
+
+// The services of `HTTP_IP_SERVICES`, asked in order, or a single service.
+pub struct HttpServices<'a> {
+    pub services: &'a [HttpService],
+    pub bind: Option<&'a Bind>,
+    pub proxy: Option<&'a str>,
+}
+
+#[async_trait]
+impl IpSource for HttpServices<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        let local = local_address(self.bind, family)?;
+        whats_my_ip(self.services, family, local, self.proxy).await
+    }
+}
+
+// Asks the services in order, until one of them answers.
+async fn whats_my_ip(
+    services: &[HttpService],
+    family: RecordType,
+    local: IpAddr,
+    proxy: Option<&str>,
+) -> Result<IpAddr, AppError> {
+    let mut last_error = None;
+    for service in services {
+        debug!("Looking up my {} address using {:?}", family, service);
+        match service.lookup(family, local, proxy).await {
+            Ok(ip) => {
+                debug!("HTTP IP service {:?} answered {}", service, ip);
+                return Ok(ip);
+            }
+            Err(e) => {
+                warn!("HTTP IP service {:?} failed: {}", service, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| AppError::new("No HTTP IP service configured")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ip_of_json_response, ip_of_response, HttpService};
+    use std::net::{IpAddr, Ipv4Addr};
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    #[test]
+    fn ip_of_response_validates() {
+        assert_eq!(
+            Ok(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))),
+            ip_of_response(b"198.51.100.7\n", RecordType::A)
+        );
+        assert_eq!(
+            Ok("2001:db8::7".parse().unwrap()),
+            ip_of_response(b"2001:db8::7", RecordType::AAAA)
+        );
+        assert!(ip_of_response(b"2001:db8::7", RecordType::A).is_err());
+        assert!(ip_of_response(b"<html>blocked</html>", RecordType::A).is_err());
+        assert!(ip_of_response(&[b' '; 65], RecordType::A).is_err());
+        assert_eq!(Ok(HttpService::Ipify), "ipify".parse());
+        assert!("ifconfig".parse::<HttpService>().is_err());
+    }
+
+    #[test]
+    fn ip_of_json_response_finds_field() {
+        let body = br#"{"ip": "198.51.100.7", "client": {"address": "2001:db8::7"}}"#;
+        assert_eq!(
+            Ok(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))),
+            ip_of_json_response(body, "ip", RecordType::A)
+        );
+        assert_eq!(
+            Ok("2001:db8::7".parse().unwrap()),
+            ip_of_json_response(body, "client.address", RecordType::AAAA)
+        );
+        assert!(ip_of_json_response(body, "addr", RecordType::A).is_err());
+        assert!(ip_of_json_response(body, "client", RecordType::A).is_err());
+        assert!(ip_of_json_response(b"198.51.100.7", "ip", RecordType::A).is_err());
+    }
+}
@@ -0,0 +1,925 @@
+use log::{debug, info, warn};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use futures::future::{join_all, select_ok};
+use if_addrs::get_if_addrs;
+use once_cell::sync::OnceCell;
+use tokio::process::Command;
+use tokio::time::timeout;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::config::AppConfig;
+use crate::http_whoami::{ip_of_response, HttpService, HttpServices, UrlService};
+use crate::metadata::CloudProvider;
+use crate::natpmp::{NatPmpGateway, PcpGateway};
+use crate::stun::StunServers;
+use crate::upnp::UpnpGateway;
+use crate::whoami::{WhoamiService, WhoamiServices, WhoamiStrategy};
+use crate::{AppError, Resolver, HTTP_TIMEOUT};
+
+static CGNAT: &str = "carrier-grade NAT";
+
+// Standard input can only be read once, but is asked for both families.
+static STDIN: OnceCell<String> = OnceCell::new();
+
+// Flags of IPv6 addresses in `/proc/net/if_inet6`, see linux/if_addr.h.
+static IF_INET6: &str = "/proc/net/if_inet6";
+static IFA_F_TEMPORARY: u32 = 0x01;
+static IFA_F_DADFAILED: u32 = 0x08;
+static IFA_F_DEPRECATED: u32 = 0x20;
+static IFA_F_TENTATIVE: u32 = 0x40;
+
+// A way to look up the public IP address. The sources are asked and their
+// answers checked by `whats_my_ip`.
+#[async_trait]
+pub trait IpSource: Send + Sync {
+    // The public IPv4 address for `family` A, the IPv6 address for AAAA.
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError>;
+}
+
+// An entry of `IP_SOURCES`, which names its source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpSourceKind {
+    // The services of `WHOAMI_SERVICES`, asked as by `WHOAMI_STRATEGY`.
+    Dns,
+    // A single whoami service, e.g. `opendns` or `dns:opendns`.
+    Whoami(WhoamiService),
+    // The services of `HTTP_IP_SERVICES`, in order.
+    Http,
+    // A single HTTP service, e.g. `http:ipify`.
+    HttpService(HttpService),
+    // An own HTTP service, e.g. `https://example.net/ip?format=json#ip`.
+    Url(String),
+    // The public address of a local network interface, e.g.
+    // `interface:eth0`, for hosts which have one.
+    Interface(String),
+    // The external address of the router, asked with UPnP.
+    Upnp,
+    // The external address of the gateway, asked with NAT-PMP or PCP.
+    NatPmp,
+    Pcp,
+    // The reflexive address seen by the servers of `STUN_SERVERS`.
+    Stun,
+    // The public address of a cloud instance, e.g. `metadata:aws`.
+    Metadata(CloudProvider),
+    // The output of a command, e.g. `exec:/usr/local/bin/get-ip`.
+    Exec(String),
+    // The address written to a file, e.g. `file:/run/current-ip`, or
+    // passed on standard input, for hooks of other daemons.
+    File(PathBuf),
+    Stdin,
+}
+
+impl FromStr for IpSourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.split_once(':') {
+            None if s == "dns" => Ok(IpSourceKind::Dns),
+            None if s == "http" => Ok(IpSourceKind::Http),
+            None if s == "upnp" => Ok(IpSourceKind::Upnp),
+            None if s == "natpmp" => Ok(IpSourceKind::NatPmp),
+            None if s == "pcp" => Ok(IpSourceKind::Pcp),
+            None if s == "stun" => Ok(IpSourceKind::Stun),
+            None if s == "stdin" => Ok(IpSourceKind::Stdin),
+            Some(("http", rest)) | Some(("https", rest)) if rest.starts_with("//") => {
+                Ok(IpSourceKind::Url(s.into()))
+            }
+            Some(("dns", service)) => service.parse().map(IpSourceKind::Whoami),
+            Some(("http", service)) => service.parse().map(IpSourceKind::HttpService),
+            Some(("metadata", provider)) => provider.parse().map(IpSourceKind::Metadata),
+            Some(("exec", command)) if !command.trim().is_empty() => {
+                Ok(IpSourceKind::Exec(command.trim().into()))
+            }
+            Some(("file", path)) if !path.trim().is_empty() => {
+                Ok(IpSourceKind::File(path.trim().into()))
+            }
+            Some(("interface", name)) if !name.trim().is_empty() => {
+                Ok(IpSourceKind::Interface(name.trim().into()))
+            }
+            None => s.parse().map(IpSourceKind::Whoami).map_err(|_| {
+                format!(
+                    "Unknown IP source '{}', expected dns, http, upnp, natpmp, pcp, stun, stdin, a whoami service, http:SERVICE, a URL, metadata:PROVIDER, exec:COMMAND, file:PATH or interface:NAME",
+                    s
+                )
+            }),
+            Some(_) => Err(format!("Unknown IP source '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for IpSourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpSourceKind::Dns => write!(f, "whoami services"),
+            IpSourceKind::Whoami(service) => write!(f, "whoami service {:?}", service),
+            IpSourceKind::Http => write!(f, "HTTP services"),
+            IpSourceKind::HttpService(service) => write!(f, "HTTP service {:?}", service),
+            IpSourceKind::Url(url) => write!(f, "HTTP service {}", url),
+            IpSourceKind::Interface(name) => write!(f, "interface {}", name),
+            IpSourceKind::Upnp => write!(f, "UPnP gateway"),
+            IpSourceKind::NatPmp => write!(f, "NAT-PMP gateway"),
+            IpSourceKind::Pcp => write!(f, "PCP gateway"),
+            IpSourceKind::Stun => write!(f, "STUN servers"),
+            IpSourceKind::Metadata(provider) => write!(f, "{:?} instance metadata", provider),
+            IpSourceKind::Exec(command) => write!(f, "command {}", command),
+            IpSourceKind::File(path) => write!(f, "file {}", path.display()),
+            IpSourceKind::Stdin => write!(f, "standard input"),
+        }
+    }
+}
+
+impl IpSourceKind {
+    // Whether the source reports the WAN address of this host or its
+    // gateway, rather than the address seen from the internet.
+    fn is_local(&self) -> bool {
+        matches!(
+            self,
+            IpSourceKind::Interface(_)
+                | IpSourceKind::Upnp
+                | IpSourceKind::NatPmp
+                | IpSourceKind::Pcp
+        )
+    }
+
+    // The source of the entry, which looks up the address.
+    pub fn source<'a>(
+        &'a self,
+        config: &'a AppConfig,
+        bootstrap_resolver: &'a Resolver,
+    ) -> Box<dyn IpSource + 'a> {
+        let bind = config.bind.as_ref();
+        let proxy = config.proxy_url.as_deref();
+        match self {
+            IpSourceKind::Dns => Box::new(WhoamiServices {
+                resolver: bootstrap_resolver,
+                services: &config.whoami_services,
+                strategy: config.whoami_strategy,
+                allow_private_ip: config.allow_private_ip,
+                bind,
+            }),
+            IpSourceKind::Whoami(service) => Box::new(WhoamiServices {
+                resolver: bootstrap_resolver,
+                services: std::slice::from_ref(service),
+                strategy: WhoamiStrategy::Fallback,
+                allow_private_ip: config.allow_private_ip,
+                bind,
+            }),
+            IpSourceKind::Http => Box::new(HttpServices {
+                services: &config.http_ip_services,
+                bind,
+                proxy,
+            }),
+            IpSourceKind::HttpService(service) => Box::new(HttpServices {
+                services: std::slice::from_ref(service),
+                bind,
+                proxy,
+            }),
+            IpSourceKind::Url(url) => Box::new(UrlService { url, bind, proxy }),
+            IpSourceKind::Interface(name) => Box::new(Interface {
+                name,
+                temporary: config.ipv6_temporary_addresses,
+            }),
+            IpSourceKind::Upnp => Box::new(UpnpGateway { bind }),
+            IpSourceKind::NatPmp => Box::new(NatPmpGateway {
+                gateway: config.gateway,
+                bind,
+            }),
+            IpSourceKind::Pcp => Box::new(PcpGateway {
+                gateway: config.gateway,
+                bind,
+            }),
+            IpSourceKind::Stun => Box::new(StunServers {
+                resolver: bootstrap_resolver,
+                servers: &config.stun_servers,
+                bind,
+            }),
+            IpSourceKind::Metadata(provider) => Box::new(*provider),
+            IpSourceKind::Exec(command) => Box::new(Exec(command)),
+            IpSourceKind::File(path) => Box::new(File(path)),
+            IpSourceKind::Stdin => Box::new(Stdin),
+        }
+    }
+}
+
+struct Interface<'a> {
+    name: &'a str,
+    // Whether temporary and deprecated IPv6 addresses may be taken.
+    temporary: bool,
+}
+
+#[async_trait]
+impl IpSource for Interface<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        interface_address(self.name, family, self.temporary)
+    }
+}
+
+struct Exec<'a>(&'a str);
+
+#[async_trait]
+impl IpSource for Exec<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        exec_address(self.0, family).await
+    }
+}
+
+struct File<'a>(&'a Path);
+
+#[async_trait]
+impl IpSource for File<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        let text = fs::read_to_string(self.0)
+            .map_err(|e| AppError::new(&format!("Cannot read {}: {}", self.0.display(), e)))?;
+        ip_of_lines(&text, family)
+    }
+}
+
+struct Stdin;
+
+#[async_trait]
+impl IpSource for Stdin {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        let text = STDIN.get_or_try_init(|| {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text).map(|_| text)
+        });
+        let text =
+            text.map_err(|e| AppError::new(&format!("Cannot read standard input: {}", e)))?;
+        ip_of_lines(text, family)
+    }
+}
+
+fn reserved_range_v4(ip: Ipv4Addr) -> Option<&'static str> {
+    let [a, b, ..] = ip.octets();
+    if ip.is_private() {
+        Some("private (RFC 1918)")
+    } else if a == 100 && (b & 0xc0) == 64 {
+        // Shared address space 100.64.0.0/10.
+        Some(CGNAT)
+    } else if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if a == 0 || ip.is_broadcast() || ip.is_multicast() {
+        // "This network" 0.0.0.0/8, including the unspecified address.
+        Some("not unicast")
+    } else if ip.is_documentation() {
+        Some("documentation")
+    } else if a == 192 && b == 0 && ip.octets()[2] == 0 {
+        Some("IETF protocol assignments")
+    } else if a == 198 && (b & 0xfe) == 18 {
+        Some("benchmarking")
+    } else if a >= 240 {
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+fn reserved_range_v6(ip: Ipv6Addr) -> Option<&'static str> {
+    let first = ip.segments()[0];
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if (first & 0xffc0) == 0xfe80 {
+        Some("link-local")
+    } else if (first & 0xfe00) == 0xfc00 {
+        Some("unique local")
+    } else if first == 0x2001 && ip.segments()[1] == 0x0db8 {
+        Some("documentation")
+    } else if (first & 0xe000) != 0x2000 {
+        // Outside of global unicast 2000::/3.
+        Some("not global unicast")
+    } else {
+        None
+    }
+}
+
+// The kind of address range the address is reserved for, if it is not
+// reachable from the internet, such as private or link-local.
+pub fn reserved_range(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => reserved_range_v4(ip),
+        IpAddr::V6(ip) => reserved_range_v6(ip),
+    }
+}
+
+// Whether the address may be the one of the WAN side of a gateway, which
+// is public or, behind carrier-grade NAT, a shared address.
+pub fn is_wan_address(ip: IpAddr) -> bool {
+    reserved_range(ip).filter(|range| *range != CGNAT).is_none()
+}
+
+// The answer of a source racing others, which must not win with an address
+// which is not public, unless private addresses are allowed.
+pub fn race_answer(ip: IpAddr, allow_private_ip: bool) -> Result<IpAddr, AppError> {
+    match reserved_range(ip) {
+        Some(range) if !allow_private_ip => Err(AppError::new(&format!(
+            "Answer {} is not public but {}",
+            ip, range
+        ))),
+        _ => Ok(ip),
+    }
+}
+
+// The flags of the IPv6 addresses of the interface, from the lines of
+// `/proc/net/if_inet6`: address, index, prefix length, scope, flags, name.
+fn ipv6_flags(if_inet6: &str, name: &str) -> Vec<(Ipv6Addr, u32)> {
+    if_inet6
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 || fields[5] != name || fields[0].len() != 32 {
+                return None;
+            }
+            let ip = u128::from_str_radix(fields[0], 16).ok()?;
+            let flags = u32::from_str_radix(fields[4], 16).ok()?;
+            Some((Ipv6Addr::from(ip), flags))
+        })
+        .collect()
+}
+
+// Whether an IPv6 address is stable: neither a temporary address of the
+// privacy extensions, which change every few hours, nor deprecated or not
+// yet usable. Addresses without known flags are taken as stable.
+fn is_stable(ip: &IpAddr, flags: &[(Ipv6Addr, u32)]) -> bool {
+    let unstable = IFA_F_TEMPORARY | IFA_F_DEPRECATED | IFA_F_TENTATIVE | IFA_F_DADFAILED;
+    match ip {
+        IpAddr::V6(ip) => !flags.iter().any(|(a, f)| a == ip && f & unstable != 0),
+        IpAddr::V4(_) => true,
+    }
+}
+
+// The first public address of the family on the interface, or a shared
+// address of carrier-grade NAT, which is reported by `whats_my_ip`.
+fn interface_address(name: &str, family: RecordType, temporary: bool) -> Result<IpAddr, AppError> {
+    let addresses: Vec<IpAddr> = get_if_addrs()
+        .map_err(|e| AppError::new(&format!("Cannot list network interfaces: {}", e)))?
+        .into_iter()
+        .filter(|i| i.name == name)
+        .map(|i| i.ip())
+        .collect();
+    if addresses.is_empty() {
+        return Err(AppError::new(&format!(
+            "Network interface {} not found or without addresses",
+            name
+        )));
+    }
+    let of_family: Vec<IpAddr> = addresses
+        .into_iter()
+        .filter(|ip| ip.is_ipv6() == (family == RecordType::AAAA))
+        .collect();
+    // Only Linux has the flags, other systems take the first address.
+    let flags = if family == RecordType::AAAA && !temporary {
+        let if_inet6 = fs::read_to_string(IF_INET6).unwrap_or_default();
+        ipv6_flags(&if_inet6, name)
+    } else {
+        Vec::new()
+    };
+    of_family
+        .iter()
+        .copied()
+        .find(|ip| is_wan_address(*ip) && is_stable(ip, &flags))
+        .ok_or_else(|| {
+            let skipped: Vec<String> = of_family.iter().map(|ip| ip.to_string()).collect();
+            AppError::new(&format!(
+                "Network interface {} has no public {} address (skipped: {})",
+                name,
+                family,
+                skipped.join(" ")
+            ))
+        })
+}
+
+// The address printed by the command, which is run with its arguments
+// split at whitespace, without a shell. `IP_FAMILY` tells it whether to
+// print the IPv4 (A) or IPv6 (AAAA) address.
+async fn exec_address(command: &str, family: RecordType) -> Result<IpAddr, AppError> {
+    let mut args = command.split_whitespace();
+    let program = args.next().unwrap_or_default();
+    let output = Command::new(program)
+        .args(args)
+        .env("IP_FAMILY", family.to_string())
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = timeout(HTTP_TIMEOUT, output)
+        .await
+        .map_err(|_| AppError::new(&format!("Command {} timed out", program)))?
+        .map_err(|e| AppError::new(&format!("Cannot run command {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(AppError::new(&format!(
+            "Command {} failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    ip_of_response(&output.stdout, family).map_err(|e| AppError::new(&e))
+}
+
+// The first address of the family of text with an address per line, such
+// as the IPv4 and IPv6 address of an interface written by a hook.
+fn ip_of_lines(text: &str, family: RecordType) -> Result<IpAddr, AppError> {
+    let mut addresses = vec![];
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let ip: IpAddr = line
+            .parse()
+            .map_err(|_| AppError::new(&format!("'{}' is not an IP address", line)))?;
+        addresses.push(ip);
+    }
+    addresses
+        .into_iter()
+        .find(|ip| ip.is_ipv6() == (family == RecordType::AAAA))
+        .ok_or_else(|| AppError::new(&format!("No {} address given", family)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpStrategy {
+    // Ask the sources in order, until one of them answers.
+    Fallback,
+    // Ask all sources concurrently, and use the address a majority of them
+    // answers with, so that a single misbehaving service cannot cause a
+    // bad update.
+    Majority,
+    // Ask all sources concurrently, the first valid answer wins.
+    Race,
+}
+
+impl FromStr for IpStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "fallback" => Ok(IpStrategy::Fallback),
+            "majority" => Ok(IpStrategy::Majority),
+            "race" => Ok(IpStrategy::Race),
+            other => Err(format!(
+                "Unknown IP strategy '{}', expected fallback, majority or race",
+                other
+            )),
+        }
+    }
+}
+
+async fn lookup_logged(
+    (kind, source): &(&IpSourceKind, Box<dyn IpSource + '_>),
+    family: RecordType,
+) -> Result<IpAddr, AppError> {
+    debug!("Looking up my {} address using {}", family, kind);
+    let result = source.current_ip(family).await;
+    match &result {
+        Ok(ip) => debug!("IP source {} answered {}", kind, ip),
+        Err(e) => warn!("IP source {} failed: {}", kind, e),
+    }
+    result
+}
+
+// The address more than half of all sources answered with, failed sources
+// included.
+fn majority(answers: &[Option<IpAddr>]) -> Option<IpAddr> {
+    answers.iter().flatten().copied().find(|ip| {
+        let votes = answers.iter().filter(|a| **a == Some(*ip)).count();
+        votes * 2 > answers.len()
+    })
+}
+
+fn cgnat_error(source: &IpSourceKind, ip: IpAddr) -> AppError {
+    AppError::new(&format!(
+        "The {} reports the address {} of carrier-grade NAT: the provider shares a public address between customers, which cannot be reached from the internet. Ask the provider for a public IPv4 address, or update AAAA records only",
+        source, ip
+    ))
+}
+
+// Detects carrier-grade NAT (or another NAT in front of the gateway) from
+// the answers of the sources, which otherwise would publish an address
+// which cannot be reached.
+fn check_nat(sources: &[&IpSourceKind], answers: &[Option<IpAddr>]) -> Result<(), AppError> {
+    let answered = sources
+        .iter()
+        .zip(answers)
+        .filter_map(|(s, a)| a.map(|ip| (s, ip)));
+    if let Some((source, ip)) = answered
+        .clone()
+        .find(|(_, ip)| reserved_range(*ip) == Some(CGNAT))
+    {
+        return Err(cgnat_error(source, ip));
+    }
+    let local = answered.clone().find(|(s, _)| s.is_local());
+    let remote = answered.clone().find(|(s, _)| !s.is_local());
+    if let (Some((local, wan)), Some((remote, seen))) = (local, remote) {
+        if wan != seen {
+            return Err(AppError::new(&format!(
+                "The {} reports the address {}, but the {} sees {}: the network is behind another NAT, such as carrier-grade NAT of the provider, and cannot be reached from the internet",
+                local, wan, remote, seen
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Looks up the public IPv4 address for `family` A with `IP_SOURCES`, the
+// IPv6 address for AAAA with `IP_SOURCES_V6`.
+pub async fn whats_my_ip(
+    config: &AppConfig,
+    bootstrap_resolver: &Resolver,
+    family: RecordType,
+) -> Result<IpAddr, AppError> {
+    let sources: Vec<_> = config
+        .ip_sources_of(family)
+        .iter()
+        .map(|kind| (kind, kind.source(config, bootstrap_resolver)))
+        .collect();
+    ask(
+        &sources,
+        config.ip_strategy,
+        config.allow_private_ip,
+        family,
+    )
+    .await
+}
+
+// Asks the sources as by the strategy. Unless private addresses are allowed,
+// carrier-grade NAT is detected from the IPv4 answers; IPv6 is not
+// translated, and local sources see the stable address while remote ones
+// see the temporary one in use. As the fallback strategy
+// stops at the first answer, the first source of the other kind, local or
+// remote, is asked as well for the comparison.
+async fn ask(
+    sources: &[(&IpSourceKind, Box<dyn IpSource + '_>)],
+    strategy: IpStrategy,
+    allow_private_ip: bool,
+    family: RecordType,
+) -> Result<IpAddr, AppError> {
+    match strategy {
+        IpStrategy::Fallback => {
+            let mut last_error = None;
+            for source in sources {
+                match lookup_logged(source, family).await {
+                    Ok(ip) => {
+                        if !allow_private_ip && family == RecordType::A {
+                            let other = sources
+                                .iter()
+                                .find(|(kind, _)| kind.is_local() != source.0.is_local());
+                            match other {
+                                Some(other) => {
+                                    let seen = lookup_logged(other, family).await.ok();
+                                    check_nat(&[source.0, other.0], &[Some(ip), seen])?;
+                                }
+                                None => check_nat(&[source.0], &[Some(ip)])?,
+                            }
+                        }
+                        return Ok(ip);
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(last_error.unwrap_or_else(|| AppError::new("No IP source configured")))
+        }
+        IpStrategy::Race => {
+            if sources.is_empty() {
+                return Err(AppError::new("No IP source configured"));
+            }
+            let lookups = sources.iter().map(|source| {
+                Box::pin(async move {
+                    let ip = lookup_logged(source, family).await?;
+                    race_answer(ip, allow_private_ip).map_err(|e| {
+                        warn!("IP source {} failed: {}", source.0, e);
+                        e
+                    })
+                })
+            });
+            let (ip, _pending) = select_ok(lookups).await?;
+            Ok(ip)
+        }
+        IpStrategy::Majority => {
+            let lookups = sources.iter().map(|source| lookup_logged(source, family));
+            let answers: Vec<Option<IpAddr>> = join_all(lookups)
+                .await
+                .into_iter()
+                .map(|r| r.ok())
+                .collect();
+            let kinds: Vec<&IpSourceKind> = sources.iter().map(|(kind, _)| *kind).collect();
+            if !allow_private_ip && family == RecordType::A {
+                check_nat(&kinds, &answers)?;
+            }
+            let summary: Vec<String> = kinds
+                .iter()
+                .zip(&answers)
+                .map(|(kind, answer)| match answer {
+                    Some(ip) => format!("{}={}", kind, ip),
+                    None => format!("{}=failed", kind),
+                })
+                .collect();
+            match majority(&answers) {
+                Some(ip) => {
+                    info!("IP sources agree on {} ({})", ip, summary.join(", "));
+                    Ok(ip)
+                }
+                None => Err(AppError::new(&format!(
+                    "No majority of IP sources agrees on my {} address ({})",
+                    family,
+                    summary.join(", ")
+                ))),
+            }
+        }
+    }
+}
+
+// The sources of `IP_SOURCE`, which names the source to ask first, with the
+// others of the default chain as fallback. Likewise for `WHOAMI_SERVICE`.
+pub fn preferring<T: Clone + PartialEq>(first: T, default: &[T]) -> Vec<T> {
+    let others = default.iter().filter(|s| **s != first).cloned();
+    std::iter::once(first.clone()).chain(others).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ask, check_nat, exec_address, ip_of_lines, ipv6_flags, is_stable, majority, preferring,
+        race_answer, reserved_range, IpSource, IpSourceKind, IpStrategy,
+    };
+    use crate::http_whoami::HttpService;
+    use crate::metadata::CloudProvider;
+    use crate::whoami::WhoamiService;
+    use crate::AppError;
+    use async_trait::async_trait;
+    use std::net::IpAddr;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    #[test]
+    fn ip_source_parses_ok() {
+        assert_eq!(Ok(IpSourceKind::Dns), "dns".parse());
+        assert_eq!(Ok(IpSourceKind::Upnp), "upnp".parse());
+        assert_eq!(Ok(IpSourceKind::Pcp), "pcp".parse());
+        assert_eq!(
+            Ok(IpSourceKind::Metadata(CloudProvider::Aws)),
+            "metadata:aws".parse()
+        );
+        assert_eq!(
+            Ok(IpSourceKind::Whoami(WhoamiService::OpenDns)),
+            "opendns".parse()
+        );
+        assert_eq!(
+            Ok(IpSourceKind::Whoami(WhoamiService::Akamai)),
+            " dns:akamai".parse()
+        );
+        assert_eq!(
+            Ok(IpSourceKind::HttpService(HttpService::Ipify)),
+            "http:ipify".parse()
+        );
+        assert!("http:foo".parse::<IpSourceKind>().is_err());
+        assert_eq!(
+            Ok(IpSourceKind::Url(
+                "https://example.net/ip?format=json#ip".into()
+            )),
+            "https://example.net/ip?format=json#ip".parse()
+        );
+        assert_eq!(
+            Ok(IpSourceKind::Interface("eth0".into())),
+            "interface:eth0".parse()
+        );
+        assert!("interface:".parse::<IpSourceKind>().is_err());
+        assert_eq!(Ok(IpSourceKind::Stdin), "stdin".parse());
+        assert_eq!(
+            Ok(IpSourceKind::File("/run/current-ip".into())),
+            "file:/run/current-ip".parse()
+        );
+        assert_eq!(
+            Ok(IpSourceKind::Exec("/usr/local/bin/get-ip --v4".into())),
+            "exec:/usr/local/bin/get-ip --v4".parse()
+        );
+        assert!("foo:eth0".parse::<IpSourceKind>().is_err());
+        assert!("foo".parse::<IpSourceKind>().is_err());
+    }
+
+    #[tokio::test]
+    async fn exec_address_parses_output() {
+        assert_eq!(
+            "198.51.100.7".parse::<IpAddr>().unwrap(),
+            exec_address("echo 198.51.100.7", RecordType::A)
+                .await
+                .unwrap()
+        );
+        assert!(exec_address("echo 198.51.100.7", RecordType::AAAA)
+            .await
+            .is_err());
+        assert!(exec_address("false", RecordType::A).await.is_err());
+    }
+
+    #[test]
+    fn ip_of_lines_picks_family() {
+        let text = "198.51.100.7\n\n 2001:db8::7\n";
+        assert_eq!(
+            "2001:db8::7".parse::<IpAddr>().unwrap(),
+            ip_of_lines(text, RecordType::AAAA).unwrap()
+        );
+        assert_eq!(
+            "198.51.100.7".parse::<IpAddr>().unwrap(),
+            ip_of_lines(text, RecordType::A).unwrap()
+        );
+        assert!(ip_of_lines("2001:db8::7", RecordType::A).is_err());
+        assert!(ip_of_lines("198.51.100.7 up", RecordType::A).is_err());
+    }
+
+    // A source which always answers the same.
+    struct Fixed(Option<&'static str>);
+
+    #[async_trait]
+    impl IpSource for Fixed {
+        async fn current_ip(&self, _family: RecordType) -> Result<IpAddr, AppError> {
+            self.0
+                .map(|ip| ip.parse().unwrap())
+                .ok_or_else(|| AppError::new("No answer"))
+        }
+    }
+
+    #[tokio::test]
+    async fn ask_falls_back_and_votes() {
+        let http = IpSourceKind::Http;
+        let sources: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&http, Box::new(Fixed(None))),
+            (&http, Box::new(Fixed(Some("198.51.99.1")))),
+            (&http, Box::new(Fixed(Some("198.51.99.2")))),
+        ];
+        let fallback = ask(&sources, IpStrategy::Fallback, false, RecordType::A).await;
+        assert_eq!("198.51.99.1", fallback.unwrap().to_string());
+        let majority = ask(&sources, IpStrategy::Majority, false, RecordType::A).await;
+        assert!(majority.is_err());
+
+        let cgnat: Vec<(&IpSourceKind, Box<dyn IpSource>)> =
+            vec![(&http, Box::new(Fixed(Some("100.64.7.1"))))];
+        assert!(ask(&cgnat, IpStrategy::Fallback, false, RecordType::A)
+            .await
+            .is_err());
+        assert!(ask(&cgnat, IpStrategy::Fallback, true, RecordType::A)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn ask_races_for_valid_answer() {
+        let http = IpSourceKind::Http;
+        let sources: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&http, Box::new(Fixed(Some("192.168.1.2")))),
+            (&http, Box::new(Fixed(None))),
+            (&http, Box::new(Fixed(Some("198.51.99.1")))),
+        ];
+        let race = ask(&sources, IpStrategy::Race, false, RecordType::A).await;
+        assert_eq!("198.51.99.1", race.unwrap().to_string());
+        assert!(ask(&sources[..2], IpStrategy::Race, false, RecordType::A)
+            .await
+            .is_err());
+        assert!(ask(&sources[..2], IpStrategy::Race, true, RecordType::A)
+            .await
+            .is_ok());
+        assert!(ask(&[], IpStrategy::Race, false, RecordType::A)
+            .await
+            .is_err());
+        assert_eq!(Ok(IpStrategy::Race), "race".parse());
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(race_answer(ip("198.51.99.1"), false).is_ok());
+        assert!(race_answer(ip("100.64.7.1"), false).is_err());
+        assert!(race_answer(ip("10.0.0.1"), true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn ask_falls_back_and_compares_local_with_remote() {
+        let upnp = IpSourceKind::Upnp;
+        let http = IpSourceKind::Http;
+        let nat: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&upnp, Box::new(Fixed(Some("198.51.99.1")))),
+            (&http, Box::new(Fixed(Some("198.51.99.2")))),
+        ];
+        assert!(ask(&nat, IpStrategy::Fallback, false, RecordType::A)
+            .await
+            .is_err());
+        let allowed = ask(&nat, IpStrategy::Fallback, true, RecordType::A).await;
+        assert_eq!("198.51.99.1", allowed.unwrap().to_string());
+
+        // A remote source answering first is compared with the local one.
+        let remote_first: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&http, Box::new(Fixed(Some("198.51.99.2")))),
+            (&upnp, Box::new(Fixed(Some("198.51.99.1")))),
+        ];
+        assert!(
+            ask(&remote_first, IpStrategy::Fallback, false, RecordType::A)
+                .await
+                .is_err()
+        );
+
+        // Failed comparisons do not fail the lookup.
+        let agreeing: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&upnp, Box::new(Fixed(Some("198.51.99.1")))),
+            (&http, Box::new(Fixed(None))),
+        ];
+        let ip = ask(&agreeing, IpStrategy::Fallback, false, RecordType::A).await;
+        assert_eq!("198.51.99.1", ip.unwrap().to_string());
+
+        // The stable address of the interface differs from the temporary
+        // one seen remotely, which is no NAT.
+        let interface = IpSourceKind::Interface("eth0".into());
+        let ipv6: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&interface, Box::new(Fixed(Some("2001:db8::7")))),
+            (&http, Box::new(Fixed(Some("2001:db8::aaaa:bbbb:cccc")))),
+        ];
+        let ip = ask(&ipv6, IpStrategy::Fallback, false, RecordType::AAAA).await;
+        assert_eq!("2001:db8::7", ip.unwrap().to_string());
+        let ipv6: Vec<(&IpSourceKind, Box<dyn IpSource>)> = vec![
+            (&interface, Box::new(Fixed(Some("2001:db8::7")))),
+            (&http, Box::new(Fixed(Some("2001:db8::aaaa:bbbb:cccc")))),
+            (&http, Box::new(Fixed(Some("2001:db8::aaaa:bbbb:cccc")))),
+        ];
+        let ip = ask(&ipv6, IpStrategy::Majority, false, RecordType::AAAA).await;
+        assert_eq!("2001:db8::aaaa:bbbb:cccc", ip.unwrap().to_string());
+    }
+
+    #[test]
+    fn check_nat_detects_cgnat() {
+        let ip = |s: &str| Some(s.parse().unwrap());
+        let sources = [&IpSourceKind::Upnp, &IpSourceKind::Http];
+        assert!(check_nat(&sources, &[ip("198.51.99.1"), ip("198.51.99.1")]).is_ok());
+        assert!(check_nat(&sources, &[None, ip("198.51.99.1")]).is_ok());
+        assert!(check_nat(&sources, &[ip("100.64.7.1"), None]).is_err());
+        assert!(check_nat(&sources, &[ip("198.51.99.1"), ip("198.51.99.2")]).is_err());
+        assert!(check_nat(&sources[1..], &[ip("100.64.7.1")]).is_err());
+    }
+
+    #[test]
+    fn ipv6_flags_select_stable() {
+        let if_inet6 = "\
+20010db8000000000000000000000007 02 40 00 00 eth0
+20010db8000000000000aaaabbbbcccc 02 40 00 01 eth0
+20010db8000000000000000000000008 02 40 00 20 eth0
+20010db8000000000000000000000009 03 40 00 00 wlan0
+fe800000000000000000000000000001 02 40 20 80 eth0
+";
+        let flags = ipv6_flags(if_inet6, "eth0");
+        assert_eq!(4, flags.len());
+        let stable = |s: &str| is_stable(&s.parse().unwrap(), &flags);
+        assert!(stable("2001:db8::7"));
+        assert!(!stable("2001:db8::aaaa:bbbb:cccc"));
+        assert!(!stable("2001:db8::8"));
+        assert!(stable("2001:db8::9"));
+        assert!(stable("198.51.100.7"));
+    }
+
+    #[test]
+    fn is_public_skips_reserved() {
+        let public = |s: &str| reserved_range(s.parse().unwrap()).is_none();
+        assert!(public("198.51.99.1"));
+        assert!(public("100.128.0.1"));
+        assert!(!public("192.168.1.2"));
+        assert!(!public("10.0.0.1"));
+        assert!(!public("100.64.0.1"));
+        assert!(!public("169.254.1.1"));
+        assert!(!public("127.0.0.1"));
+        assert!(public("2a01:4f8::1"));
+        assert!(!public("fe80::1"));
+        assert!(!public("fd00::1"));
+        assert!(!public("::1"));
+        assert!(!public("2001:db8::1"));
+        let range = |s: &str| reserved_range(s.parse().unwrap());
+        assert_eq!(Some("carrier-grade NAT"), range("100.100.0.1"));
+        assert_eq!(Some("not unicast"), range("0.1.2.3"));
+        assert_eq!(Some("IETF protocol assignments"), range("192.0.0.9"));
+        assert_eq!(None, range("192.0.1.1"));
+        assert_eq!(Some("benchmarking"), range("198.19.255.1"));
+        assert_eq!(None, range("198.20.0.1"));
+        assert_eq!(Some("reserved"), range("240.0.0.1"));
+        assert_eq!(Some("not unicast"), range("255.255.255.255"));
+        assert_eq!(Some("unique local"), range("fd00::1"));
+        assert_eq!(None, range("2a01:4f8::1"));
+    }
+
+    #[test]
+    fn majority_counts_failed_sources() {
+        let a = Some("192.0.2.1".parse().unwrap());
+        let b = Some("192.0.2.2".parse().unwrap());
+        assert_eq!(a, majority(&[a, b, a]));
+        assert_eq!(a, majority(&[b, a, a]));
+        assert_eq!(None, majority(&[a, b]));
+        assert_eq!(None, majority(&[a, None, None]));
+        assert_eq!(a, majority(&[a, a, None]));
+        assert_eq!(None, majority(&[]));
+    }
+
+    #[test]
+    fn preferring_keeps_fallbacks() {
+        let default = vec![IpSourceKind::Dns, IpSourceKind::Http];
+        assert_eq!(default, preferring(IpSourceKind::Dns, &default));
+        assert_eq!(
+            vec![IpSourceKind::Http, IpSourceKind::Dns],
+            preferring(IpSourceKind::Http, &default)
+        );
+    }
+}
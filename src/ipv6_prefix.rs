@@ -0,0 +1,101 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+// An IPv6 network prefix such as `2001:db8:1200::/56`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv6Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Ipv6Prefix {
+    // The prefix of the given length of `addr`, e.g. the delegated prefix of
+    // a host's address.
+    pub fn of(addr: &Ipv6Addr, len: u8) -> Self {
+        let len = len.min(128);
+        let prefix = Self { addr: *addr, len };
+        Self {
+            addr: Ipv6Addr::from(u128::from(*addr) & prefix.mask()),
+            len,
+        }
+    }
+
+    fn mask(&self) -> u128 {
+        if self.len == 0 {
+            0
+        } else {
+            !0u128 << (128 - u32::from(self.len))
+        }
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    pub fn contains(&self, addr: &Ipv6Addr) -> bool {
+        (u128::from(*addr) & self.mask()) == u128::from(self.addr)
+    }
+
+    // Replaces the prefix bits of `addr` by the ones of this prefix,
+    // keeping the host suffix.
+    pub fn apply(&self, addr: &Ipv6Addr) -> Ipv6Addr {
+        let mask = self.mask();
+        Ipv6Addr::from(u128::from(self.addr) | (u128::from(*addr) & !mask))
+    }
+}
+
+impl FromStr for Ipv6Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid IPv6 prefix '{}', expected ADDRESS/LENGTH", s))?;
+        let addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|e| format!("Invalid IPv6 prefix '{}': {}", s, e))?;
+        let len: u8 = len
+            .parse()
+            .ok()
+            .filter(|len| *len <= 128)
+            .ok_or_else(|| format!("Invalid IPv6 prefix length in '{}'", s))?;
+        // Normalize, host bits of the given address are ignored.
+        Ok(Self::of(&addr, len))
+    }
+}
+
+impl fmt::Display for Ipv6Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ipv6Prefix;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn prefix_parses_ok() {
+        let prefix: Ipv6Prefix = "2001:db8:1200:ff::1/56".parse().unwrap();
+        assert_eq!("2001:db8:1200::/56", prefix.to_string());
+        assert!("2001:db8::".parse::<Ipv6Prefix>().is_err());
+        assert!("2001:db8::/129".parse::<Ipv6Prefix>().is_err());
+    }
+
+    #[test]
+    fn prefix_rewrites_keeping_suffix() {
+        let old: Ipv6Prefix = "2001:db8:1200::/56".parse().unwrap();
+        let new: Ipv6Prefix = "2001:db8:3400::/56".parse().unwrap();
+        let addr: Ipv6Addr = "2001:db8:1200:1::1:2".parse().unwrap();
+        assert!(old.contains(&addr));
+        assert!(!new.contains(&addr));
+        let expected: Ipv6Addr = "2001:db8:3400:1::1:2".parse().unwrap();
+        assert_eq!(expected, new.apply(&addr));
+        let delegated = Ipv6Prefix::of(&"2001:db8:3400:1::7".parse().unwrap(), 56);
+        let suffix: Ipv6Addr = "::1:0:0:1:2".parse().unwrap();
+        assert_eq!(expected, delegated.apply(&suffix));
+    }
+}
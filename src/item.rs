@@ -0,0 +1,592 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::fqdn::Fqdn;
+use crate::ipv6_prefix::Ipv6Prefix;
+
+// A dynamic item of `DOMAIN_DYNAMIC_ITEMS`, e.g. `home`, `home:AAAA`,
+// `www:CNAME=home` or `home:A[owner=alice;service=nextcloud]`. Labels are
+// free-form key/value pairs, which are carried into the log output for
+// easier triage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicItem {
+    // Relative to the zone, as in Gandi rrset names.
+    pub name: String,
+    pub zone: Fqdn,
+    pub record_type: RecordType,
+    // Configured values, separated by `|`. Empty for A and AAAA items,
+    // which are set to the public IP address.
+    pub values: Vec<String>,
+    pub labels: Vec<(String, String)>,
+}
+
+// Label which is assumed to have no records of its own, see `dns_name`.
+static WILDCARD_PROBE: &str = "gandi-dns-update-wildcard-probe";
+
+// Record types which can be managed as dynamic items.
+pub fn parse_record_type(s: &str) -> Result<RecordType, String> {
+    match s.trim().to_uppercase().as_str() {
+        "A" => Ok(RecordType::A),
+        "AAAA" => Ok(RecordType::AAAA),
+        "CNAME" => Ok(RecordType::CNAME),
+        "MX" => Ok(RecordType::MX),
+        "SRV" => Ok(RecordType::SRV),
+        "CAA" => Ok(RecordType::CAA),
+        other => Err(format!(
+            "Unsupported record type '{}', expected A, AAAA, CNAME, MX, SRV or CAA",
+            other
+        )),
+    }
+}
+
+fn is_address_type(record_type: RecordType) -> bool {
+    record_type == RecordType::A || record_type == RecordType::AAAA
+}
+
+// Numeric fields preceding the target name in values of types such as MX
+// (`PREFERENCE TARGET`) and SRV (`PRIORITY WEIGHT PORT TARGET`).
+fn numeric_fields(record_type: RecordType) -> Option<&'static [&'static str]> {
+    match record_type {
+        RecordType::MX => Some(&["PREFERENCE"]),
+        RecordType::SRV => Some(&["PRIORITY", "WEIGHT", "PORT"]),
+        _ => None,
+    }
+}
+
+// Canonical CAA value `FLAGS TAG "VALUE"`, the form in which configured
+// and served values are compared. Parameters of issue values are
+// separated by `; `.
+pub fn caa_value(critical: bool, tag: &str, value: &str) -> String {
+    let tag = tag.to_lowercase();
+    let value = if tag == "issue" || tag == "issuewild" {
+        value
+            .split(';')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>()
+            .join("; ")
+    } else {
+        value.trim().to_string()
+    };
+    format!("{} {} \"{}\"", if critical { 128 } else { 0 }, tag, value)
+}
+
+// Parses a configured CAA value `FLAGS TAG "VALUE"` into its canonical
+// form. Only the issuer critical flag (128) is supported.
+fn parse_caa(s: &str) -> Result<String, String> {
+    let err = || format!("Invalid CAA value '{}', expected FLAGS TAG \"VALUE\"", s);
+    let mut fields = s.trim().splitn(3, char::is_whitespace);
+    let critical = match fields.next().map(|f| f.parse::<u8>()) {
+        Some(Ok(0)) => false,
+        Some(Ok(128)) => true,
+        _ => return Err(err()),
+    };
+    let tag = fields
+        .next()
+        .filter(|t| !t.is_empty() && t.chars().all(|c| c.is_ascii_alphanumeric()))
+        .ok_or_else(err)?;
+    let value = fields
+        .next()
+        .map(|v| v.trim())
+        .and_then(|v| v.strip_prefix('"'))
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(err)?;
+    Ok(caa_value(critical, tag, value))
+}
+
+// Checks the configured values of a record type.
+fn check_values(record_type: RecordType, values: &[String]) -> Result<(), String> {
+    match record_type {
+        RecordType::AAAA if values.len() == 1 && values[0].parse::<Ipv6Addr>().is_ok() => Ok(()),
+        RecordType::AAAA if !values.is_empty() => Err(format!(
+            "AAAA items take no value or a single host suffix such as `::1:2`, not '{}'",
+            values.join("|")
+        )),
+        t if is_address_type(t) && !values.is_empty() => Err(format!(
+            "{} items are set to the public IP address and take no value",
+            t
+        )),
+        t if !is_address_type(t) && values.is_empty() => Err(format!(
+            "{} items require a value, e.g. `name:{}=value`",
+            t, t
+        )),
+        RecordType::CNAME if values.len() > 1 => Err("CNAME items take a single target".into()),
+        RecordType::CAA => values.iter().try_for_each(|v| parse_caa(v).map(|_| ())),
+        t => match numeric_fields(t) {
+            Some(numeric) => values.iter().try_for_each(|v| {
+                let fields: Vec<&str> = v.split_whitespace().collect();
+                if fields.len() == numeric.len() + 1
+                    && fields[..numeric.len()]
+                        .iter()
+                        .all(|f| f.parse::<u16>().is_ok())
+                {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "Invalid {} value '{}', expected {} TARGET",
+                        t,
+                        v,
+                        numeric.join(" ")
+                    ))
+                }
+            }),
+            None => Ok(()),
+        },
+    }
+}
+
+// Absolute, lower case form of a domain name given in a record value,
+// names without trailing dot are relative to the zone, `@` is the apex.
+fn absolute_name(name: &str, zone: &Fqdn) -> String {
+    let name = ascii_name(name.trim()).unwrap_or_else(|_| name.trim().to_lowercase());
+    if name == "@" {
+        zone.as_dns().into()
+    } else if name.ends_with('.') {
+        name
+    } else {
+        format!("{}.{}", name, zone.as_dns())
+    }
+}
+
+// Name in the form used in DNS queries and API requests: IDNA encoded
+// labels, which implies lower case. `@` and the wildcard label `*` are
+// kept as they are.
+pub fn ascii_name(name: &str) -> Result<String, String> {
+    name.split('.')
+        .map(|label| match label {
+            "" | "@" | "*" => Ok(label.to_string()),
+            _ => idna::domain_to_ascii(label)
+                .map_err(|e| format!("Invalid name '{}': {:?}", name, e)),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|labels| labels.join("."))
+}
+
+// Name relative to the zone of a fully qualified name ending with a dot,
+// `@` for the zone apex. None if the name is not within the zone.
+fn relative_name(name: &str, zone: &Fqdn) -> Option<String> {
+    let name = ascii_name(name.trim_end_matches('.')).ok()?;
+    if name == zone.without_dot() {
+        return Some("@".into());
+    }
+    name.strip_suffix(zone.without_dot())
+        .and_then(|n| n.strip_suffix('.'))
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_string())
+}
+
+// Zone of a fully qualified name and the name relative to it. Of nested
+// zones, such as `example.com.` and `home.example.com.`, the innermost one
+// is chosen.
+fn zone_of<'a>(name: &str, zones: &'a [Fqdn]) -> Option<(&'a Fqdn, String)> {
+    zones
+        .iter()
+        .filter_map(|zone| relative_name(name, zone).map(|relative| (zone, relative)))
+        .max_by_key(|(zone, _)| zone.as_dns().len())
+}
+
+// Name with Unicode labels, for log output.
+pub fn unicode_name(name: &str) -> String {
+    idna::domain_to_unicode(name).0
+}
+
+fn parse_labels(s: &str) -> Result<Vec<(String, String)>, String> {
+    s.split(';')
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| match l.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => {
+                Ok((k.trim().to_string(), v.trim().to_string()))
+            }
+            _ => Err(format!("Invalid label '{}', expected key=value", l)),
+        })
+        .collect()
+}
+
+impl DynamicItem {
+    // Parses one entry of `DOMAIN_DYNAMIC_ITEMS`. An entry without record
+    // type yields one item per default record type. Names ending with a dot
+    // are fully qualified and have to be within one of the zones, other
+    // names are relative to the first zone.
+    pub fn parse(
+        s: &str,
+        zones: &[Fqdn],
+        default_types: &[RecordType],
+    ) -> Result<Vec<Self>, String> {
+        let default_zone = zones.first().ok_or("No domain to parse items in")?;
+        let s = s.trim();
+        let (head, labels) = match s.split_once('[') {
+            Some((head, rest)) => {
+                let labels = rest
+                    .strip_suffix(']')
+                    .ok_or_else(|| format!("Missing ']' in item '{}'", s))?;
+                (head.trim(), parse_labels(labels)?)
+            }
+            None => (s, vec![]),
+        };
+        let (name, record_types, values) = match head.split_once(':') {
+            Some((name, spec)) => {
+                let (record_type, values) = match spec.split_once('=') {
+                    Some((record_type, values)) => (
+                        parse_record_type(record_type)?,
+                        values
+                            .split('|')
+                            .map(|v| v.trim().to_string())
+                            .filter(|v| !v.is_empty())
+                            .collect(),
+                    ),
+                    None => (parse_record_type(spec)?, vec![]),
+                };
+                check_values(record_type, &values)?;
+                (name.trim(), vec![record_type], values)
+            }
+            None => (head, default_types.to_vec(), vec![]),
+        };
+        let relative;
+        let (name, zone) = if name.ends_with('.') && name != "." {
+            let (zone, name) = zone_of(name, zones).ok_or_else(|| {
+                let zones: Vec<String> = zones.iter().map(|z| z.to_string()).collect();
+                format!("Item '{}' is not within domain {}", s, zones.join(" or "))
+            })?;
+            relative = name;
+            (relative.as_str(), zone)
+        } else {
+            (name, default_zone)
+        };
+        if name.is_empty() {
+            return Err(format!("Missing name in item '{}'", s));
+        }
+        if name.split('.').any(|label| label.is_empty()) {
+            return Err(format!("Empty label in name of item '{}'", s));
+        }
+        if name.split('.').any(|label| label == "@") && name != "@" {
+            return Err(format!("'@' must be the whole name in item '{}'", s));
+        }
+        let misplaced_wildcard = name
+            .split('.')
+            .enumerate()
+            .any(|(i, label)| label.contains('*') && (i > 0 || label != "*"));
+        if misplaced_wildcard {
+            return Err(format!(
+                "Wildcard '*' must be the whole first label in item '{}'",
+                s
+            ));
+        }
+        let name = ascii_name(name)?;
+        Ok(record_types
+            .into_iter()
+            .map(|record_type| Self {
+                name: name.clone(),
+                zone: zone.clone(),
+                record_type,
+                values: values.clone(),
+                labels: labels.clone(),
+            })
+            .collect())
+    }
+
+    // Whether the item is set to the public IP address, rather than to
+    // configured values.
+    pub fn is_dynamic(&self) -> bool {
+        is_address_type(self.record_type)
+    }
+
+    // Host suffix of an AAAA item such as `nas:AAAA=::1:2`, whose address
+    // is the delegated prefix of the public IPv6 address combined with the
+    // suffix.
+    pub fn ipv6_suffix(&self) -> Option<Ipv6Addr> {
+        match self.record_type {
+            RecordType::AAAA => self.values.first().and_then(|v| v.parse().ok()),
+            _ => None,
+        }
+    }
+
+    // The address of the item, given the public IP address.
+    pub fn address(&self, my_ip: IpAddr, prefix_len: u8) -> IpAddr {
+        match (my_ip, self.ipv6_suffix()) {
+            (IpAddr::V6(ip), Some(suffix)) => {
+                IpAddr::V6(Ipv6Prefix::of(&ip, prefix_len).apply(&suffix))
+            }
+            _ => my_ip,
+        }
+    }
+
+    // Configured values in the form the authoritative name servers answer
+    // with, used for comparison and for updates.
+    pub fn values_in_zone(&self) -> Vec<String> {
+        let zone = &self.zone;
+        self.values
+            .iter()
+            .map(|v| match self.record_type {
+                RecordType::CNAME => absolute_name(v, zone),
+                // Validated by `check_values`.
+                RecordType::CAA => parse_caa(v).unwrap_or_else(|_| v.clone()),
+                t if numeric_fields(t).is_some() => {
+                    // The target is the last field, see `check_values`.
+                    let mut fields: Vec<String> =
+                        v.split_whitespace().map(|f| f.to_string()).collect();
+                    if let Some(target) = fields.last_mut() {
+                        *target = absolute_name(target, zone);
+                    }
+                    fields.join(" ")
+                }
+                _ => v.clone(),
+            })
+            .collect()
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.name == "*" || self.name.starts_with("*.")
+    }
+
+    // Absolute name to look up in DNS, `@` names the zone apex. Wildcards
+    // are looked up through a probe name below them, which the name server
+    // answers from the wildcard record.
+    pub fn dns_name(&self) -> String {
+        let zone = &self.zone;
+        if self.name == "@" {
+            zone.as_dns().into()
+        } else if self.is_wildcard() {
+            format!("{}{}.{}", WILDCARD_PROBE, &self.name[1..], zone.as_dns())
+        } else {
+            format!("{}.{}", self.name, zone.as_dns())
+        }
+    }
+
+    // Identifies the item in the state file and on the command line: the
+    // name for items of the default zone, the name within the zone for
+    // items of other zones, such as `nas.other-domain.net`.
+    pub fn key(&self, default_zone: &Fqdn) -> String {
+        if &self.zone == default_zone {
+            self.name.clone()
+        } else if self.name == "@" {
+            self.zone.without_dot().into()
+        } else {
+            format!("{}.{}", self.name, self.zone.without_dot())
+        }
+    }
+
+    // Labels formatted for log output, e.g. ` [owner=alice service=nextcloud]`,
+    // or empty if there are no labels.
+    pub fn labels_suffix(&self) -> String {
+        if self.labels.is_empty() {
+            return String::new();
+        }
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        format!(" [{}]", labels.join(" "))
+    }
+}
+
+impl fmt::Display for DynamicItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}{}",
+            unicode_name(&self.name),
+            self.record_type,
+            self.labels_suffix()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DynamicItem;
+    use crate::fqdn::Fqdn;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    fn parse_in(s: &str, zone: &str) -> Result<Vec<DynamicItem>, String> {
+        DynamicItem::parse(s, &[zone.parse().unwrap()], &[RecordType::A])
+    }
+
+    fn parse(s: &str) -> Result<Vec<DynamicItem>, String> {
+        parse_in(s, "example.com")
+    }
+
+    #[test]
+    fn item_parses_ok() {
+        let actual = parse(" home[owner=alice; service=nextcloud]").unwrap();
+        let expected = vec![DynamicItem {
+            name: "home".into(),
+            zone: "example.com".parse().unwrap(),
+            record_type: RecordType::A,
+            values: vec![],
+            labels: vec![
+                ("owner".into(), "alice".into()),
+                ("service".into(), "nextcloud".into()),
+            ],
+        }];
+        assert_eq!(expected, actual);
+        assert_eq!(
+            "home A [owner=alice service=nextcloud]",
+            actual[0].to_string()
+        );
+        assert_eq!("vpn AAAA", parse("vpn:aaaa").unwrap()[0].to_string());
+    }
+
+    #[test]
+    fn item_expands_default_types() {
+        let zones: Vec<Fqdn> = vec!["example.com".parse().unwrap()];
+        let types = [RecordType::A, RecordType::AAAA];
+        let actual = DynamicItem::parse("home[owner=alice]", &zones, &types).unwrap();
+        assert_eq!(2, actual.len());
+        assert_eq!(RecordType::A, actual[0].record_type);
+        assert_eq!(RecordType::AAAA, actual[1].record_type);
+        assert_eq!(actual[0].labels, actual[1].labels);
+    }
+
+    #[test]
+    fn cname_item_parses_ok() {
+        let item = &parse("www:CNAME=Home[service=web]").unwrap()[0];
+        assert_eq!(RecordType::CNAME, item.record_type);
+        assert!(!item.is_dynamic());
+        assert_eq!(vec!["home.example.com.".to_string()], item.values_in_zone());
+        let item = &parse("www:cname=host.example.net.").unwrap()[0];
+        assert_eq!(vec!["host.example.net.".to_string()], item.values_in_zone());
+        assert!(parse("www:CNAME").is_err());
+        assert!(parse("www:CNAME=a|b").is_err());
+        assert!(parse("home:A=192.0.2.1").is_err());
+    }
+
+    #[test]
+    fn item_rejects_invalid() {
+        assert!(parse("home[owner=alice").is_err());
+        assert!(parse("home[owner]").is_err());
+        assert!(parse("[owner=alice]").is_err());
+        assert!(parse(":A").is_err());
+        assert!(parse("office..home").is_err());
+        assert!(parse("office.home").is_ok());
+        assert!(parse("home.*").is_err());
+        assert!(parse("*home").is_err());
+        assert!(parse("home:TLSA=x").is_err());
+    }
+
+    #[test]
+    fn wildcard_item_is_probed() {
+        let item = &parse("*.home").unwrap()[0];
+        assert!(item.is_wildcard());
+        assert_eq!(
+            "gandi-dns-update-wildcard-probe.home.example.com.",
+            item.dns_name()
+        );
+        assert_eq!(
+            "gandi-dns-update-wildcard-probe.example.com.",
+            parse("*").unwrap()[0].dns_name()
+        );
+        assert_eq!("home.example.com.", parse("home").unwrap()[0].dns_name());
+        assert_eq!("example.com.", parse("@").unwrap()[0].dns_name());
+    }
+
+    #[test]
+    fn fqdn_item_is_made_relative() {
+        assert_eq!("home", parse("Home.Example.com.").unwrap()[0].name);
+        assert_eq!("*.home", parse("*.home.example.com.:A").unwrap()[0].name);
+        assert_eq!("@", parse("example.com.:MX=10 home").unwrap()[0].name);
+        assert!(parse("home.example.net.").is_err());
+        assert!(parse("homeexample.com.").is_err());
+        assert!(parse(".").is_err());
+    }
+
+    #[test]
+    fn item_is_assigned_to_zone() {
+        let zones: Vec<Fqdn> = ["example.com", "other.net", "home.example.com"]
+            .iter()
+            .map(|z| z.parse().unwrap())
+            .collect();
+        let parse = |s| DynamicItem::parse(s, &zones, &[RecordType::A]).unwrap();
+        let item = &parse("vpn")[0];
+        assert_eq!(
+            (zones[0].clone(), "vpn"),
+            (item.zone.clone(), item.name.as_str())
+        );
+        assert_eq!("vpn", item.key(&zones[0]));
+        let item = &parse("nas.other.net.:CNAME=vpn")[0];
+        assert_eq!(
+            (zones[1].clone(), "nas"),
+            (item.zone.clone(), item.name.as_str())
+        );
+        assert_eq!("nas.other.net.", item.dns_name());
+        assert_eq!(vec!["vpn.other.net.".to_string()], item.values_in_zone());
+        assert_eq!("nas.other.net", item.key(&zones[0]));
+        let item = &parse("office.home.example.com.")[0];
+        assert_eq!(
+            (zones[2].clone(), "office"),
+            (item.zone.clone(), item.name.as_str())
+        );
+        assert_eq!("other.net", parse("other.net.")[0].key(&zones[0]));
+        assert!(DynamicItem::parse("nas.example.org.", &zones, &[RecordType::A]).is_err());
+    }
+
+    #[test]
+    fn idn_item_is_encoded() {
+        let item = &parse_in("Bäcker:CNAME=straße", "münchen.de").unwrap()[0];
+        assert_eq!("xn--bcker-gra", item.name);
+        assert_eq!("xn--bcker-gra.xn--mnchen-3ya.de.", item.dns_name());
+        assert_eq!(
+            vec!["xn--strae-oqa.xn--mnchen-3ya.de.".to_string()],
+            item.values_in_zone()
+        );
+        assert_eq!("bäcker CNAME", item.to_string());
+    }
+
+    #[test]
+    fn aaaa_item_takes_suffix() {
+        let item = &parse("nas:AAAA=::1:2").unwrap()[0];
+        assert!(item.is_dynamic());
+        assert_eq!(Some("::1:2".parse().unwrap()), item.ipv6_suffix());
+        let my_ip = "2001:db8:3400:1::7".parse().unwrap();
+        assert_eq!(
+            "2001:db8:3400:1::1:2".parse::<std::net::IpAddr>().unwrap(),
+            item.address(my_ip, 64)
+        );
+        assert_eq!(my_ip, parse("nas:AAAA").unwrap()[0].address(my_ip, 64));
+        assert!(parse("nas:AAAA=home").is_err());
+        assert!(parse("nas:AAAA=::1|::2").is_err());
+        assert!(parse("nas:A=::1").is_err());
+    }
+
+    #[test]
+    fn mx_item_parses_ok() {
+        let item = &parse("@:MX=10 home|20 mx.example.net.").unwrap()[0];
+        assert_eq!(RecordType::MX, item.record_type);
+        let expected = vec![
+            "10 home.example.com.".to_string(),
+            "20 mx.example.net.".to_string(),
+        ];
+        assert_eq!(expected, item.values_in_zone());
+        assert!(parse("home:MX").is_err());
+        assert!(parse("home:MX=home").is_err());
+        assert!(parse("home:MX=high home").is_err());
+        assert!(parse("a.@:A").is_err());
+    }
+
+    #[test]
+    fn caa_item_parses_ok() {
+        let item =
+            &parse(r#"home:CAA=0 issue "letsencrypt.org;validationmethods=dns-01"|128 IODEF "mailto:ops@example.com""#)
+                .unwrap()[0];
+        assert_eq!(RecordType::CAA, item.record_type);
+        let expected = vec![
+            r#"0 issue "letsencrypt.org; validationmethods=dns-01""#.to_string(),
+            r#"128 iodef "mailto:ops@example.com""#.to_string(),
+        ];
+        assert_eq!(expected, item.values_in_zone());
+        assert!(parse(r#"home:CAA=1 issue "letsencrypt.org""#).is_err());
+        assert!(parse("home:CAA=0 issue letsencrypt.org").is_err());
+    }
+
+    #[test]
+    fn srv_item_parses_ok() {
+        let item = &parse("_sip._udp:SRV=10 5 5060 home").unwrap()[0];
+        assert_eq!(RecordType::SRV, item.record_type);
+        let expected = vec!["10 5 5060 home.example.com.".to_string()];
+        assert_eq!(expected, item.values_in_zone());
+        assert!(parse("_sip._udp:SRV=10 5060 home").is_err());
+        assert!(parse("_sip._udp:SRV=10 5 70000 home").is_err());
+    }
+}
@@ -1,30 +1,66 @@
-use log::{debug, info, trace};
+use log::{debug, error, info, trace, warn};
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::{error, fmt};
 
-use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 use trust_dns_resolver::lookup::Lookup;
+use trust_dns_resolver::proto::rr::rdata::caa;
 use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
 use trust_dns_resolver::proto::xfer::DnsRequestOptions;
-use trust_dns_resolver::{TokioAsyncResolver, error::ResolveError, TokioHandle};
+use trust_dns_resolver::{TokioAsyncResolver, TokioHandle};
 
+mod args;
+mod authoritative;
+mod bind;
+mod config;
+mod dispatcher;
+mod fqdn;
 mod gandi_client;
+mod http;
+mod http_whoami;
+mod ip_source;
+mod ipv6_prefix;
+mod item;
+mod metadata;
+mod natpmp;
+#[cfg(target_os = "linux")]
+mod netlink;
+mod ownership;
+mod propagation;
+mod state;
+mod stun;
+mod transport;
+mod upnp;
+mod whoami;
+mod window;
+mod zone;
+mod zonefile;
 
-use gandi_client::GandiClient;
+use args::{Args, Command};
+use config::AppConfig;
+use fqdn::Fqdn;
+use gandi_client::{GandiClient, GandiRRSet};
+use ipv6_prefix::Ipv6Prefix;
+use state::{CachedIp, State};
+use transport::{DnsTransport, DnssecMode};
+use zone::{CheckMode, UpdateMode};
 
 type Resolver = TokioAsyncResolver;
 
-fn resolver(
-    config: ResolverConfig,
-    options: ResolverOpts
-) -> Result<Resolver, ResolveError> {
+#[allow(clippy::result_large_err)]
+fn resolver(config: ResolverConfig, options: ResolverOpts) -> Result<Resolver, ResolveError> {
     Resolver::new(config, options, TokioHandle)
 }
 
 static DNS_TIMEOUT: Duration = Duration::from_secs(15);
+// TTL of created rrsets without `DOMAIN_TTL`, existing rrsets keep theirs.
+static RECORD_TTL: Duration = Duration::from_secs(300);
 static HTTP_TIMEOUT: Duration = Duration::from_secs(15);
 
 macro_rules! crate_name {
@@ -33,44 +69,6 @@ macro_rules! crate_name {
     };
 }
 
-#[derive(Debug)]
-struct AppConfig {
-    gandi_api_key: String,
-    domain_ip: Option<Ipv4Addr>,
-    domain_fqdn: String,
-    domain_dynamic_items: Vec<String>,
-}
-
-impl AppConfig {
-    pub fn from_env() -> Self {
-        let gandi_api_key = env::var("GANDI_API_KEY").expect("GANDI_API_KEY env-var is present");
-        let domain_ip = env::var_os("DOMAIN_IP").map(|os| os.into_string().unwrap());
-        let domain_ip: Option<Ipv4Addr> = domain_ip.map(|s| s.parse().expect("Valid Ipv4Addr"));
-        let domain_fqdn = env::var("DOMAIN_FQDN").expect("DOMAIN_FQDN env-var is present");
-        let domain_dynamic_items =
-            env::var("DOMAIN_DYNAMIC_ITEMS").expect("DOMAIN_DYNAMIC_ITEMS env-var is present");
-
-        if !domain_fqdn.ends_with('.') {
-            panic!(
-                "Configuration entry `domain_fqdn` does not end with '.': {}",
-                domain_fqdn
-            );
-        }
-
-        let domain_dynamic_items: Vec<String> = domain_dynamic_items
-            .split(',')
-            .map(|s| s.to_string())
-            .collect();
-
-        Self {
-            gandi_api_key,
-            domain_ip,
-            domain_fqdn,
-            domain_dynamic_items,
-        }
-    }
-}
-
 #[derive(Debug)]
 struct AppError {
     msg: String,
@@ -106,16 +104,17 @@ impl From<ResolveError> for AppError {
     }
 }
 
-fn ns_of_record(record: &Record) -> Option<String> {
+fn ipv4_of_record(record: &Record) -> Option<Ipv4Addr> {
     match record.rdata() {
-        RData::NS(name) => Some(name.to_utf8()),
+        RData::A(ip) => Some(*ip),
         _ => None,
     }
 }
 
-fn ipv4_of_record(record: &Record) -> Option<Ipv4Addr> {
+fn ip_of_record(record: &Record) -> Option<IpAddr> {
     match record.rdata() {
-        RData::A(ip) => Some(*ip),
+        RData::A(ip) => Some(IpAddr::V4(*ip)),
+        RData::AAAA(ip) => Some(IpAddr::V6(*ip)),
         _ => None,
     }
 }
@@ -143,6 +142,70 @@ async fn dns_lookup(
     })
 }
 
+// Values of a record as the name server answers, in the form they are
+// configured in, e.g. `192.0.2.1` or `home.example.com.`.
+fn value_of_record(record: &Record) -> Option<String> {
+    match record.rdata() {
+        RData::A(ip) => Some(ip.to_string()),
+        RData::AAAA(ip) => Some(ip.to_string()),
+        RData::CNAME(name) => Some(name.to_utf8().to_lowercase()),
+        RData::MX(mx) => Some(format!(
+            "{} {}",
+            mx.preference(),
+            mx.exchange().to_utf8().to_lowercase()
+        )),
+        RData::SRV(srv) => Some(format!(
+            "{} {} {} {}",
+            srv.priority(),
+            srv.weight(),
+            srv.port(),
+            srv.target().to_utf8().to_lowercase()
+        )),
+        RData::CAA(caa) => {
+            let value = match caa.value() {
+                caa::Value::Issuer(name, options) => {
+                    let mut parts: Vec<String> = name
+                        .iter()
+                        .map(|n| n.to_utf8().trim_end_matches('.').to_string())
+                        .collect();
+                    parts.extend(options.iter().map(|o| format!("{}={}", o.key(), o.value())));
+                    parts.join("; ")
+                }
+                caa::Value::Url(url) => url.to_string(),
+                caa::Value::Unknown(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            };
+            Some(item::caa_value(
+                caa.issuer_critical(),
+                caa.tag().as_str(),
+                &value,
+            ))
+        }
+        _ => None,
+    }
+}
+
+// All values of the given type, empty if the name has no such records.
+async fn dns_lookup_values(
+    resolver: &Resolver,
+    name: String,
+    rr_type: RecordType,
+) -> Result<Vec<String>, AppError> {
+    match resolver
+        .lookup(name, rr_type, DnsRequestOptions::default())
+        .await
+    {
+        Ok(lookup) => Ok(lookup
+            .record_iter()
+            .filter(|r| r.rr_type() == rr_type)
+            .filter_map(value_of_record)
+            .collect()),
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(vec![]),
+            _ => Err(From::from(e)),
+        },
+    }
+}
+
 fn resolver_opts_with_timeout() -> ResolverOpts {
     ResolverOpts {
         timeout: DNS_TIMEOUT,
@@ -151,40 +214,18 @@ fn resolver_opts_with_timeout() -> ResolverOpts {
     }
 }
 
-async fn whats_my_ip(bootstrap_resolver: &Resolver) -> Result<Ipv4Addr, AppError> {
-    let resolver_record = dns_lookup(
-        bootstrap_resolver,
-        "resolver1.opendns.com.".into(),
-        RecordType::A,
-    )
-    .await?;
-
-    let resolver_ip =
-        ipv4_of_record(&resolver_record).ok_or_else(|| AppError::new("No IPv4 record found"))?;
-
-    let ns_config = NameServerConfig {
-        protocol: Protocol::Udp,
-        socket_addr: SocketAddr::new(IpAddr::V4(resolver_ip), 53),
-        tls_dns_name: None,
-        trust_nx_responses: true,
-    };
-
-    let resolver_config = ResolverConfig::from_parts(
-        Some(resolver_record.name().clone()),
-        vec![],
-        vec![ns_config],
-    );
-
-    let resolver = resolver(resolver_config, resolver_opts_with_timeout())?;
-
-    let my_ip_record = dns_lookup(&resolver, "myip.opendns.com".into(), RecordType::A).await?;
-
-    ipv4_of_record(&my_ip_record).ok_or_else(|| AppError::new("No IPv4 record found"))
+// Options of resolvers querying authoritative name servers, whose answers
+// have to reflect the live zone data and are never cached.
+fn uncached_resolver_opts() -> ResolverOpts {
+    ResolverOpts {
+        cache_size: 0,
+        ..resolver_opts_with_timeout()
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-        match std::env::var("RUST_LOG") {
+    match std::env::var("RUST_LOG") {
         Ok(_) => {}
         Err(_) => {
             let logger = crate_name!().replace("-", "_");
@@ -193,92 +234,1219 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     }
     env_logger::init();
 
-    let config = AppConfig::from_env();
-    let google_dns = resolver(ResolverConfig::google(), resolver_opts_with_timeout())?;
-    let gandi = GandiClient::new(config.gandi_api_key, HTTP_TIMEOUT);
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, args::USAGE);
+        std::process::exit(2);
+    });
+    let configs = AppConfig::from_env();
+    match &args.command {
+        Command::Healthcheck => {
+            if !healthcheck(&configs)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Command::Status => return status(&configs),
+        Command::Pause { items } => return set_paused(&configs, items, true),
+        Command::Resume { items } => return set_paused(&configs, items, false),
+        _ => {}
+    }
+    for config in &configs {
+        if let Err(e) = config.check_sandbox_option(args.sandbox) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
+    if let Some(hook_args) = &args.dispatcher {
+        let event = dispatcher::Event::from_hook(hook_args, |k| env::var(k).ok())?;
+        let interface = event.interface.as_deref().unwrap_or("unknown interface");
+        if !event.is_relevant() {
+            debug!("Ignoring event {} of {}", event.action, interface);
+            return Ok(());
+        }
+        info!("Running for event {} of {}", event.action, interface);
+    }
+
+    for item in args.only.iter().chain(args.skip.iter()) {
+        let configured = configs.iter().any(|c| {
+            c.domain_dynamic_items
+                .iter()
+                .any(|i| &i.key(&c.domain_fqdn) == item)
+        });
+        if !configured {
+            warn!(
+                "Selected item {} is not configured in DOMAIN_DYNAMIC_ITEMS",
+                item
+            );
+        }
+    }
+
+    if args.command == Command::Watch {
+        return watch(&configs, &args).await;
+    }
+    run_profiles(&configs, &args).await
+}
+
+// Profiles are processed in isolation, a failing profile does not prevent
+// the others from being processed.
+async fn run_profiles(
+    configs: &[AppConfig],
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut failed = vec![];
+    for config in configs {
+        if !config.profile.is_empty() {
+            info!("Processing profile {}", config.profile);
+        }
+        let result = match &args.command {
+            Command::RewritePrefix { old, new } => rewrite_prefix(config, args, old, new).await,
+            Command::Restore { file, selection } => restore(config, args, file, selection).await,
+            Command::RestoreSnapshot { id, selection } => {
+                restore_snapshot(config, args, id, selection).await
+            }
+            Command::Export { file } => {
+                if config.domain_zones.len() > 1 {
+                    info!(
+                        "Exporting domain {}, the first of DOMAIN_FQDN",
+                        config.domain_fqdn
+                    );
+                }
+                let gandi = gandi_client(config, args);
+                zone::export(&gandi, config.domain_fqdn.without_dot(), file).await
+            }
+            Command::Import { file, selection } => import(config, args, file, selection).await,
+            Command::Delete { selection } => delete(config, args, selection).await,
+            Command::List { selection } => list(config, args, selection).await,
+            Command::Domains => domains(config, args).await,
+            Command::SetTtl { ttl } => set_ttl(config, args, *ttl).await,
+            _ => run_profile(config, args).await,
+        };
+        if let Err(e) = result {
+            if configs.len() == 1 {
+                return Err(e);
+            }
+            error!("Profile {} failed: {}", config.profile, e);
+            failed.push(config.profile.as_str());
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(From::from(format!(
+            "Profiles failed: {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+// Addresses and routes change in bursts, e.g. when an interface comes up.
+static WATCH_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Updates, and updates again after each change of the addresses or the
+// default route of the host. Failed updates are logged and retried with the
+// next change.
+#[cfg(target_os = "linux")]
+async fn watch(configs: &[AppConfig], args: &Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let watcher = netlink::Watcher::new()
+        .map_err(|e| format!("Cannot watch the network addresses: {}", e))?;
+    loop {
+        if let Err(e) = run_profiles(configs, args).await {
+            error!("Update failed: {}", e);
+        }
+        match next_wakeup(configs) {
+            Some(wakeup) => {
+                let wait = wakeup.duration_since(SystemTime::now()).unwrap_or_default();
+                tokio::select! {
+                    changed = watcher.changed() => changed?,
+                    _ = tokio::time::sleep(wait) => {
+                        info!("Updating without network change, for pending changes or the dead man's switch");
+                        continue;
+                    }
+                }
+            }
+            None => watcher.changed().await?,
+        }
+        tokio::time::sleep(WATCH_SETTLE_TIME).await;
+        watcher.drain();
+        info!("Network addresses changed, updating");
+    }
+}
+
+// The time of the next run without network change: when the update window
+// of a profile with pending changes (see `State::pending`) opens, or when
+// the dead man's switch of a profile would fire, so that it is checked even
+// if the network does not change.
+#[cfg(target_os = "linux")]
+fn next_wakeup(configs: &[AppConfig]) -> Option<SystemTime> {
+    let now = SystemTime::now();
+    let windows = configs
+        .iter()
+        .filter(|config| {
+            config
+                .state_file
+                .as_ref()
+                .and_then(|state_file| State::load(state_file).ok())
+                .filter(|state| !state.pending.is_empty())
+                .is_some()
+        })
+        .filter_map(|config| config.update_policy.next_allowed(now));
+    let switches = configs
+        .iter()
+        .filter_map(|config| config.dead_mans_switch)
+        .map(|max_age| now + max_age);
+    windows.chain(switches).min()
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn watch(_configs: &[AppConfig], _args: &Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    Err(From::from("The watch command is only supported on Linux"))
+}
+
+// Logs the alert of the dead man's switch if the last successful run is
+// longer ago than `DEAD_MANS_SWITCH_AFTER`. Returns whether it fired.
+fn dead_mans_switch(config: &AppConfig, state: &State) -> bool {
+    let max_age = match config.dead_mans_switch {
+        Some(max_age) => max_age,
+        None => return false,
+    };
+    match state.overdue(max_age, SystemTime::now()) {
+        Some(age) => {
+            error!(
+                "Dead man's switch{}: no successful update for {} seconds (limit is {} seconds)",
+                profile_suffix(config),
+                age.as_secs(),
+                max_age.as_secs()
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+// Whether the last successful run of each profile is recent enough.
+fn healthcheck(configs: &[AppConfig]) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut healthy = true;
+    for config in configs {
+        let state_file = config
+            .state_file
+            .as_ref()
+            .ok_or("The healthcheck requires STATE_FILE")?;
+        let state = State::load(state_file)?;
+        if dead_mans_switch(config, &state) {
+            healthy = false;
+        }
+        match state.since_last_success(SystemTime::now()) {
+            Some(age) if age <= config.healthcheck_max_age => {
+                info!(
+                    "Healthy{}: last successful run {} seconds ago",
+                    profile_suffix(config),
+                    age.as_secs()
+                );
+            }
+            Some(age) => {
+                error!(
+                    "Unhealthy{}: last successful run {} seconds ago, limit is {} seconds",
+                    profile_suffix(config),
+                    age.as_secs(),
+                    config.healthcheck_max_age.as_secs()
+                );
+                healthy = false;
+            }
+            None => {
+                error!("Unhealthy{}: no successful run yet", profile_suffix(config));
+                healthy = false;
+            }
+        }
+    }
+    Ok(healthy)
+}
+
+fn state_file(config: &AppConfig) -> Result<&PathBuf, Box<dyn Error + Send + Sync>> {
+    match &config.state_file {
+        Some(state_file) => Ok(state_file),
+        None => Err(From::from(format!(
+            "STATE_FILE is required{}",
+            profile_suffix(config)
+        ))),
+    }
+}
+
+fn status(configs: &[AppConfig]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for config in configs {
+        let state = State::load(state_file(config)?)?;
+        if !config.profile.is_empty() {
+            println!("Profile {}", config.profile);
+        }
+        match state.since_last_success(SystemTime::now()) {
+            Some(age) => println!("Last successful run: {} seconds ago", age.as_secs()),
+            None => println!("Last successful run: never"),
+        }
+        for item in &config.domain_dynamic_items {
+            let paused = if state.paused.contains(&item.key(&config.domain_fqdn)) {
+                "paused"
+            } else {
+                "active"
+            };
+            println!(
+                "  {}.{} {} {}",
+                item::unicode_name(&item.name),
+                item.zone,
+                item.record_type,
+                paused
+            );
+        }
+        for (key, values) in &state.pending {
+            println!("  {} pending: {}", key, values);
+        }
+    }
+    Ok(())
+}
+
+fn set_paused(
+    configs: &[AppConfig],
+    items: &[String],
+    paused: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for item in items {
+        let mut found = false;
+        for config in configs {
+            let configured = config
+                .domain_dynamic_items
+                .iter()
+                .any(|i| &i.key(&config.domain_fqdn) == item);
+            if !configured {
+                continue;
+            }
+            found = true;
+            let state_file = state_file(config)?;
+            let mut state = State::load(state_file)?;
+            if paused {
+                state.paused.insert(item.clone());
+                info!("Paused record {}{}", item, profile_suffix(config));
+            } else {
+                state.paused.remove(item);
+                info!("Resumed record {}{}", item, profile_suffix(config));
+            }
+            state.save(state_file)?;
+        }
+        if !found {
+            return Err(From::from(format!(
+                "Item {} is not configured in DOMAIN_DYNAMIC_ITEMS",
+                item
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn profile_suffix(config: &AppConfig) -> String {
+    if config.profile.is_empty() {
+        String::new()
+    } else {
+        format!(" (profile {})", config.profile)
+    }
+}
+
+async fn run_profile(config: &AppConfig, args: &Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for window in &config.update_policy.windows {
+        debug!("Update window ({:?}) {}", config.update_policy.mode, window);
+    }
+    // Checked by every run, also by those which end early or fail.
+    if let Some(state_file) = &config.state_file {
+        dead_mans_switch(config, &State::load(state_file)?);
+    }
+
+    // Held until the end of the run.
+    let _lock = match &config.state_file {
+        Some(state_file) => match state::Lock::acquire(state_file)? {
+            Some(lock) => Some(lock),
+            None if args.dispatcher.is_some() => {
+                info!(
+                    "Another run is in progress{}, ignoring the event",
+                    profile_suffix(config)
+                );
+                return Ok(());
+            }
+            None => {
+                return Err(From::from(format!(
+                    "Another run holds the lock of {}",
+                    state_file.display()
+                )))
+            }
+        },
+        None => None,
+    };
+
+    if let (Some(_), Some(state_file)) = (&args.dispatcher, &config.state_file) {
+        let mut state = State::load(state_file)?;
+        let now = state::unix_time(SystemTime::now());
+        let debounced = state
+            .last_dispatch
+            .filter(|t| now.saturating_sub(*t) < config.dispatcher_debounce.as_secs())
+            .is_some();
+        if debounced {
+            info!(
+                "Ignoring the event, the last one was less than {} seconds ago{}",
+                config.dispatcher_debounce.as_secs(),
+                profile_suffix(config)
+            );
+            return Ok(());
+        }
+        state.last_dispatch = Some(now);
+        state.save(state_file)?;
+    }
+
+    let state = match &config.state_file {
+        Some(state_file) => State::load(state_file)?,
+        None => Default::default(),
+    };
+
+    let mut published = BTreeMap::new();
+    let mut ip_cache = BTreeMap::new();
+    let mut pending = BTreeMap::new();
+    let result = update_records(
+        config,
+        args,
+        &state,
+        &mut published,
+        &mut ip_cache,
+        &mut pending,
+    )
+    .await;
+
+    if let Some(state_file) = &config.state_file {
+        let mut state = State::load(state_file)?;
+        let now = SystemTime::now();
+        // Published addresses are kept even if a later item failed.
+        state.published.extend(published);
+        state.ip_cache.extend(ip_cache);
+        if result.is_ok() {
+            state.last_success = Some(state::unix_time(now));
+            // Changes not deferred again were applied.
+            state.pending = pending;
+        } else {
+            state.pending.extend(pending);
+        }
+        state.save(state_file)?;
+    }
+
+    result
+}
+
+async fn rewrite_prefix(
+    config: &AppConfig,
+    args: &Args,
+    old: &Ipv6Prefix,
+    new: &Ipv6Prefix,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let gandi = gandi_client(config, args);
+    for zone in &config.domain_zones {
+        let domain = zone.without_dot();
+        if let Some(dir) = &config.backup_dir {
+            zone::backup(&gandi, domain, dir, config.backup_retention).await?;
+        }
+        zone::rewrite_prefix(&gandi, domain, old, new).await?;
+    }
+    Ok(())
+}
+
+// The zone commands restoring, importing and deleting rrsets act on the
+// first zone of `DOMAIN_FQDN`, selections of other zones are rejected.
+fn first_zone_only(config: &AppConfig, selection: &[String]) -> Result<(), AppError> {
+    match config.other_zone_selection(selection) {
+        Some((entry, zone)) => Err(AppError::new(&format!(
+            "Selection {} is in domain {}, but the command acts on the first domain {} of DOMAIN_FQDN only",
+            entry, zone, config.domain_fqdn
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Prints the domains the credential has access to, and warns about zones
+// of `DOMAIN_FQDN` which are not among them.
+async fn domains(config: &AppConfig, args: &Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let gandi = gandi_client(config, args);
+    let domains = gandi.list_domains().await?;
+    for domain in &domains {
+        println!("{}", domain.fqdn);
+    }
+    for zone in &config.domain_zones {
+        if !domains.iter().any(|d| d.fqdn == zone.without_dot()) {
+            warn!(
+                "Domain {} of DOMAIN_FQDN is not available to the Gandi credential",
+                zone
+            );
+        }
+    }
+    Ok(())
+}
 
-    // Which IP address to use for updating domain records.
-    let my_ip = match config.domain_ip {
-        Some(ip) => {
+// Prints the rrsets of all zones of `DOMAIN_FQDN` as Gandi serves them.
+async fn list(
+    config: &AppConfig,
+    args: &Args,
+    selection: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let gandi = gandi_client(config, args);
+    for zone in &config.domain_zones {
+        let rrsets = gandi.list_records(zone.without_dot()).await?;
+        println!("; {}", zone);
+        for line in zone::zone_lines(&rrsets, selection) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+async fn restore(
+    config: &AppConfig,
+    args: &Args,
+    file: &Path,
+    selection: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    first_zone_only(config, selection)?;
+    let gandi = gandi_client(config, args);
+    let domain = config.domain_fqdn.without_dot();
+    zone::restore(&gandi, domain, file, selection, args.yes).await
+}
+
+async fn restore_snapshot(
+    config: &AppConfig,
+    args: &Args,
+    id: &str,
+    selection: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    first_zone_only(config, selection)?;
+    let gandi = gandi_client(config, args);
+    let domain = config.domain_fqdn.without_dot();
+    zone::restore_snapshot(&gandi, domain, id, selection, args.yes).await
+}
+
+async fn import(
+    config: &AppConfig,
+    args: &Args,
+    file: &Path,
+    selection: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    first_zone_only(config, selection)?;
+    let gandi = gandi_client(config, args);
+    let domain = config.domain_fqdn.without_dot();
+    if let (Some(dir), true) = (&config.backup_dir, args.yes) {
+        zone::backup(&gandi, domain, dir, config.backup_retention).await?;
+    }
+    zone::import(&gandi, domain, file, selection, args.yes).await
+}
+
+// Sets the TTL of the rrsets of the selected dynamic items, zone by zone.
+async fn set_ttl(
+    config: &AppConfig,
+    args: &Args,
+    ttl: u64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let gandi = gandi_client(config, args);
+    for zone in &config.domain_zones {
+        let selection: Vec<String> = config
+            .domain_dynamic_items
+            .iter()
+            .filter(|i| i.zone == *zone && args.selects(&i.key(&config.domain_fqdn)))
+            .map(|i| format!("{}/{}", i.name, i.record_type))
+            .collect();
+        if selection.is_empty() {
+            continue;
+        }
+        if let (Some(dir), true) = (&config.backup_dir, args.yes) {
+            zone::backup(&gandi, zone.without_dot(), dir, config.backup_retention).await?;
+        }
+        zone::set_ttl(&gandi, zone.without_dot(), &selection, ttl, args.yes).await?;
+    }
+    Ok(())
+}
+
+async fn delete(
+    config: &AppConfig,
+    args: &Args,
+    selection: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    first_zone_only(config, selection)?;
+    let gandi = gandi_client(config, args);
+    let domain = config.domain_fqdn.without_dot();
+    if let (Some(dir), true) = (&config.backup_dir, args.yes) {
+        zone::backup(&gandi, domain, dir, config.backup_retention).await?;
+    }
+    zone::delete(&gandi, domain, selection, args.yes).await
+}
+
+fn gandi_client(config: &AppConfig, args: &Args) -> GandiClient {
+    let base_url = config.gandi_base_url(args.sandbox);
+    if base_url == gandi_client::GANDI_SANDBOX_LIVE_DNS_BASE_URL {
+        info!("Using the Gandi sandbox environment");
+    }
+    GandiClient::new(
+        config.gandi_credential.clone(),
+        config.gandi_sharing_id.clone(),
+        config.gandi_max_retries,
+        base_url,
+        HTTP_TIMEOUT,
+        config.proxy_url.as_deref(),
+    )
+}
+
+// The public IP address for records of type A or AAAA. Looked up addresses
+// are reused within `IP_CACHE_TTL`, except for runs of network hooks and
+// `watch`, after which the address may have changed.
+async fn my_ip(
+    config: &AppConfig,
+    args: &Args,
+    bootstrap_dns: &Resolver,
+    state: &State,
+    ip_cache: &mut BTreeMap<String, CachedIp>,
+    rr_type: RecordType,
+) -> Result<IpAddr, Box<dyn Error + Send + Sync>> {
+    let given = config.given_ip(rr_type);
+    let now = SystemTime::now();
+    let cached = config
+        .ip_cache_ttl
+        .filter(|_| args.dispatcher.is_none() && args.command != Command::Watch)
+        .and_then(|ttl| state.cached_ip(&rr_type.to_string(), ttl, now));
+    let ip = match (given, cached) {
+        (Some(ip), _) => {
             info!("Using given IP address {}", ip);
             ip
         }
-        None => {
-            // Initially get my external IP address
-            info!("Looking up my IP address");
-            whats_my_ip(&google_dns).await?
+        (None, Some(ip)) => {
+            info!("Using cached IP address {}", ip);
+            ip
+        }
+        (None, None) => {
+            info!("Looking up my {} address", rr_type);
+            let ip = ip_source::whats_my_ip(config, bootstrap_dns, rr_type).await?;
+            // A private address is most likely the one of the local network,
+            // not the one the records should point to.
+            match ip_source::reserved_range(ip) {
+                Some(range) if !config.allow_private_ip => {
+                    return Err(From::from(format!(
+                        "My IP address {} is not public but {}, allow with `ALLOW_PRIVATE_IP`",
+                        ip, range
+                    )));
+                }
+                Some(range) => warn!("My IP address {} is not public but {}", ip, range),
+                None => (),
+            }
+            if config.ip_cache_ttl.is_some() {
+                let looked_up = state::unix_time(now);
+                ip_cache.insert(rr_type.to_string(), CachedIp { ip, looked_up });
+            }
+            ip
         }
     };
-    info!("My IP address is {}", my_ip);
+    info!("My IP address is {}", ip);
+    Ok(ip)
+}
 
-    for domain_dynamic_item in &config.domain_dynamic_items {
-        info!(
-            "Processing domain name {}, record {}",
-            &config.domain_fqdn, domain_dynamic_item
-        );
+// The authoritative name servers of the zone polled for propagation, from
+// the cache of the run. None are discovered if the transport cannot reach
+// them, or if the discovery fails, then propagation is not waited for.
+async fn propagation_servers<'a>(
+    cache: &'a mut Vec<(Fqdn, Vec<(String, Ipv4Addr)>)>,
+    bootstrap: &Resolver,
+    transport: DnsTransport,
+    zone: &Fqdn,
+) -> &'a [(String, Ipv4Addr)] {
+    if !transport.reaches_authoritative() {
+        return &[];
+    }
+    match authoritative::per_zone(cache, zone, || authoritative::name_servers(bootstrap, zone))
+        .await
+    {
+        Ok(servers) => servers,
+        Err(e) => {
+            warn!("Cannot look up the name servers of domain {}: {}", zone, e);
+            &[]
+        }
+    }
+}
 
-        // Determine the domains authoritative name server IP address
-        // and use this to construct a resolver to query this NS.
-        let domain_record =
-            dns_lookup(&google_dns, config.domain_fqdn.clone(), RecordType::NS).await?;
-        let domain_fqdn: String = domain_record.name().to_utf8();
-        trace!("Domain {} DNS INFO {:?}", domain_fqdn, domain_record);
-
-        // Get name of authoritative NS
-        let domain_ns = ns_of_record(&domain_record).expect("Cannot get NS record");
-        debug!("Domain {} first NS name is {}", domain_fqdn, domain_ns);
-
-        // Get the IP address of the authoritative NS
-        let domain_ns_a = dns_lookup(&google_dns, domain_ns, RecordType::A).await?;
-        let domain_ns_ip = ipv4_of_record(&domain_ns_a).expect("Cannot get A record");
-        debug!("Domain {} NS IP {}", domain_fqdn, domain_ns_ip);
-
-        // Construct a resolver to query this NS
-        let ns_config = NameServerConfig {
-            protocol: Protocol::Udp,
-            socket_addr: SocketAddr::new(IpAddr::V4(domain_ns_ip), 53),
-            tls_dns_name: None,
-            trust_nx_responses: true,
+// An update of an rrset deferred to the request of its zone, see
+// `BATCH_UPDATES`.
+struct PendingUpdate {
+    zone: Fqdn,
+    name: String,
+    rr_type: RecordType,
+    // The public address of A and AAAA items.
+    ip: Option<IpAddr>,
+    // The values of other items.
+    desired: Vec<String>,
+    previous: Option<String>,
+    published_key: String,
+    dns_name: String,
+    display_name: String,
+}
+
+impl PendingUpdate {
+    // The updated rrset, merged with and keeping the TTL of the current one
+    // like a single update.
+    fn rrset(&self, current: &[GandiRRSet], config: &AppConfig) -> GandiRRSet {
+        let rr_type = self.rr_type.to_string();
+        let existing = current.iter().find(|r| {
+            r.name.as_deref() == Some(self.name.as_str()) && r.r#type.as_deref() == Some(&rr_type)
+        });
+        let values = match (self.ip, existing) {
+            (Some(ip), Some(existing)) if config.update_mode == UpdateMode::Merge => {
+                gandi_client::merge_values(
+                    &existing.values,
+                    self.previous.as_deref(),
+                    &ip.to_string(),
+                )
+            }
+            (Some(ip), _) => vec![ip.to_string()],
+            (None, _) => self.desired.clone(),
         };
-        let domain_resolver_config =
-            ResolverConfig::from_parts(Some(domain_record.name().clone()), vec![], vec![ns_config]);
+        let ttl = config
+            .domain_ttl
+            .or_else(|| existing.map(|r| r.ttl))
+            .unwrap_or(RECORD_TTL.as_secs());
+        GandiRRSet {
+            r#type: Some(rr_type),
+            ttl,
+            name: Some(self.name.clone()),
+            values,
+        }
+    }
+}
+
+async fn update_records(
+    config: &AppConfig,
+    args: &Args,
+    state: &State,
+    published: &mut BTreeMap<String, String>,
+    ip_cache: &mut BTreeMap<String, CachedIp>,
+    pending: &mut BTreeMap<String, String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let gandi = gandi_client(config, args);
+    // A rejected credential fails the run before any DNS work.
+    for zone in &config.domain_zones {
+        gandi.check_domain(zone.without_dot()).await?;
+    }
+
+    let bootstrap_config = config.resolver.config(
+        &config.resolver_servers,
+        config.dns_transport,
+        &config.dns_tls_name,
+    )?;
+    let bootstrap_opts = ResolverOpts {
+        validate: config.dnssec == DnssecMode::Strict,
+        ..resolver_opts_with_timeout()
+    };
+    let bootstrap_dns = resolver(bootstrap_config, bootstrap_opts)?;
+
+    // Which IP addresses to use for updating domain records, one per
+    // record type, looked up when first needed.
+    let mut my_ips: Vec<(RecordType, IpAddr)> = vec![];
+
+    // Resolvers querying the authoritative name servers of each zone,
+    // discovered when first needed and shared by all items of the zone.
+    let mut zone_resolvers: Vec<(Fqdn, Resolver)> = vec![];
+    let mut zone_servers: Vec<(Fqdn, Vec<(String, Ipv4Addr)>)> = vec![];
+
+    // Each zone is backed up and snapshotted once per run, before its first
+    // change.
+    let mut backed_up: Vec<Fqdn> = vec![];
+    // Zones with changed records, which get the `LAST_UPDATED_RECORD`.
+    let mut changed: Vec<Fqdn> = vec![];
+
+    // Updated records which were not served by all name servers in time.
+    let mut unpropagated: Vec<String> = vec![];
+
+    // Updates deferred to one request per zone, with `BATCH_UPDATES`.
+    let mut batch: Vec<PendingUpdate> = vec![];
+
+    for domain_dynamic_item in &config.domain_dynamic_items {
+        let item_key = domain_dynamic_item.key(&config.domain_fqdn);
+        if !args.selects(&item_key) {
+            debug!("Skipping record {}, not selected", domain_dynamic_item);
+            continue;
+        }
+        if state.paused.contains(&item_key) {
+            info!("Skipping record {}, paused", domain_dynamic_item);
+            continue;
+        }
 
-        let domain_resolver = resolver(domain_resolver_config, ResolverOpts::default())?;
+        let rr_type = domain_dynamic_item.record_type;
+        let my_ip = match my_ips.iter().find(|(t, _)| *t == rr_type) {
+            Some((_, ip)) => Some(*ip),
+            None if domain_dynamic_item.is_dynamic() => {
+                let ip = my_ip(config, args, &bootstrap_dns, state, ip_cache, rr_type).await?;
+                my_ips.push((rr_type, ip));
+                Some(ip)
+            }
+            None => None,
+        }
+        .map(|ip| domain_dynamic_item.address(ip, config.ipv6_prefix_len));
 
-        // Check the dynamic DNS record using this resolver
-        let dynamic_record_name = format!("{}.{}", domain_dynamic_item, domain_fqdn);
+        let domain_fqdn = &domain_dynamic_item.zone;
         info!(
-            "Checking domain {} dynamic item {}",
-            domain_fqdn, &dynamic_record_name
+            "Processing domain name {}, record {}",
+            domain_fqdn, domain_dynamic_item
         );
 
-        let dynamic_record =
-            dns_lookup(&domain_resolver, dynamic_record_name.clone(), RecordType::A).await?;
-        trace!("Dynamic domain {} record {:?}", domain_fqdn, dynamic_record);
-        let dynamic_ip = ipv4_of_record(&dynamic_record).expect("Cannot get IPv4 record");
+        let dynamic_record_name = domain_dynamic_item.dns_name();
+        let display_name = item::unicode_name(&dynamic_record_name);
+        let labels = domain_dynamic_item.labels_suffix();
+
+        // TTL of the rrset, only known with `CheckMode::Api`.
+        let mut current_ttl = None;
+        // Answers of each name server, only known with `NS_QUORUM`.
+        let mut answers = None;
+        let mut current = match config.check_mode {
+            CheckMode::Dns if config.ns_quorum.is_some() => {
+                let servers = authoritative::per_zone(&mut zone_servers, domain_fqdn, || {
+                    authoritative::name_servers(&bootstrap_dns, domain_fqdn)
+                })
+                .await?;
+                info!(
+                    "Checking domain {} dynamic item {} at {} name servers",
+                    domain_fqdn,
+                    display_name,
+                    servers.len()
+                );
+                let ns_answers = authoritative::answers(
+                    servers,
+                    config.dns_transport,
+                    &dynamic_record_name,
+                    rr_type,
+                )
+                .await;
+                trace!(
+                    "Dynamic domain {} record values {:?}",
+                    domain_fqdn,
+                    ns_answers
+                );
+                if ns_answers.is_empty() {
+                    return Err(From::from(format!(
+                        "No name server of domain {} answered for {}",
+                        domain_fqdn, display_name
+                    )));
+                }
+                let current = authoritative::consensus(&ns_answers);
+                answers = Some(ns_answers);
+                current
+            }
+            CheckMode::Dns => {
+                // Query the domains authoritative name servers directly.
+                let domain_resolver =
+                    authoritative::per_zone(&mut zone_resolvers, domain_fqdn, || {
+                        authoritative::zone_resolver(
+                            &bootstrap_dns,
+                            domain_fqdn,
+                            config.dns_transport,
+                            config.dnssec,
+                        )
+                    })
+                    .await?;
 
-        if dynamic_ip != my_ip {
+                // Check the dynamic DNS record using this resolver
+                info!(
+                    "Checking domain {} dynamic item {}",
+                    domain_fqdn, display_name
+                );
+                let current =
+                    dns_lookup_values(domain_resolver, dynamic_record_name.clone(), rr_type)
+                        .await?;
+                trace!("Dynamic domain {} record values {:?}", domain_fqdn, current);
+                current
+            }
+            CheckMode::None => vec![],
+            CheckMode::Api => {
+                info!(
+                    "Getting domain {} dynamic item {} from the API",
+                    domain_fqdn, display_name
+                );
+                let rrset = gandi
+                    .get_record(
+                        domain_fqdn.without_dot(),
+                        &domain_dynamic_item.name,
+                        &rr_type.to_string(),
+                    )
+                    .await?;
+                trace!("Dynamic domain {} rrset {:?}", domain_fqdn, rrset);
+                match rrset {
+                    Some(rrset) => {
+                        current_ttl = Some(rrset.ttl);
+                        rrset.values
+                    }
+                    None => vec![],
+                }
+            }
+        };
+        // Missing address records are only created on request.
+        let refused = zone::refuses_create(&current, config.check_mode, config.allow_create);
+        if refused && domain_dynamic_item.is_dynamic() {
+            return Err(From::from(format!(
+                "Record {} {} not found, set ALLOW_CREATE=true to create it",
+                display_name, rr_type
+            )));
+        }
+        let published_key = format!("{}/{}", item_key, rr_type);
+        let previous = state.published.get(&published_key).map(|p| p.as_str());
+        let mut desired = match my_ip {
+            Some(ip) if config.update_mode == UpdateMode::Merge => {
+                gandi_client::merge_values(&current, previous, &ip.to_string())
+            }
+            Some(ip) => vec![ip.to_string()],
+            None => domain_dynamic_item.values_in_zone(),
+        };
+        if my_ip.is_some()
+            && zone::refuses_replace(&current, config.update_mode, args.force_replace)
+        {
+            return Err(From::from(format!(
+                "Record {} {} has {} values ({}), refusing to replace them by a single address; use UPDATE_MODE=merge or --force-replace",
+                display_name,
+                rr_type,
+                current.len(),
+                current.join(" ")
+            )));
+        }
+        current.sort();
+        desired.sort();
+        let ttl_differs = match (current_ttl, config.domain_ttl) {
+            (Some(current), Some(desired)) => current != desired,
+            _ => false,
+        };
+        let current_text = match (config.check_mode, current_ttl) {
+            (CheckMode::None, _) => "unchecked".to_string(),
+            (_, Some(ttl)) if ttl_differs => format!("{} (ttl {})", current.join(" "), ttl),
+            _ => current.join(" "),
+        };
+        let desired_text = match config.domain_ttl {
+            Some(ttl) if ttl_differs => format!("{} (ttl {})", desired.join(" "), ttl),
+            _ => desired.join(" "),
+        };
+        let needs_update = match (&answers, config.ns_quorum) {
+            (Some(answers), Some(quorum)) => {
+                let disagree = authoritative::quorum_disagrees(answers, &desired, quorum);
+                let lagging: Vec<&str> = answers
+                    .iter()
+                    .filter(|(_, v)| *v != desired)
+                    .map(|(ns, _)| ns.as_str())
+                    .collect();
+                if !disagree && !lagging.is_empty() {
+                    info!(
+                        "Dynamic domain {} record {} {} differs only at {}, below the quorum of {}",
+                        domain_fqdn,
+                        display_name,
+                        rr_type,
+                        lagging.join(" "),
+                        quorum
+                    );
+                }
+                disagree
+            }
+            _ => zone::needs_update(config.check_mode, &current, &desired, ttl_differs),
+        };
+
+        if needs_update && !config.update_policy.allows(SystemTime::now()) {
+            warn!(
+                "Dynamic domain {} record {} {}{} needs update: {} != {}, pending until the update window opens",
+                domain_fqdn, display_name, rr_type, labels, current_text, desired_text
+            );
+            pending.insert(published_key, desired_text);
+            if let Some(ttl) = config.prelower_ttl {
+                zone::prelower_ttl(
+                    &gandi,
+                    domain_fqdn.without_dot(),
+                    &domain_dynamic_item.name,
+                    &rr_type.to_string(),
+                    ttl,
+                )
+                .await?;
+            }
+        } else if needs_update {
             info!(
-                "Dynamic domain {} record {} needs update: {} != {}",
-                domain_fqdn, &dynamic_record_name, dynamic_ip, my_ip
+                "Dynamic domain {} record {} {}{} needs update: {} != {}",
+                domain_fqdn, display_name, rr_type, labels, current_text, desired_text
             );
 
-            let domain_fqdn_without_dot = domain_fqdn.trim_end_matches('.');
+            if !backed_up.contains(domain_fqdn) {
+                if let Some(dir) = &config.backup_dir {
+                    zone::backup(
+                        &gandi,
+                        domain_fqdn.without_dot(),
+                        dir,
+                        config.backup_retention,
+                    )
+                    .await?;
+                }
+                if config.snapshot_before_update {
+                    let name = format!("gandi-dns-update-{}", state::unix_time(SystemTime::now()));
+                    let id = gandi
+                        .create_snapshot(domain_fqdn.without_dot(), &name)
+                        .await?;
+                    info!(
+                        "Created snapshot {} of domain {} before the update",
+                        id, domain_fqdn
+                    );
+                }
+                backed_up.push(domain_fqdn.clone());
+            }
 
-            gandi
-                .update_a_record(
-                    domain_fqdn_without_dot,
-                    domain_dynamic_item,
-                    &my_ip.to_string(),
-                    Duration::from_secs(300).into(),
+            if let Some(owner_id) = &config.owner_id {
+                let owned = ownership::ensure_owned(
+                    &gandi,
+                    domain_fqdn.without_dot(),
+                    &domain_dynamic_item.name,
+                    &rr_type.to_string(),
+                    owner_id,
                 )
                 .await?;
+                if !owned {
+                    continue;
+                }
+            }
+
+            if config.batch_updates {
+                debug!(
+                    "Deferring the update of {} {} to the request of domain {}",
+                    display_name, rr_type, domain_fqdn
+                );
+                batch.push(PendingUpdate {
+                    zone: domain_fqdn.clone(),
+                    name: domain_dynamic_item.name.clone(),
+                    rr_type,
+                    ip: my_ip,
+                    desired,
+                    previous: previous.map(|p| p.to_string()),
+                    published_key,
+                    dns_name: dynamic_record_name,
+                    display_name,
+                });
+            } else {
+                // The SOA serial tells when the name servers serve the
+                // updated zone.
+                let serial_before = match config.propagation {
+                    Some(_) => {
+                        let servers = propagation_servers(
+                            &mut zone_servers,
+                            &bootstrap_dns,
+                            config.dns_transport,
+                            domain_fqdn,
+                        )
+                        .await;
+                        propagation::serial(servers, config.dns_transport, domain_fqdn).await
+                    }
+                    None => None,
+                };
+
+                let domain = domain_fqdn.without_dot();
+                let name = &domain_dynamic_item.name;
+                // Without `DOMAIN_TTL` the TTL is kept, to not change the TTL
+                // policy of the zone.
+                let ttl = match config.domain_ttl.or(current_ttl) {
+                    Some(ttl) => ttl,
+                    None => gandi
+                        .get_record(domain, name, &rr_type.to_string())
+                        .await?
+                        .map(|rrset| rrset.ttl)
+                        .unwrap_or_else(|| RECORD_TTL.as_secs()),
+                };
+                let ttl = Duration::from_secs(ttl).into();
+                let written = match my_ip {
+                    Some(ip) if config.update_mode == UpdateMode::Merge => {
+                        gandi
+                            .merge_record(
+                                domain,
+                                name,
+                                &rr_type.to_string(),
+                                previous,
+                                &ip.to_string(),
+                                ttl,
+                            )
+                            .await?
+                    }
+                    Some(IpAddr::V4(ip)) => {
+                        gandi
+                            .update_a_record(domain, name, &ip.to_string(), ttl)
+                            .await?;
+                        vec![ip.to_string()]
+                    }
+                    Some(IpAddr::V6(ip)) => {
+                        gandi
+                            .update_aaaa_record(domain, name, &ip.to_string(), ttl)
+                            .await?;
+                        vec![ip.to_string()]
+                    }
+                    None => {
+                        gandi
+                            .update_record(domain, name, &rr_type.to_string(), desired.clone(), ttl)
+                            .await?;
+                        desired
+                    }
+                };
+                if !changed.contains(domain_fqdn) {
+                    changed.push(domain_fqdn.clone());
+                }
+                if let Some(ip) = my_ip {
+                    published.insert(published_key, ip.to_string());
+                }
+
+                if let Some(poll) = config.propagation {
+                    let servers = propagation_servers(
+                        &mut zone_servers,
+                        &bootstrap_dns,
+                        config.dns_transport,
+                        domain_fqdn,
+                    )
+                    .await;
+                    let propagated = propagation::wait(
+                        servers,
+                        config.dns_transport,
+                        domain_fqdn,
+                        &dynamic_record_name,
+                        rr_type,
+                        &written,
+                        serial_before,
+                        poll,
+                    )
+                    .await;
+                    if !propagated {
+                        unpropagated.push(format!("{} {}", display_name, rr_type));
+                    }
+                }
+            }
         } else {
             info!(
-                "Dynamic domain {} record {} is up to date: {}",
-                domain_fqdn, &dynamic_record_name, dynamic_ip
+                "Dynamic domain {} record {} {}{} is up to date: {}",
+                domain_fqdn, display_name, rr_type, labels, current_text
             );
+            if let Some(ip) = my_ip {
+                published.insert(published_key, ip.to_string());
+            }
+        }
+
+        if let Some(owner_id) = &config.owner_id {
+            ownership::refresh(
+                &gandi,
+                domain_fqdn.without_dot(),
+                &domain_dynamic_item.name,
+                owner_id,
+            )
+            .await?;
+        }
+    }
+
+    for zone in &config.domain_zones {
+        let pending: Vec<&PendingUpdate> = batch.iter().filter(|p| p.zone == *zone).collect();
+        if pending.is_empty() {
+            continue;
+        }
+        let serial_before = match config.propagation {
+            Some(_) => {
+                let servers = propagation_servers(
+                    &mut zone_servers,
+                    &bootstrap_dns,
+                    config.dns_transport,
+                    zone,
+                )
+                .await;
+                propagation::serial(servers, config.dns_transport, zone).await
+            }
+            None => None,
+        };
+        let domain = zone.without_dot();
+        let current = gandi.list_records(domain).await?;
+        let updates: Vec<GandiRRSet> = pending.iter().map(|p| p.rrset(&current, config)).collect();
+        match updates.as_slice() {
+            [update] => {
+                gandi
+                    .update_record(
+                        domain,
+                        &pending[0].name,
+                        &pending[0].rr_type.to_string(),
+                        update.values.clone(),
+                        Duration::from_secs(update.ttl).into(),
+                    )
+                    .await?
+            }
+            _ => {
+                info!(
+                    "Updating {} rrsets of domain {} in one request",
+                    updates.len(),
+                    zone
+                );
+                gandi
+                    .replace_records(domain, &zone::with_rrsets(&current, &updates))
+                    .await?
+            }
+        }
+        if !changed.contains(zone) {
+            changed.push(zone.clone());
         }
+
+        for (p, update) in pending.iter().zip(&updates) {
+            if let Some(ip) = p.ip {
+                published.insert(p.published_key.clone(), ip.to_string());
+            }
+            if let Some(poll) = config.propagation {
+                let servers = propagation_servers(
+                    &mut zone_servers,
+                    &bootstrap_dns,
+                    config.dns_transport,
+                    zone,
+                )
+                .await;
+                let propagated = propagation::wait(
+                    servers,
+                    config.dns_transport,
+                    zone,
+                    &p.dns_name,
+                    p.rr_type,
+                    &update.values,
+                    serial_before,
+                    poll,
+                )
+                .await;
+                if !propagated {
+                    unpropagated.push(format!("{} {}", p.display_name, p.rr_type));
+                }
+            }
+        }
+    }
+
+    if let Some(name) = &config.last_updated_record {
+        let marker = format!("last-updated={}", state::unix_time(SystemTime::now()));
+        let ttl = config.domain_ttl.unwrap_or(RECORD_TTL.as_secs());
+        for zone in &changed {
+            debug!("Setting TXT record {} of {} to {}", name, zone, marker);
+            gandi
+                .update_txt_record(
+                    zone.without_dot(),
+                    name,
+                    std::slice::from_ref(&marker),
+                    Duration::from_secs(ttl).into(),
+                )
+                .await?;
+        }
+    }
+
+    if let (Some(owner_id), Some(max_age)) = (&config.owner_id, config.ownership_gc_after) {
+        for zone in &config.domain_zones {
+            ownership::collect_garbage(
+                &gandi,
+                zone.without_dot(),
+                owner_id,
+                max_age,
+                config.ownership_gc_delete_data,
+            )
+            .await?;
+        }
+    }
+
+    if config.propagation_required && !unpropagated.is_empty() {
+        return Err(From::from(format!(
+            "Records not propagated to all name servers: {}",
+            unpropagated.join(", ")
+        )));
     }
 
     Ok(())
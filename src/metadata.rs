@@ -0,0 +1,132 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::RequestBuilder;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::http_whoami::ip_of_response;
+use crate::ip_source::IpSource;
+use crate::AppError;
+
+// The metadata services are link-local, they answer quickly or not at all.
+static METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+static AWS_TOKEN_URL: &str = "http://169.254.169.254/latest/api/token";
+
+// Cloud providers whose instance metadata service knows the public
+// address of the instance, which the instance itself does not see.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloudProvider {
+    Aws,
+    Gcp,
+    Azure,
+}
+
+impl FromStr for CloudProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "aws" => Ok(CloudProvider::Aws),
+            "gcp" => Ok(CloudProvider::Gcp),
+            "azure" => Ok(CloudProvider::Azure),
+            other => Err(format!(
+                "Unknown cloud provider '{}', expected aws, gcp or azure",
+                other
+            )),
+        }
+    }
+}
+
+fn metadata_error(msg: &str) -> AppError {
+    AppError::new(&format!("Instance metadata: {}", msg))
+}
+
+async fn send(request: RequestBuilder) -> Result<Vec<u8>, AppError> {
+    let http_err = |e: reqwest::Error| metadata_error(&format!("Request failed: {}", e));
+    let body = request
+        .timeout(METADATA_TIMEOUT)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(http_err)?
+        .bytes()
+        .await
+        .map_err(http_err)?;
+    Ok(body.to_vec())
+}
+
+impl CloudProvider {
+    // Metadata path of the public address of the primary network interface.
+    fn url(self, family: RecordType) -> Option<&'static str> {
+        match (self, family) {
+            (CloudProvider::Aws, RecordType::A) => {
+                Some("http://169.254.169.254/latest/meta-data/public-ipv4")
+            }
+            // IPv6 addresses of instances are global, there is no NAT.
+            (CloudProvider::Aws, RecordType::AAAA) => {
+                Some("http://169.254.169.254/latest/meta-data/ipv6")
+            }
+            (CloudProvider::Gcp, RecordType::A) => Some(
+                "http://169.254.169.254/computeMetadata/v1/instance/network-interfaces/0/access-configs/0/external-ip",
+            ),
+            (CloudProvider::Azure, RecordType::A) => Some(
+                "http://169.254.169.254/metadata/instance/network/interface/0/ipv4/ipAddress/0/publicIpAddress?api-version=2021-02-01&format=text",
+            ),
+            _ => None,
+        }
+    }
+
+    // Looks up the public IPv4 address for `family` A, the IPv6 address
+    // for AAAA.
+    async fn public_ip(self, family: RecordType) -> Result<IpAddr, AppError> {
+        let url = self.url(family).ok_or_else(|| {
+            metadata_error(&format!("{:?} has no public {} address", self, family))
+        })?;
+        // The metadata service is link-local, out of reach of a proxy.
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .map_err(|e| metadata_error(&format!("Request failed: {}", e)))?;
+        let request = match self {
+            CloudProvider::Aws => {
+                // IMDSv2, which requires a session token.
+                let token = send(
+                    client
+                        .put(AWS_TOKEN_URL)
+                        .header("X-aws-ec2-metadata-token-ttl-seconds", "60"),
+                )
+                .await?;
+                let token = String::from_utf8_lossy(&token).trim().to_string();
+                client.get(url).header("X-aws-ec2-metadata-token", token)
+            }
+            CloudProvider::Gcp => client.get(url).header("Metadata-Flavor", "Google"),
+            CloudProvider::Azure => client.get(url).header("Metadata", "true"),
+        };
+        let body = send(request).await?;
+        ip_of_response(&body, family).map_err(|e| metadata_error(&e))
+    }
+}
+
+#[async_trait]
+impl IpSource for CloudProvider {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        self.public_ip(family).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CloudProvider;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    #[test]
+    fn cloud_provider_parses_ok() {
+        assert_eq!(Ok(CloudProvider::Gcp), "gcp".parse());
+        assert!("hetzner".parse::<CloudProvider>().is_err());
+        assert!(CloudProvider::Aws.url(RecordType::AAAA).is_some());
+        assert!(CloudProvider::Azure.url(RecordType::AAAA).is_none());
+    }
+}
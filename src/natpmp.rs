@@ -0,0 +1,266 @@
+use log::debug;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::bind::{local_address, Bind};
+use crate::ip_source::{is_wan_address, IpSource};
+use crate::AppError;
+
+static GATEWAY_PORT: u16 = 5351;
+
+// Requests are repeated with doubling timeouts, as by RFC 6886.
+static FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+static ATTEMPTS: u32 = 4;
+
+static PCP_VERSION: u8 = 2;
+static PCP_OPCODE_MAP: u8 = 1;
+// Lifetime of the mapping, which is only requested for its response and
+// deleted right away.
+static PCP_MAP_LIFETIME: u32 = 30;
+
+fn natpmp_error(msg: &str) -> AppError {
+    AppError::new(&format!("NAT-PMP/PCP: {}", msg))
+}
+
+// Gateway of the IPv4 default route in the format of `/proc/net/route`,
+// with addresses as little endian hex numbers.
+fn default_gateway_of(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        Some(Ipv4Addr::from(gateway.swap_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+// The gateway of the default route, on Linux.
+fn default_gateway() -> Result<Ipv4Addr, AppError> {
+    let routes = fs::read_to_string("/proc/net/route")
+        .map_err(|e| natpmp_error(&format!("Cannot read routes: {}", e)))?;
+    default_gateway_of(&routes).ok_or_else(|| natpmp_error("No default gateway found"))
+}
+
+// Sends the request until a response arrives, returns its length.
+async fn exchange(socket: &UdpSocket, request: &[u8], buf: &mut [u8]) -> Result<usize, AppError> {
+    let io_err = |e: std::io::Error| natpmp_error(&format!("Request failed: {}", e));
+    let mut wait = FIRST_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await.map_err(io_err)?;
+        if let Ok(received) = timeout(wait, socket.recv(buf)).await {
+            return received.map_err(io_err);
+        }
+        wait *= 2;
+    }
+    Err(natpmp_error("Gateway did not answer"))
+}
+
+async fn connect(gateway: Ipv4Addr, local: IpAddr) -> Result<UdpSocket, AppError> {
+    let io_err = |e: std::io::Error| natpmp_error(&format!("Cannot reach gateway: {}", e));
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))
+        .await
+        .map_err(io_err)?;
+    socket
+        .connect(SocketAddr::new(IpAddr::V4(gateway), GATEWAY_PORT))
+        .await
+        .map_err(io_err)?;
+    Ok(socket)
+}
+
+// The external address of a NAT-PMP public address response (RFC 6886).
+fn natpmp_response_ip(response: &[u8]) -> Result<Ipv4Addr, String> {
+    match response {
+        [0, 128, 0, 0, _, _, _, _, a, b, c, d] => Ok(Ipv4Addr::new(*a, *b, *c, *d)),
+        [0, 128, hi, lo, ..] => Err(format!(
+            "Gateway refused with result code {}",
+            u16::from_be_bytes([*hi, *lo])
+        )),
+        [version, ..] if *version == PCP_VERSION => Err("Gateway only supports PCP".into()),
+        _ => Err("Invalid response".into()),
+    }
+}
+
+// A PCP MAP request for the UDP port of the client (RFC 6887).
+fn pcp_map_request(client: Ipv4Addr, port: u16, nonce: &[u8; 12], lifetime: u32) -> Vec<u8> {
+    let mut request = vec![PCP_VERSION, PCP_OPCODE_MAP, 0, 0];
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client.to_ipv6_mapped().octets());
+    request.extend_from_slice(nonce);
+    // UDP, reserved.
+    request.extend_from_slice(&[17, 0, 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    // No suggested external port and address.
+    request.extend_from_slice(&[0, 0]);
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+// The assigned external address of a PCP MAP response to the nonce.
+fn pcp_response_ip(response: &[u8], nonce: &[u8; 12]) -> Result<Ipv4Addr, String> {
+    if response.len() < 60 || response[0] != PCP_VERSION || response[1] != 0x80 | PCP_OPCODE_MAP {
+        return Err("Invalid response".into());
+    }
+    if response[3] != 0 {
+        return Err(format!("Gateway refused with result code {}", response[3]));
+    }
+    if &response[24..36] != nonce {
+        return Err("Response does not match the request".into());
+    }
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&response[44..60]);
+    std::net::Ipv6Addr::from(octets)
+        .to_ipv4()
+        .ok_or_else(|| "External address is not IPv4".to_string())
+}
+
+fn checked(ip: Ipv4Addr) -> Result<IpAddr, AppError> {
+    // Behind another NAT, the gateway only knows a private address.
+    if !is_wan_address(IpAddr::V4(ip)) {
+        return Err(natpmp_error(&format!(
+            "External address {} of the gateway is not public",
+            ip
+        )));
+    }
+    Ok(IpAddr::V4(ip))
+}
+
+// The external IPv4 address of the gateway, asked with NAT-PMP.
+async fn natpmp_external_ip(gateway: Ipv4Addr, local: IpAddr) -> Result<IpAddr, AppError> {
+    debug!(
+        "Asking gateway {} for its external address with NAT-PMP",
+        gateway
+    );
+    let socket = connect(gateway, local).await?;
+    let mut buf = [0u8; 16];
+    let len = exchange(&socket, &[0, 0], &mut buf).await?;
+    checked(natpmp_response_ip(&buf[..len]).map_err(|e| natpmp_error(&e))?)
+}
+
+// The external IPv4 address of the gateway, asked with PCP. PCP has no
+// request for the address alone, it is taken from a short-lived mapping,
+// which is deleted afterwards.
+async fn pcp_external_ip(gateway: Ipv4Addr, local: IpAddr) -> Result<IpAddr, AppError> {
+    debug!(
+        "Asking gateway {} for its external address with PCP",
+        gateway
+    );
+    let socket = connect(gateway, local).await?;
+    let local = socket
+        .local_addr()
+        .map_err(|e| natpmp_error(&format!("Cannot reach gateway: {}", e)))?;
+    let client = match local.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(natpmp_error("Gateway is not reached over IPv4")),
+    };
+    let nonce: [u8; 12] = rand::random();
+    let mut buf = [0u8; 1100];
+    let request = pcp_map_request(client, local.port(), &nonce, PCP_MAP_LIFETIME);
+    let len = exchange(&socket, &request, &mut buf).await?;
+    let ip = pcp_response_ip(&buf[..len], &nonce).map_err(|e| natpmp_error(&e))?;
+    let delete = pcp_map_request(client, local.port(), &nonce, 0);
+    if let Err(e) = exchange(&socket, &delete, &mut buf).await {
+        debug!("Deleting the PCP mapping failed: {}", e);
+    }
+    checked(ip)
+}
+
+// The configured gateway, or the one of the default route.
+fn gateway_or_default(gateway: Option<Ipv4Addr>) -> Result<Ipv4Addr, AppError> {
+    match gateway {
+        Some(gateway) => Ok(gateway),
+        None => default_gateway(),
+    }
+}
+
+// The gateway `GATEWAY`, asked with NAT-PMP.
+pub struct NatPmpGateway<'a> {
+    pub gateway: Option<Ipv4Addr>,
+    pub bind: Option<&'a Bind>,
+}
+
+#[async_trait]
+impl IpSource for NatPmpGateway<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        if family == RecordType::AAAA {
+            return Err(natpmp_error("IPv6 is not supported"));
+        }
+        let local = local_address(self.bind, family)?;
+        natpmp_external_ip(gateway_or_default(self.gateway)?, local).await
+    }
+}
+
+// The gateway `GATEWAY`, asked with PCP.
+pub struct PcpGateway<'a> {
+    pub gateway: Option<Ipv4Addr>,
+    pub bind: Option<&'a Bind>,
+}
+
+#[async_trait]
+impl IpSource for PcpGateway<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        if family == RecordType::AAAA {
+            return Err(natpmp_error("IPv6 is not supported"));
+        }
+        let local = local_address(self.bind, family)?;
+        pcp_external_ip(gateway_or_default(self.gateway)?, local).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        default_gateway_of, natpmp_response_ip, pcp_map_request, pcp_response_ip, PCP_VERSION,
+    };
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn default_gateway_is_parsed() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            Some(Ipv4Addr::new(192, 168, 1, 1)),
+            default_gateway_of(routes)
+        );
+        assert_eq!(None, default_gateway_of("Iface\tDestination\tGateway\n"));
+    }
+
+    #[test]
+    fn natpmp_response_is_parsed() {
+        let response = [0, 128, 0, 0, 0, 0, 1, 0, 198, 51, 100, 7];
+        assert_eq!(
+            Ok(Ipv4Addr::new(198, 51, 100, 7)),
+            natpmp_response_ip(&response)
+        );
+        assert!(natpmp_response_ip(&[0, 128, 0, 3, 0, 0, 1, 0, 0, 0, 0, 0]).is_err());
+        assert!(natpmp_response_ip(&[PCP_VERSION, 0x80, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn pcp_map_is_encoded_and_parsed() {
+        let nonce = [7u8; 12];
+        let request = pcp_map_request(Ipv4Addr::new(192, 168, 1, 2), 4711, &nonce, 30);
+        assert_eq!(60, request.len());
+        assert_eq!(&[2, 1, 0, 0, 0, 0, 0, 30], &request[..8]);
+        assert_eq!(&[0xff, 0xff, 192, 168, 1, 2], &request[18..24]);
+
+        let mut response = request.clone();
+        response[1] = 0x81;
+        response[3] = 0;
+        response[56..60].copy_from_slice(&[198, 51, 100, 7]);
+        assert_eq!(
+            Ok(Ipv4Addr::new(198, 51, 100, 7)),
+            pcp_response_ip(&response, &nonce)
+        );
+        assert!(pcp_response_ip(&response, &[0u8; 12]).is_err());
+        response[3] = 2;
+        assert!(pcp_response_ip(&response, &nonce).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use tokio::io::unix::AsyncFd;
+
+// Message types and multicast groups of rtnetlink, see rtnetlink(7).
+static RTM_NEWADDR: u16 = 20;
+static RTM_DELADDR: u16 = 21;
+static RTM_NEWROUTE: u16 = 24;
+static RTM_DELROUTE: u16 = 25;
+static RTMGRP_IPV4_IFADDR: u32 = 0x10;
+static RTMGRP_IPV4_ROUTE: u32 = 0x40;
+static RTMGRP_IPV6_IFADDR: u32 = 0x100;
+static RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+// Scope of addresses which are valid beyond the host and link.
+static RT_SCOPE_UNIVERSE: u8 = 0;
+
+static NLMSG_HDRLEN: usize = 16;
+
+// Whether a message of rtnetlink may change the public address: a global
+// address was added or removed, or the default route changed.
+fn is_relevant(kind: u16, payload: &[u8]) -> bool {
+    if kind == RTM_NEWADDR || kind == RTM_DELADDR {
+        // struct ifaddrmsg: family, prefix length, flags, scope, index.
+        payload.get(3) == Some(&RT_SCOPE_UNIVERSE)
+    } else if kind == RTM_NEWROUTE || kind == RTM_DELROUTE {
+        // struct rtmsg: family, destination length, ...
+        payload.get(1) == Some(&0)
+    } else {
+        false
+    }
+}
+
+// Whether any of the messages in a datagram of rtnetlink is relevant.
+fn has_relevant_message(mut datagram: &[u8]) -> bool {
+    while datagram.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([datagram[0], datagram[1], datagram[2], datagram[3]]) as usize;
+        let kind = u16::from_ne_bytes([datagram[4], datagram[5]]);
+        if len < NLMSG_HDRLEN || len > datagram.len() {
+            return false;
+        }
+        if is_relevant(kind, &datagram[NLMSG_HDRLEN..len]) {
+            return true;
+        }
+        // Messages are aligned to four bytes.
+        let aligned = (len + 3) & !3;
+        datagram = datagram.get(aligned..).unwrap_or_default();
+    }
+    false
+}
+
+struct Socket(RawFd);
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+impl Socket {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len =
+            unsafe { libc::recv(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(len as usize)
+        }
+    }
+}
+
+// Subscription to the address and route changes of the kernel.
+pub struct Watcher {
+    socket: AsyncFd<Socket>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Socket(fd);
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups =
+            RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE;
+        let bound = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            socket: AsyncFd::new(socket)?,
+        })
+    }
+
+    // Waits for a change which may change the public address.
+    pub async fn changed(&self) -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let mut guard = self.socket.readable().await?;
+            match guard.try_io(|socket| socket.get_ref().recv(&mut buf)) {
+                Ok(Ok(len)) if has_relevant_message(&buf[..len]) => return Ok(()),
+                Ok(Ok(_)) => (),
+                // Events were dropped, some of which may have been relevant.
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => (),
+            }
+        }
+    }
+
+    // Discards pending changes, which are covered by the next run.
+    pub fn drain(&self) {
+        let mut buf = [0u8; 8192];
+        while self.socket.get_ref().recv(&mut buf).is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_relevant_message, RTM_NEWADDR, RTM_NEWROUTE};
+
+    fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut message = ((16 + payload.len()) as u32).to_ne_bytes().to_vec();
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn relevant_messages_are_found() {
+        // Global and link-local address.
+        let global = message(RTM_NEWADDR, &[10, 64, 0, 0, 2, 0, 0, 0]);
+        let link = message(RTM_NEWADDR, &[10, 64, 0, 253, 2, 0, 0, 0]);
+        assert!(has_relevant_message(&global));
+        assert!(!has_relevant_message(&link));
+        // Default route and route of a subnet.
+        let default = message(RTM_NEWROUTE, &[2, 0, 0, 0, 254, 3, 0, 1, 0, 0, 0, 0]);
+        let subnet = message(RTM_NEWROUTE, &[2, 24, 0, 0, 254, 3, 0, 1, 0, 0, 0, 0]);
+        assert!(has_relevant_message(&default));
+        assert!(!has_relevant_message(&subnet));
+
+        let mut datagram = link;
+        datagram.extend_from_slice(&default);
+        assert!(has_relevant_message(&datagram));
+        assert!(!has_relevant_message(&datagram[..20]));
+    }
+}
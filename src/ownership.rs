@@ -0,0 +1,273 @@
+use log::{debug, info, warn};
+use std::boxed::Box;
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+
+use crate::gandi_client::{quote_txt, unquote_txt, GandiClient};
+use crate::state::unix_time;
+
+static HERITAGE: &str = "heritage=gandi-dns-update";
+
+// Ownership records are refreshed at most this often, to keep the number
+// of API requests low.
+static REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+// Parsed ownership TXT value, e.g.
+// `heritage=gandi-dns-update,owner=<id>,refreshed=<unix time>`.
+#[derive(Debug, PartialEq)]
+pub struct Ownership {
+    pub owner: String,
+    // Unix time in seconds, None for values written without timestamp.
+    pub refreshed: Option<u64>,
+}
+
+impl Ownership {
+    pub fn new(owner_id: &str, now: SystemTime) -> Self {
+        Self {
+            owner: owner_id.into(),
+            refreshed: Some(unix_time(now)),
+        }
+    }
+
+    // Parses a (quoted) TXT value, None if the value is no ownership value.
+    pub fn parse(txt_value: &str) -> Option<Self> {
+        let value = unquote_txt(txt_value);
+        let mut fields = value.split(',');
+        if fields.next()? != HERITAGE {
+            return None;
+        }
+        let mut owner = None;
+        let mut refreshed = None;
+        for field in fields {
+            if let Some(o) = field.strip_prefix("owner=") {
+                owner = Some(o.to_string());
+            } else if let Some(r) = field.strip_prefix("refreshed=") {
+                refreshed = r.parse().ok();
+            }
+        }
+        Some(Self {
+            owner: owner?,
+            refreshed,
+        })
+    }
+
+    // Quoted TXT value.
+    pub fn to_txt(&self) -> String {
+        let mut value = format!("{},owner={}", HERITAGE, self.owner);
+        if let Some(refreshed) = self.refreshed {
+            value.push_str(&format!(",refreshed={}", refreshed));
+        }
+        quote_txt(&value)
+    }
+
+    fn is_older_than(&self, age: Duration, now: SystemTime) -> bool {
+        match self.refreshed {
+            Some(t) => unix_time(now).saturating_sub(t) > age.as_secs(),
+            None => true,
+        }
+    }
+}
+
+// Verifies, before an rrset of the given name and type is modified, that
+// it is owned by this instance according to the ownership TXT record of
+// the same name. If the rrset does not exist yet, ownership is claimed by
+// adding the ownership TXT value. Returns false if the rrset must not be
+// modified.
+pub async fn ensure_owned(
+    gandi: &GandiClient,
+    domain: &str,
+    name: &str,
+    rr_type: &str,
+    owner_id: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let txt = gandi.get_record(domain, name, "TXT").await?;
+    let owners: Vec<Ownership> = txt
+        .iter()
+        .flat_map(|rrset| rrset.values.iter())
+        .filter_map(|v| Ownership::parse(v))
+        .collect();
+
+    if owners.iter().any(|o| o.owner == owner_id) {
+        return Ok(true);
+    }
+    if !owners.is_empty() {
+        let others: Vec<&str> = owners.iter().map(|o| o.owner.as_str()).collect();
+        warn!(
+            "Record {} of domain {} is owned by {}, not by {}, refusing to modify it",
+            name,
+            domain,
+            others.join(", "),
+            owner_id
+        );
+        return Ok(false);
+    }
+    if gandi.get_record(domain, name, rr_type).await?.is_some() {
+        warn!(
+            "Record {} {} of domain {} exists without ownership record, refusing to modify it",
+            name, rr_type, domain
+        );
+        return Ok(false);
+    }
+
+    info!(
+        "Claiming ownership of record {} of domain {} for {}",
+        name, domain, owner_id
+    );
+    let (mut values, ttl) = match txt {
+        Some(rrset) => (rrset.values, Duration::from_secs(rrset.ttl)),
+        None => (vec![], Duration::from_secs(300)),
+    };
+    values.push(Ownership::new(owner_id, SystemTime::now()).to_txt());
+    gandi
+        .update_record(domain, name, "TXT", values, ttl.into())
+        .await?;
+    Ok(true)
+}
+
+// Renews the timestamp of this instance's ownership value of the given
+// name, so that it is not collected by `collect_garbage`.
+pub async fn refresh(
+    gandi: &GandiClient,
+    domain: &str,
+    name: &str,
+    owner_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let now = SystemTime::now();
+    let rrset = match gandi.get_record(domain, name, "TXT").await? {
+        Some(rrset) => rrset,
+        None => return Ok(()),
+    };
+    let stale = rrset.values.iter().any(|v| {
+        Ownership::parse(v)
+            .filter(|o| o.owner == owner_id && o.is_older_than(REFRESH_INTERVAL, now))
+            .is_some()
+    });
+    if !stale {
+        return Ok(());
+    }
+
+    debug!(
+        "Refreshing ownership of record {} of domain {}",
+        name, domain
+    );
+    let values = rrset
+        .values
+        .into_iter()
+        .map(|v| match Ownership::parse(&v) {
+            Some(o) if o.owner == owner_id => Ownership::new(owner_id, now).to_txt(),
+            _ => v,
+        })
+        .collect();
+    gandi
+        .update_record(
+            domain,
+            name,
+            "TXT",
+            values,
+            Duration::from_secs(rrset.ttl).into(),
+        )
+        .await
+}
+
+// Removes ownership values of other owners, which have not been refreshed
+// within `max_age`. With `delete_data` the data records of the same name
+// are deleted as well, once no owner is left.
+pub async fn collect_garbage(
+    gandi: &GandiClient,
+    domain: &str,
+    owner_id: &str,
+    max_age: Duration,
+    delete_data: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let now = SystemTime::now();
+    let rrsets = gandi.list_records(domain).await?;
+
+    for rrset in rrsets.iter().filter(|r| r.r#type.as_deref() == Some("TXT")) {
+        let name = match &rrset.name {
+            Some(name) => name,
+            None => continue,
+        };
+        let (orphaned, kept): (Vec<&String>, Vec<&String>) = rrset.values.iter().partition(|v| {
+            Ownership::parse(v)
+                .filter(|o| {
+                    o.owner != owner_id && o.refreshed.is_some() && o.is_older_than(max_age, now)
+                })
+                .is_some()
+        });
+        if orphaned.is_empty() {
+            continue;
+        }
+        let owned = kept.iter().any(|v| Ownership::parse(v).is_some());
+
+        info!(
+            "Removing {} orphaned ownership record(s) of {} of domain {}",
+            orphaned.len(),
+            name,
+            domain
+        );
+        if kept.is_empty() {
+            gandi.delete_record(domain, name, "TXT").await?;
+        } else {
+            let kept = kept.into_iter().cloned().collect();
+            gandi
+                .update_record(
+                    domain,
+                    name,
+                    "TXT",
+                    kept,
+                    Duration::from_secs(rrset.ttl).into(),
+                )
+                .await?;
+        }
+
+        if delete_data && !owned {
+            for data in rrsets.iter().filter(|r| {
+                r.name.as_deref() == Some(name.as_str()) && r.r#type.as_deref() != Some("TXT")
+            }) {
+                let rr_type = data.r#type.as_deref().unwrap_or_default();
+                info!(
+                    "Deleting orphaned record {} {} of domain {}",
+                    name, rr_type, domain
+                );
+                gandi.delete_record(domain, name, rr_type).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ownership;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn ownership_roundtrips() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let ownership = Ownership::new("host-1", now);
+        let txt = ownership.to_txt();
+        assert_eq!(
+            "\"heritage=gandi-dns-update,owner=host-1,refreshed=1000\"",
+            txt
+        );
+        assert_eq!(Some(ownership), Ownership::parse(&txt));
+    }
+
+    #[test]
+    fn ownership_parses_legacy_and_foreign_values() {
+        let legacy = Ownership::parse("\"heritage=gandi-dns-update,owner=host-1\"").unwrap();
+        assert_eq!("host-1", legacy.owner);
+        assert_eq!(None, legacy.refreshed);
+        assert_eq!(None, Ownership::parse("\"v=spf1 -all\""));
+        assert_eq!(None, Ownership::parse("\"heritage=other,owner=host-1\""));
+    }
+
+    #[test]
+    fn ownership_ages() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let ownership = Ownership::new("host-1", UNIX_EPOCH + Duration::from_secs(900));
+        assert!(ownership.is_older_than(Duration::from_secs(50), now));
+        assert!(!ownership.is_older_than(Duration::from_secs(100), now));
+    }
+}
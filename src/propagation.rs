@@ -0,0 +1,244 @@
+use log::{debug, info, warn};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use tokio::time::sleep;
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::proto::xfer::DnsRequestOptions;
+
+use crate::authoritative;
+use crate::dns_lookup_values;
+use crate::fqdn::Fqdn;
+use crate::transport::DnsTransport;
+
+// What an updated name server serves.
+struct Expected<'a> {
+    zone: &'a Fqdn,
+    name: &'a str,
+    rr_type: RecordType,
+    // Sorted, as `serves` compares them.
+    values: Vec<String>,
+    // SOA serial of the zone before the update, if known.
+    serial_before: Option<u32>,
+}
+
+// Whether serial `a` is newer than `b`, in serial number arithmetic
+// (RFC 1982) as serials wrap around.
+fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+// SOA serial of the zone as served by the name server.
+async fn serial_at(ns: (&str, Ipv4Addr), transport: DnsTransport, zone: &Fqdn) -> Option<u32> {
+    let lookup = authoritative::server_resolver(ns, transport)?
+        .lookup(zone.as_dns(), RecordType::SOA, DnsRequestOptions::default())
+        .await
+        .ok()?;
+    let serial = lookup.record_iter().find_map(|r| match r.rdata() {
+        RData::SOA(soa) => Some(soa.serial()),
+        _ => None,
+    });
+    serial
+}
+
+// Whether the name server currently serves the expected values, and a
+// serial newer than the one before the update.
+async fn serves(ns: (&str, Ipv4Addr), transport: DnsTransport, expected: &Expected<'_>) -> bool {
+    if let Some(before) = expected.serial_before {
+        match serial_at(ns, transport, expected.zone).await {
+            Some(serial) if serial_newer(serial, before) => {}
+            _ => return false,
+        }
+    }
+    let ns_resolver = match authoritative::server_resolver(ns, transport) {
+        Some(r) => r,
+        None => return false,
+    };
+    match dns_lookup_values(&ns_resolver, expected.name.into(), expected.rr_type).await {
+        Ok(mut served) => {
+            served.sort();
+            served == expected.values
+        }
+        Err(_) => false,
+    }
+}
+
+// Time until the name server serves the new values, None on timeout.
+async fn wait_for(
+    ns: (&str, Ipv4Addr),
+    transport: DnsTransport,
+    expected: &Expected<'_>,
+    poll: Poll,
+) -> Option<Duration> {
+    let start = Instant::now();
+    loop {
+        if serves(ns, transport, expected).await {
+            return Some(start.elapsed());
+        }
+        if start.elapsed() + poll.interval > poll.timeout {
+            return None;
+        }
+        sleep(poll.interval).await;
+    }
+}
+
+// Formats per name server results as `ns1=1.3s ns2=timeout`.
+fn summarize(results: &[(String, Option<Duration>)]) -> String {
+    results
+        .iter()
+        .map(|(ns, elapsed)| match elapsed {
+            Some(d) => format!("{}={:.1}s", ns, d.as_secs_f64()),
+            None => format!("{}=timeout", ns),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// How long and how often the name servers are polled.
+#[derive(Debug, Clone, Copy)]
+pub struct Poll {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+// Highest SOA serial of the zone served by its authoritative name servers
+// `servers`, taken before an update. None if it cannot be determined, then
+// waiting relies on the updated values alone.
+pub async fn serial(
+    servers: &[(String, Ipv4Addr)],
+    transport: DnsTransport,
+    zone: &Fqdn,
+) -> Option<u32> {
+    if !transport.reaches_authoritative() {
+        return None;
+    }
+    let serials = join_all(
+        servers
+            .iter()
+            .map(|(ns, ns_ip)| serial_at((ns, *ns_ip), transport, zone)),
+    )
+    .await;
+    let serial = serials
+        .into_iter()
+        .flatten()
+        .fold(None, |max, s| match max {
+            Some(m) if !serial_newer(s, m) => Some(m),
+            _ => Some(s),
+        });
+    debug!("Domain {} SOA serial is {:?}", zone, serial);
+    serial
+}
+
+// Waits, after an update of `name` to `values`, until all authoritative
+// name servers `servers` of the zone serve the new values and a SOA serial newer
+// than `serial_before`, and logs how long this took. Returns whether they
+// all did within the timeout.
+#[allow(clippy::too_many_arguments)]
+pub async fn wait(
+    servers: &[(String, Ipv4Addr)],
+    transport: DnsTransport,
+    zone: &Fqdn,
+    name: &str,
+    rr_type: RecordType,
+    values: &[String],
+    serial_before: Option<u32>,
+    poll: Poll,
+) -> bool {
+    if !transport.reaches_authoritative() {
+        warn!(
+            "Cannot wait for propagation of {}, name servers are not queried directly",
+            name
+        );
+        return false;
+    }
+    if servers.is_empty() {
+        warn!(
+            "Cannot wait for propagation of {}, name servers are unknown",
+            name
+        );
+        return false;
+    }
+    let mut values = values.to_vec();
+    values.sort();
+    let expected = Expected {
+        zone,
+        name,
+        rr_type,
+        values,
+        serial_before,
+    };
+    debug!(
+        "Waiting up to {} seconds for {} name servers to serve {} {} {} and a SOA serial newer than {:?}",
+        poll.timeout.as_secs(),
+        servers.len(),
+        name,
+        rr_type,
+        expected.values.join(" "),
+        serial_before
+    );
+
+    let elapsed = join_all(
+        servers
+            .iter()
+            .map(|(ns, ns_ip)| wait_for((ns, *ns_ip), transport, &expected, poll)),
+    )
+    .await;
+    let results: Vec<(String, Option<Duration>)> = servers
+        .iter()
+        .map(|(ns, _)| ns.clone())
+        .zip(elapsed)
+        .collect();
+
+    // None if any name server timed out.
+    let all = results.iter().map(|(_, e)| *e).collect::<Option<Vec<_>>>();
+    match all.and_then(|e| e.into_iter().max()) {
+        Some(max) => {
+            info!(
+                "Record {} propagated to all name servers, propagation_secs={:.1} ({})",
+                name,
+                max.as_secs_f64(),
+                summarize(&results)
+            );
+            true
+        }
+        None => {
+            warn!(
+                "Record {} not propagated to all name servers within {} seconds ({})",
+                name,
+                poll.timeout.as_secs(),
+                summarize(&results)
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serial_newer, summarize};
+    use std::time::Duration;
+
+    #[test]
+    fn summarize_ok() {
+        let results = vec![
+            (
+                "ns1.example.".to_string(),
+                Some(Duration::from_millis(1300)),
+            ),
+            ("ns2.example.".to_string(), None),
+        ];
+        assert_eq!(
+            "ns1.example.=1.3s ns2.example.=timeout",
+            summarize(&results)
+        );
+    }
+
+    #[test]
+    fn serial_newer_wraps() {
+        assert!(serial_newer(2021120102, 2021120101));
+        assert!(!serial_newer(2021120101, 2021120101));
+        assert!(!serial_newer(2021120100, 2021120101));
+        assert!(serial_newer(5, u32::MAX - 5));
+    }
+}
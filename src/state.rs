@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Persistent state shared between runs, stored as JSON.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct State {
+    // Unix time in seconds of the last run which completed without error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<u64>,
+    // Names of dynamic items, which are not updated until resumed.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub paused: BTreeSet<String>,
+    // Address last published per dynamic record, keyed by `NAME/TYPE`,
+    // e.g. `home/A`. Used to tell own values from foreign ones.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub published: BTreeMap<String, String>,
+    // Unix time in seconds of the last run triggered by a network hook.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_dispatch: Option<u64>,
+    // Looked up IP addresses per record type, reused within `IP_CACHE_TTL`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ip_cache: BTreeMap<String, CachedIp>,
+    // Changes deferred as updates were not allowed by `UPDATE_WINDOWS`,
+    // the desired values keyed by `NAME/TYPE`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pending: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CachedIp {
+    pub ip: IpAddr,
+    // Unix time in seconds of the lookup.
+    pub looked_up: u64,
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl State {
+    // A missing state file is treated as empty state.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(serde_json::from_str(&text)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+
+    // Writes to a temporary file first and renames it, to never leave a
+    // partially written state file behind.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // The address of the record type looked up less than `ttl` ago.
+    pub fn cached_ip(&self, rr_type: &str, ttl: Duration, now: SystemTime) -> Option<IpAddr> {
+        self.ip_cache
+            .get(rr_type)
+            .filter(|c| unix_time(now).saturating_sub(c.looked_up) < ttl.as_secs())
+            .map(|c| c.ip)
+    }
+
+    // Time since the last successful run, if any run succeeded so far.
+    pub fn since_last_success(&self, now: SystemTime) -> Option<Duration> {
+        self.last_success
+            .map(|t| Duration::from_secs(unix_time(now).saturating_sub(t)))
+    }
+
+    // Time since the last successful run if it exceeds `max_age`, which
+    // fires the dead man's switch.
+    pub fn overdue(&self, max_age: Duration, now: SystemTime) -> Option<Duration> {
+        self.since_last_success(now).filter(|age| *age > max_age)
+    }
+}
+
+// Locks left behind by a crashed run are ignored after this time.
+static STALE_LOCK_AGE: Duration = Duration::from_secs(600);
+
+// Lock file next to the state file, held while a run updates records so
+// that runs of cron, timers and network hooks do not overlap. The lock is
+// released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    // Returns None if another run holds the lock.
+    pub fn acquire(state_file: &Path) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let path = state_file.with_extension("lock");
+        let stale = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .filter(|age| *age > STALE_LOCK_AGE)
+            .is_some();
+        if stale {
+            fs::remove_file(&path)?;
+        }
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(Some(Self { path })),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedIp, Lock, State};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn state_roundtrips_ok() {
+        let input = State {
+            last_success: Some(1234),
+            paused: vec!["home".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let text = serde_json::to_string(&input).unwrap();
+        assert_eq!(r#"{"last_success":1234,"paused":["home"]}"#, text);
+        let actual: State = serde_json::from_str(&text).unwrap();
+        assert_eq!(input, actual);
+        let empty: State = serde_json::from_str("{}").unwrap();
+        assert_eq!(State::default(), empty);
+    }
+
+    #[test]
+    fn since_last_success_ok() {
+        let state = State {
+            last_success: Some(100),
+            ..Default::default()
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(160);
+        assert_eq!(Some(Duration::from_secs(60)), state.since_last_success(now));
+        assert_eq!(None, State::default().since_last_success(now));
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            state.overdue(Duration::from_secs(59), now)
+        );
+        assert_eq!(None, state.overdue(Duration::from_secs(60), now));
+    }
+
+    #[test]
+    fn cached_ip_expires() {
+        let mut state = State::default();
+        state.ip_cache.insert(
+            "A".into(),
+            CachedIp {
+                ip: "198.51.100.7".parse().unwrap(),
+                looked_up: 100,
+            },
+        );
+        let ttl = Duration::from_secs(60);
+        let now = UNIX_EPOCH + Duration::from_secs(159);
+        assert_eq!(
+            Some("198.51.100.7".parse().unwrap()),
+            state.cached_ip("A", ttl, now)
+        );
+        assert_eq!(None, state.cached_ip("AAAA", ttl, now));
+        let later = UNIX_EPOCH + Duration::from_secs(160);
+        assert_eq!(None, state.cached_ip("A", ttl, later));
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let state_file =
+            std::env::temp_dir().join(format!("{}-lock-test.json", std::process::id()));
+        let lock = Lock::acquire(&state_file).unwrap();
+        assert!(lock.is_some());
+        assert!(Lock::acquire(&state_file).unwrap().is_none());
+        drop(lock);
+        assert!(Lock::acquire(&state_file).unwrap().is_some());
+    }
+}
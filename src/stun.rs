@@ -0,0 +1,244 @@
+use log::{debug, warn};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::bind::{local_address, Bind};
+use crate::ip_source::IpSource;
+use crate::{dns_lookup, ip_of_record, AppError, Resolver, DNS_TIMEOUT};
+
+static MAGIC_COOKIE: u32 = 0x2112_a442;
+static BINDING_REQUEST: u16 = 0x0001;
+static BINDING_RESPONSE: u16 = 0x0101;
+static MAPPED_ADDRESS: u16 = 0x0001;
+static XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+static DEFAULT_PORT: u16 = 3478;
+
+fn stun_error(msg: &str) -> AppError {
+    AppError::new(&format!("STUN: {}", msg))
+}
+
+// An entry of `STUN_SERVERS`, a host name with an optional port such as
+// `stun.l.google.com:19302`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StunServer {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for StunServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port in STUN server '{}'", s))?;
+                (host, port)
+            }
+            None => (s, DEFAULT_PORT),
+        };
+        if host.is_empty() || host.contains(':') {
+            return Err(format!("Invalid STUN server '{}', expected HOST[:PORT]", s));
+        }
+        Ok(StunServer {
+            host: host.into(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for StunServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+fn binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // No attributes.
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+    request
+}
+
+// Address of a (XOR-)MAPPED-ADDRESS attribute value. XOR-MAPPED-ADDRESS
+// values are XORed with the magic cookie and transaction id (RFC 5389).
+fn mapped_address(value: &[u8], xor: &[u8]) -> Option<IpAddr> {
+    let family = *value.get(1)?;
+    let address = value.get(4..)?;
+    let mut octets = [0u8; 16];
+    let len = match family {
+        1 => 4,
+        2 => 16,
+        _ => return None,
+    };
+    if address.len() < len {
+        return None;
+    }
+    for (i, octet) in octets.iter_mut().take(len).enumerate() {
+        *octet = address[i] ^ xor.get(i).copied().unwrap_or(0);
+    }
+    if len == 4 {
+        Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )))
+    } else {
+        Some(IpAddr::V6(Ipv6Addr::from(octets)))
+    }
+}
+
+// The reflexive address of a binding response to the transaction.
+fn reflexive_address(response: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr, String> {
+    if response.len() < 20
+        || response[0..2] != BINDING_RESPONSE.to_be_bytes()
+        || response[4..8] != MAGIC_COOKIE.to_be_bytes()
+    {
+        return Err("Invalid binding response".into());
+    }
+    if &response[8..20] != transaction_id {
+        return Err("Response does not match the request".into());
+    }
+    let mut xor = MAGIC_COOKIE.to_be_bytes().to_vec();
+    xor.extend_from_slice(transaction_id);
+
+    let mut mapped = None;
+    let mut attributes = &response[20..];
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes
+            .get(4..4 + len)
+            .ok_or_else(|| "Truncated attribute".to_string())?;
+        if kind == XOR_MAPPED_ADDRESS {
+            // Preferred, as NATs may rewrite addresses in plain form.
+            return mapped_address(value, &xor).ok_or_else(|| "Invalid address".into());
+        }
+        if kind == MAPPED_ADDRESS {
+            mapped = mapped_address(value, &[]);
+        }
+        // Attributes are padded to multiples of four bytes.
+        let padded = 4 + ((len + 3) & !3);
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+    mapped.ok_or_else(|| "Response without mapped address".into())
+}
+
+impl StunServer {
+    // The reflexive address of `family` seen by the server.
+    async fn lookup(
+        &self,
+        bootstrap_resolver: &Resolver,
+        family: RecordType,
+        local: IpAddr,
+    ) -> Result<IpAddr, AppError> {
+        let ip = match self.host.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                let record = dns_lookup(bootstrap_resolver, self.host.clone(), family).await?;
+                ip_of_record(&record).ok_or_else(|| stun_error("No address record found"))?
+            }
+        };
+        let io_err = |e: std::io::Error| stun_error(&format!("Request failed: {}", e));
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0))
+            .await
+            .map_err(io_err)?;
+        socket
+            .connect(SocketAddr::new(ip, self.port))
+            .await
+            .map_err(io_err)?;
+        let transaction_id: [u8; 12] = rand::random();
+        socket
+            .send(&binding_request(&transaction_id))
+            .await
+            .map_err(io_err)?;
+        let mut buf = [0u8; 576];
+        let len = timeout(DNS_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| stun_error("Request timed out"))?
+            .map_err(io_err)?;
+        reflexive_address(&buf[..len], &transaction_id).map_err(|e| stun_error(&e))
+    }
+}
+
+// The servers of `STUN_SERVERS`, asked in order.
+pub struct StunServers<'a> {
+    pub resolver: &'a Resolver,
+    pub servers: &'a [StunServer],
+    pub bind: Option<&'a Bind>,
+}
+
+#[async_trait]
+impl IpSource for StunServers<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        let local = local_address(self.bind, family)?;
+        whats_my_ip(self.resolver, self.servers, family, local).await
+    }
+}
+
+// Asks the servers in order, until one of them answers.
+async fn whats_my_ip(
+    bootstrap_resolver: &Resolver,
+    servers: &[StunServer],
+    family: RecordType,
+    local: IpAddr,
+) -> Result<IpAddr, AppError> {
+    let mut last_error = None;
+    for server in servers {
+        debug!(
+            "Looking up my {} address using STUN server {}",
+            family, server
+        );
+        match server.lookup(bootstrap_resolver, family, local).await {
+            Ok(ip) => return Ok(ip),
+            Err(e) => {
+                warn!("STUN server {} failed: {}", server, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| stun_error("No server configured")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binding_request, reflexive_address, StunServer};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn stun_server_parses_ok() {
+        let server: StunServer = "stun.l.google.com:19302".parse().unwrap();
+        assert_eq!("stun.l.google.com:19302", server.to_string());
+        let server: StunServer = "stun.example.net".parse().unwrap();
+        assert_eq!("stun.example.net:3478", server.to_string());
+        assert!("stun.example.net:x".parse::<StunServer>().is_err());
+        assert!(":3478".parse::<StunServer>().is_err());
+    }
+
+    #[test]
+    fn reflexive_address_is_parsed() {
+        let id = [1u8; 12];
+        let mut response = binding_request(&id);
+        response[0..2].copy_from_slice(&[0x01, 0x01]);
+        // XOR-MAPPED-ADDRESS of 198.51.100.7, port XORed as well.
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0x33, 0x33]);
+        response.extend_from_slice(&[198 ^ 0x21, 51 ^ 0x12, 100 ^ 0xa4, 7 ^ 0x42]);
+        response[2..4].copy_from_slice(&12u16.to_be_bytes());
+        assert_eq!(
+            Ok(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))),
+            reflexive_address(&response, &id)
+        );
+        assert!(reflexive_address(&response, &[2u8; 12]).is_err());
+        assert!(reflexive_address(&binding_request(&id), &id).is_err());
+    }
+}
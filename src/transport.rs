@@ -0,0 +1,339 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use trust_dns_resolver::config::{
+    NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig,
+};
+use trust_dns_resolver::system_conf::read_system_conf;
+
+static GOOGLE_IPS: &[IpAddr] = &[
+    IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+    IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8844)),
+];
+
+static CLOUDFLARE_IPS: &[IpAddr] = &[
+    IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+    IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)),
+    IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
+    IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1001)),
+];
+
+static QUAD9_IPS: &[IpAddr] = &[
+    IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9)),
+    IpAddr::V4(Ipv4Addr::new(149, 112, 112, 112)),
+    IpAddr::V6(Ipv6Addr::new(0x2620, 0xfe, 0, 0, 0, 0, 0, 0xfe)),
+    IpAddr::V6(Ipv6Addr::new(0x2620, 0xfe, 0, 0, 0, 0, 0xfe, 0x9)),
+];
+
+// How the bootstrap and authoritative resolvers reach their name servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsTransport {
+    // Plain DNS on port 53.
+    Udp,
+    // DNS-over-TLS on port 853.
+    Tls,
+    // DNS-over-HTTPS on port 443.
+    Https,
+}
+
+impl FromStr for DnsTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "udp" => Ok(DnsTransport::Udp),
+            "dot" | "tls" => Ok(DnsTransport::Tls),
+            "doh" | "https" => Ok(DnsTransport::Https),
+            _ => Err(format!("Unknown DNS transport '{}'", s)),
+        }
+    }
+}
+
+impl DnsTransport {
+    // Entries for the name server at `ip`. `tls_name` is the name its TLS
+    // certificate has to be valid for, unused for plain DNS. Plain DNS
+    // uses UDP, and TCP if an answer is truncated or UDP fails.
+    pub fn name_servers(self, ip: IpAddr, tls_name: &str) -> Vec<NameServerConfig> {
+        let (protocol, port) = match self {
+            DnsTransport::Udp => {
+                let tcp = NameServerConfig {
+                    protocol: Protocol::Tcp,
+                    ..udp(ip)
+                };
+                return vec![udp(ip), tcp];
+            }
+            DnsTransport::Tls => (Protocol::Tls, 853),
+            DnsTransport::Https => (Protocol::Https, 443),
+        };
+        vec![NameServerConfig {
+            protocol,
+            socket_addr: SocketAddr::new(ip, port),
+            tls_dns_name: Some(tls_name.trim_end_matches('.').into()),
+            trust_nx_responses: true,
+            tls_config: None,
+        }]
+    }
+
+    // Whether authoritative name servers can be queried directly with
+    // this transport. They do not offer DNS-over-HTTPS.
+    pub fn reaches_authoritative(self) -> bool {
+        self != DnsTransport::Https
+    }
+}
+
+// An entry of `RESOLVER_SERVERS`: a public resolver by name, or the
+// address of any recursive resolver with an optional port.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResolverServer {
+    Google,
+    Cloudflare,
+    Quad9,
+    Address(IpAddr, Option<u16>),
+}
+
+impl FromStr for ResolverServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_lowercase().as_str() {
+            "google" => return Ok(ResolverServer::Google),
+            "cloudflare" => return Ok(ResolverServer::Cloudflare),
+            "quad9" => return Ok(ResolverServer::Quad9),
+            _ => {}
+        }
+        if let Ok(ip) = s.parse() {
+            return Ok(ResolverServer::Address(ip, None));
+        }
+        s.parse::<SocketAddr>()
+            .map(|a| ResolverServer::Address(a.ip(), Some(a.port())))
+            .map_err(|_| format!("Invalid resolver server '{}'", s))
+    }
+}
+
+impl ResolverServer {
+    // Entries for the resolver. Addresses are checked against `tls_name`
+    // with DNS-over-TLS and DNS-over-HTTPS, public resolvers against their
+    // well-known names.
+    fn name_servers(self, transport: DnsTransport, tls_name: &str) -> Vec<NameServerConfig> {
+        let (ips, tls_name) = match self {
+            ResolverServer::Google => (GOOGLE_IPS, "dns.google"),
+            ResolverServer::Cloudflare => (CLOUDFLARE_IPS, "cloudflare-dns.com"),
+            ResolverServer::Quad9 => (QUAD9_IPS, "dns.quad9.net"),
+            ResolverServer::Address(ip, port) => {
+                let mut name_servers = transport.name_servers(ip, tls_name);
+                if let Some(port) = port {
+                    name_servers
+                        .iter_mut()
+                        .for_each(|n| n.socket_addr.set_port(port));
+                }
+                return name_servers;
+            }
+        };
+        ips.iter()
+            .flat_map(|ip| transport.name_servers(*ip, tls_name))
+            .collect()
+    }
+
+    pub fn is_address(self) -> bool {
+        matches!(self, ResolverServer::Address(..))
+    }
+}
+
+// Recursive resolver used to discover name servers and whoami services.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootstrapResolver {
+    // The resolvers of `RESOLVER_SERVERS`.
+    Servers,
+    // The name servers of `/etc/resolv.conf`, for networks on which public
+    // resolvers are blocked.
+    System,
+}
+
+impl FromStr for BootstrapResolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "servers" => Ok(BootstrapResolver::Servers),
+            "system" => Ok(BootstrapResolver::System),
+            _ => Err(format!("Unknown resolver '{}'", s)),
+        }
+    }
+}
+
+impl BootstrapResolver {
+    // Configuration of the resolver, reached with the given transport. The
+    // system resolver is always reached with plain DNS.
+    pub fn config(
+        self,
+        servers: &[ResolverServer],
+        transport: DnsTransport,
+        tls_name: &str,
+    ) -> io::Result<ResolverConfig> {
+        match self {
+            BootstrapResolver::Servers => {
+                let name_servers: Vec<NameServerConfig> = servers
+                    .iter()
+                    .flat_map(|s| s.name_servers(transport, tls_name))
+                    .collect();
+                Ok(ResolverConfig::from_parts(
+                    None,
+                    vec![],
+                    NameServerConfigGroup::from(name_servers),
+                ))
+            }
+            BootstrapResolver::System => read_system_conf().map(|(config, _)| config),
+        }
+    }
+}
+
+// Whether answers of the bootstrap resolver are DNSSEC validated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnssecMode {
+    Off,
+    // Answers which cannot be validated are errors.
+    Strict,
+}
+
+impl FromStr for DnssecMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(DnssecMode::Off),
+            "strict" => Ok(DnssecMode::Strict),
+            _ => Err(format!("Unknown DNSSEC mode '{}'", s)),
+        }
+    }
+}
+
+// Entry for the name server at `ip` using plain DNS, e.g. for whoami
+// services which answer with the address the query came from.
+pub fn udp(ip: IpAddr) -> NameServerConfig {
+    NameServerConfig {
+        protocol: Protocol::Udp,
+        socket_addr: SocketAddr::new(ip, 53),
+        tls_dns_name: None,
+        trust_nx_responses: true,
+        tls_config: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BootstrapResolver, DnsTransport, DnssecMode, ResolverServer};
+    use trust_dns_resolver::config::Protocol;
+
+    #[test]
+    fn dns_transport_name_servers() {
+        assert_eq!(DnsTransport::Udp, "udp".parse().unwrap());
+        assert_eq!(DnsTransport::Tls, "DoT".parse().unwrap());
+        assert_eq!(DnsTransport::Https, "doh".parse().unwrap());
+        assert!("tcp".parse::<DnsTransport>().is_err());
+
+        let ip = "8.8.8.8".parse().unwrap();
+        let ns = DnsTransport::Tls.name_servers(ip, "ns1.example.");
+        assert_eq!(1, ns.len());
+        assert_eq!(Protocol::Tls, ns[0].protocol);
+        assert_eq!(853, ns[0].socket_addr.port());
+        assert_eq!(Some("ns1.example".to_string()), ns[0].tls_dns_name);
+        let ns = DnsTransport::Https.name_servers(ip, "dns.google");
+        assert_eq!(Protocol::Https, ns[0].protocol);
+        assert_eq!(443, ns[0].socket_addr.port());
+        let ns = DnsTransport::Udp.name_servers(ip, "ns1.example.");
+        let protocols: Vec<Protocol> = ns.iter().map(|n| n.protocol).collect();
+        assert_eq!(vec![Protocol::Udp, Protocol::Tcp], protocols);
+        assert!(ns.iter().all(|n| n.socket_addr.port() == 53));
+    }
+
+    #[test]
+    fn dnssec_mode_parses_ok() {
+        assert_eq!(DnssecMode::Off, "off".parse().unwrap());
+        assert_eq!(DnssecMode::Strict, " Strict".parse().unwrap());
+        assert!("on".parse::<DnssecMode>().is_err());
+    }
+
+    #[test]
+    fn resolver_server_parses_ok() {
+        assert_eq!(ResolverServer::Quad9, "Quad9".parse().unwrap());
+        let server: ResolverServer = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            ResolverServer::Address("192.0.2.1".parse().unwrap(), None),
+            server
+        );
+        let server: ResolverServer = "[2001:db8::1]:5353".parse().unwrap();
+        let expected = ResolverServer::Address("2001:db8::1".parse().unwrap(), Some(5353));
+        assert_eq!(expected, server);
+        let ns = server.name_servers(DnsTransport::Udp, "");
+        assert!(ns.iter().all(|n| n.socket_addr.port() == 5353));
+        assert!("192.0.2.1:x".parse::<ResolverServer>().is_err());
+    }
+
+    #[test]
+    fn bootstrap_resolver_over_tls() {
+        let servers = [
+            ResolverServer::Google,
+            ResolverServer::Address("192.0.2.1".parse().unwrap(), None),
+        ];
+        let config = BootstrapResolver::Servers
+            .config(&servers, DnsTransport::Tls, "resolver.example")
+            .unwrap();
+        let ns = config.name_servers();
+        assert_eq!(5, ns.len());
+        assert!(ns
+            .iter()
+            .all(|n| n.protocol == Protocol::Tls && n.socket_addr.port() == 853));
+        let names: Vec<&str> = ns
+            .iter()
+            .filter_map(|n| n.tls_dns_name.as_deref())
+            .collect();
+        assert_eq!(
+            vec![
+                "dns.google",
+                "dns.google",
+                "dns.google",
+                "dns.google",
+                "resolver.example"
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn bootstrap_resolver_falls_back_to_tcp() {
+        let servers = [
+            ResolverServer::Quad9,
+            ResolverServer::Address("192.0.2.1".parse().unwrap(), Some(5353)),
+        ];
+        let config = BootstrapResolver::Servers
+            .config(&servers, DnsTransport::Udp, "")
+            .unwrap();
+        let ns = config.name_servers();
+        assert_eq!(10, ns.len());
+        for pair in ns.chunks(2) {
+            assert_eq!(Protocol::Udp, pair[0].protocol);
+            assert_eq!(Protocol::Tcp, pair[1].protocol);
+            assert_eq!(pair[0].socket_addr, pair[1].socket_addr);
+            assert_eq!(None, pair[1].tls_dns_name);
+        }
+        assert_eq!(5353, ns[9].socket_addr.port());
+    }
+
+    #[test]
+    fn bootstrap_resolver_over_https() {
+        let config = BootstrapResolver::Servers
+            .config(&[ResolverServer::Cloudflare], DnsTransport::Https, "")
+            .unwrap();
+        let ns = config.name_servers();
+        assert_eq!(4, ns.len());
+        assert!(ns.iter().all(|n| n.protocol == Protocol::Https
+            && n.socket_addr.port() == 443
+            && n.tls_dns_name.as_deref() == Some("cloudflare-dns.com")));
+        assert!(!DnsTransport::Https.reaches_authoritative());
+        assert!(DnsTransport::Tls.reaches_authoritative());
+    }
+}
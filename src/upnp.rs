@@ -0,0 +1,208 @@
+use log::debug;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{header, Url};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::bind::{local_address, Bind};
+use crate::ip_source::{is_wan_address, IpSource};
+use crate::{AppError, HTTP_TIMEOUT};
+
+static SSDP_ADDR: &str = "239.255.255.250:1900";
+
+// Routers answer SSDP searches within the MX seconds of the request.
+static DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+static SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+// Services of an Internet gateway device which know its external address.
+static WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+fn upnp_error(msg: &str) -> AppError {
+    AppError::new(&format!("UPnP: {}", msg))
+}
+
+// Value of a header of an SSDP response, names are case insensitive.
+fn header_value<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+// Text of the first element `tag` in `xml`, namespace prefixes aside.
+fn element_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("{}>", tag))? + tag.len() + 1;
+    let end = start + xml[start..].find('<')?;
+    Some(xml[start..end].trim())
+}
+
+// Service type and control URL of the first WAN connection service of a
+// device description, relative URLs are resolved against its location.
+fn wan_service(description: &str, location: &Url) -> Option<(&'static str, Url)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = element_text(service, "serviceType")?;
+        let known = WAN_SERVICES.iter().find(|s| **s == service_type)?;
+        let control_url = location.join(element_text(service, "controlURL")?).ok()?;
+        Some((*known, control_url))
+    })
+}
+
+// Location of the device description of the first gateway answering an
+// SSDP search.
+async fn discover(local: IpAddr) -> Result<Url, AppError> {
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+        SSDP_ADDR,
+        DISCOVERY_TIMEOUT.as_secs(),
+        SEARCH_TARGET
+    );
+    let io_err = |e: std::io::Error| upnp_error(&format!("SSDP search failed: {}", e));
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))
+        .await
+        .map_err(io_err)?;
+    socket
+        .send_to(request.as_bytes(), SSDP_ADDR)
+        .await
+        .map_err(io_err)?;
+    let mut buf = [0u8; 2048];
+    let (len, from) = timeout(DISCOVERY_TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| upnp_error("No Internet gateway device answered"))?
+        .map_err(io_err)?;
+    let response = String::from_utf8_lossy(&buf[..len]);
+    let location = header_value(&response, "LOCATION")
+        .ok_or_else(|| upnp_error(&format!("Answer of {} without location", from)))?;
+    debug!("UPnP gateway {} is described at {}", from, location);
+    Url::parse(location).map_err(|e| upnp_error(&format!("Invalid location {}: {}", location, e)))
+}
+
+// The Internet gateway device of the network, asked for its external
+// address.
+pub struct UpnpGateway<'a> {
+    pub bind: Option<&'a Bind>,
+}
+
+#[async_trait]
+impl IpSource for UpnpGateway<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        if family == RecordType::AAAA {
+            return Err(upnp_error("IPv6 is not supported"));
+        }
+        external_ip(local_address(self.bind, family)?).await
+    }
+}
+
+// The external IPv4 address of the Internet gateway device of the network,
+// as reported by its `GetExternalIPAddress` action.
+async fn external_ip(local: IpAddr) -> Result<IpAddr, AppError> {
+    let location = discover(local).await?;
+    let http_err = |e: reqwest::Error| upnp_error(&format!("Request failed: {}", e));
+    let client = reqwest::Client::builder()
+        .local_address(local)
+        .timeout(HTTP_TIMEOUT)
+        // The gateway is on the local network.
+        .no_proxy()
+        .build()
+        .map_err(http_err)?;
+    let description = client
+        .get(location.clone())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(http_err)?
+        .text()
+        .await
+        .map_err(http_err)?;
+    let (service_type, control_url) = wan_service(&description, &location)
+        .ok_or_else(|| upnp_error("Gateway has no WAN connection service"))?;
+
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:GetExternalIPAddress xmlns:u=\"{}\"/></s:Body></s:Envelope>",
+        service_type
+    );
+    let response = client
+        .post(control_url)
+        .header(header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .header(
+            "SOAPAction",
+            format!("\"{}#GetExternalIPAddress\"", service_type),
+        )
+        .body(body)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(http_err)?
+        .text()
+        .await
+        .map_err(http_err)?;
+    let address = element_text(&response, "NewExternalIPAddress")
+        .ok_or_else(|| upnp_error("Response without external address"))?;
+    let ip: Ipv4Addr = address
+        .parse()
+        .map_err(|_| upnp_error(&format!("Invalid external address '{}'", address)))?;
+    // Behind another NAT, the gateway only knows a private address.
+    if !is_wan_address(IpAddr::V4(ip)) {
+        return Err(upnp_error(&format!(
+            "External address {} of the gateway is not public",
+            ip
+        )));
+    }
+    Ok(IpAddr::V4(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{element_text, header_value, wan_service};
+    use reqwest::Url;
+
+    #[test]
+    fn ssdp_header_is_found() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            Some("http://192.168.1.1:5000/rootDesc.xml"),
+            header_value(response, "LOCATION")
+        );
+        assert_eq!(None, header_value(response, "ST"));
+    }
+
+    #[test]
+    fn wan_service_is_found() {
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let location = Url::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        let (service_type, url) = wan_service(description, &location).unwrap();
+        assert_eq!(
+            "urn:schemas-upnp-org:service:WANIPConnection:1",
+            service_type
+        );
+        assert_eq!("http://192.168.1.1:5000/ctl/IPConn", url.as_str());
+
+        let response = "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+            <NewExternalIPAddress>198.51.100.7</NewExternalIPAddress>\
+            </u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+        assert_eq!(
+            Some("198.51.100.7"),
+            element_text(response, "NewExternalIPAddress")
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use log::{debug, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use futures::future::select_ok;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use trust_dns_resolver::proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_resolver::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+
+use crate::bind::{local_address, Bind};
+use crate::ip_source::{race_answer, IpSource};
+use crate::{dns_lookup, ip_of_record, AppError, Resolver, DNS_TIMEOUT};
+
+// DNS based services which answer with the public IP address of the
+// querying client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WhoamiService {
+    // `myip.opendns.com` A, asked at `resolver1.opendns.com`.
+    OpenDns,
+    // `whoami.cloudflare` TXT in class CH, asked at 1.1.1.1.
+    Cloudflare,
+    // `whoami.akamai.net` A, asked at `ns1-1.akamaitech.net`.
+    Akamai,
+    // `o-o.myaddr.l.google.com` TXT, asked at `ns1.google.com`.
+    Google,
+    // An own service `NAME@SERVER[/TYPE]`, such as
+    // `myip.example.net@ns1.example.net/TXT`. Without type, the name is
+    // looked up with the type of the family, like at OpenDNS.
+    Custom {
+        name: String,
+        server: String,
+        rr_type: Option<RecordType>,
+    },
+}
+
+fn absolute(name: &str) -> String {
+    if name.ends_with('.') {
+        name.into()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+fn parse_custom(s: &str) -> Result<WhoamiService, String> {
+    let invalid = || {
+        format!(
+            "Invalid whoami service '{}', expected NAME@SERVER[/TYPE]",
+            s
+        )
+    };
+    let (name, rest) = s.split_once('@').ok_or_else(invalid)?;
+    let (server, rr_type) = match rest.split_once('/') {
+        Some((server, rr_type)) => match rr_type.to_uppercase().as_str() {
+            "A" => (server, Some(RecordType::A)),
+            "AAAA" => (server, Some(RecordType::AAAA)),
+            "TXT" => (server, Some(RecordType::TXT)),
+            _ => return Err(format!("Unsupported record type in whoami service '{}'", s)),
+        },
+        None => (rest, None),
+    };
+    if name.is_empty() || server.is_empty() {
+        return Err(invalid());
+    }
+    Ok(WhoamiService::Custom {
+        name: absolute(name),
+        server: absolute(server),
+        rr_type,
+    })
+}
+
+impl FromStr for WhoamiService {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "opendns" => Ok(WhoamiService::OpenDns),
+            "cloudflare" => Ok(WhoamiService::Cloudflare),
+            "akamai" => Ok(WhoamiService::Akamai),
+            "google" => Ok(WhoamiService::Google),
+            other if other.contains('@') => parse_custom(other),
+            other => Err(format!(
+                "Unknown whoami service '{}', expected opendns, cloudflare, akamai, google or NAME@SERVER[/TYPE]",
+                other
+            )),
+        }
+    }
+}
+
+static CLOUDFLARE_DNS: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+static CLOUDFLARE_DNS_V6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
+
+// Queries `name` directly at the name server at `server` from the local
+// address `local`, which selects the family. The query is sent by hand, as
+// the resolver can neither bind its sockets nor query class CH.
+async fn query(
+    server: IpAddr,
+    name: &str,
+    rr_type: RecordType,
+    class: DNSClass,
+    local: IpAddr,
+) -> Result<Vec<Record>, AppError> {
+    let name =
+        Name::from_ascii(name).map_err(|e| AppError::new(&format!("Invalid name: {}", e)))?;
+    let mut query = Query::query(name, rr_type);
+    query.set_query_class(class);
+    let mut request = Message::new();
+    request
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(false)
+        .add_query(query);
+    let request_bytes = request
+        .to_vec()
+        .map_err(|e| AppError::new(&format!("Cannot encode DNS query: {}", e)))?;
+
+    let io_err = |e: std::io::Error| AppError::new(&format!("DNS query failed: {}", e));
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))
+        .await
+        .map_err(io_err)?;
+    socket
+        .send_to(&request_bytes, SocketAddr::new(server, 53))
+        .await
+        .map_err(io_err)?;
+    let mut buf = [0u8; 512];
+    let len = timeout(DNS_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| AppError::new("DNS query timed out"))?
+        .map_err(io_err)?;
+
+    let response = Message::from_vec(&buf[..len])
+        .map_err(|e| AppError::new(&format!("Cannot decode DNS response: {}", e)))?;
+    if response.id() != request.id() {
+        return Err(AppError::new("DNS response id does not match query"));
+    }
+    Ok(response.answers().to_vec())
+}
+
+// Queries `name` directly at the given name server, which is looked up
+// using the bootstrap resolver. The name server is contacted over IPv4
+// for `family` A and over IPv6 for AAAA, as whoami services answer with
+// the address the query came from.
+async fn lookup_at(
+    bootstrap_resolver: &Resolver,
+    ns_name: &str,
+    name: &str,
+    rr_type: RecordType,
+    family: RecordType,
+    local: IpAddr,
+) -> Result<Vec<Record>, AppError> {
+    let ns_record = dns_lookup(bootstrap_resolver, ns_name.into(), family).await?;
+    let ns_ip = ip_of_record(&ns_record).ok_or_else(|| AppError::new("No address record found"))?;
+    query(ns_ip, name, rr_type, DNSClass::IN, local).await
+}
+
+fn is_of_family(ip: &IpAddr, family: RecordType) -> bool {
+    match ip {
+        IpAddr::V4(_) => family == RecordType::A,
+        IpAddr::V6(_) => family == RecordType::AAAA,
+    }
+}
+
+fn ip_of_txt_data(data: &[Box<[u8]>], family: RecordType) -> Option<IpAddr> {
+    data.iter().find_map(|d| {
+        std::str::from_utf8(d)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .filter(|ip| is_of_family(ip, family))
+    })
+}
+
+fn ip_of_txt_record(record: &Record, family: RecordType) -> Option<IpAddr> {
+    match record.rdata() {
+        RData::TXT(txt) => ip_of_txt_data(txt.txt_data(), family),
+        _ => None,
+    }
+}
+
+async fn cloudflare_whoami(family: RecordType, local: IpAddr) -> Result<IpAddr, AppError> {
+    let server = if family == RecordType::AAAA {
+        IpAddr::V6(CLOUDFLARE_DNS_V6)
+    } else {
+        IpAddr::V4(CLOUDFLARE_DNS)
+    };
+    query(
+        server,
+        "whoami.cloudflare.",
+        RecordType::TXT,
+        DNSClass::CH,
+        local,
+    )
+    .await?
+    .iter()
+    .find_map(|r| ip_of_txt_record(r, family))
+    .ok_or_else(|| AppError::new("No address TXT record found"))
+}
+
+impl WhoamiService {
+    // Looks up the public IPv4 address for `family` A, the IPv6 address
+    // for AAAA, querying from the local address of the family.
+    async fn lookup(
+        &self,
+        bootstrap_resolver: &Resolver,
+        family: RecordType,
+        local: IpAddr,
+    ) -> Result<IpAddr, AppError> {
+        let no_record = || AppError::new(&format!("No {} record found", family));
+        let (ns_name, name, rr_type) = match self {
+            WhoamiService::OpenDns => ("resolver1.opendns.com.", "myip.opendns.com.", family),
+            WhoamiService::Cloudflare => return cloudflare_whoami(family, local).await,
+            WhoamiService::Akamai if family == RecordType::AAAA => {
+                return Err(AppError::new("Akamai does not support IPv6"));
+            }
+            WhoamiService::Akamai => ("ns1-1.akamaitech.net.", "whoami.akamai.net.", RecordType::A),
+            WhoamiService::Google => (
+                "ns1.google.com.",
+                "o-o.myaddr.l.google.com.",
+                RecordType::TXT,
+            ),
+            WhoamiService::Custom {
+                name,
+                server,
+                rr_type,
+            } => (server.as_str(), name.as_str(), rr_type.unwrap_or(family)),
+        };
+        let records = lookup_at(bootstrap_resolver, ns_name, name, rr_type, family, local).await?;
+        records
+            .iter()
+            .find_map(|record| {
+                if rr_type == RecordType::TXT {
+                    ip_of_txt_record(record, family)
+                } else {
+                    ip_of_record(record).filter(|ip| is_of_family(ip, family))
+                }
+            })
+            .ok_or_else(no_record)
+    }
+}
+
+// The services of `WHOAMI_SERVICES`, asked as by `WHOAMI_STRATEGY`, or a
+// single whoami service.
+pub struct WhoamiServices<'a> {
+    pub resolver: &'a Resolver,
+    pub services: &'a [WhoamiService],
+    pub strategy: WhoamiStrategy,
+    // Whether a racing service may win with an address which is not public.
+    pub allow_private_ip: bool,
+    pub bind: Option<&'a Bind>,
+}
+
+#[async_trait]
+impl IpSource for WhoamiServices<'_> {
+    async fn current_ip(&self, family: RecordType) -> Result<IpAddr, AppError> {
+        let local = local_address(self.bind, family)?;
+        whats_my_ip(
+            self.resolver,
+            self.services,
+            self.strategy,
+            self.allow_private_ip,
+            family,
+            local,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhoamiStrategy {
+    // Ask the services in order, until one of them answers.
+    Fallback,
+    // Ask all services concurrently, the first valid answer wins.
+    Race,
+}
+
+impl FromStr for WhoamiStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "fallback" => Ok(WhoamiStrategy::Fallback),
+            "race" => Ok(WhoamiStrategy::Race),
+            other => Err(format!(
+                "Unknown whoami strategy '{}', expected fallback or race",
+                other
+            )),
+        }
+    }
+}
+
+async fn lookup_logged(
+    service: &WhoamiService,
+    bootstrap_resolver: &Resolver,
+    family: RecordType,
+    local: IpAddr,
+) -> Result<IpAddr, AppError> {
+    debug!("Looking up my {} address using {:?}", family, service);
+    let result = service.lookup(bootstrap_resolver, family, local).await;
+    match &result {
+        Ok(ip) => debug!("Whoami service {:?} answered {}", service, ip),
+        Err(e) => warn!("Whoami service {:?} failed: {}", service, e),
+    }
+    result
+}
+
+async fn whats_my_ip(
+    bootstrap_resolver: &Resolver,
+    services: &[WhoamiService],
+    strategy: WhoamiStrategy,
+    allow_private_ip: bool,
+    family: RecordType,
+    local: IpAddr,
+) -> Result<IpAddr, AppError> {
+    if services.is_empty() {
+        return Err(AppError::new("No whoami service configured"));
+    }
+    match strategy {
+        WhoamiStrategy::Fallback => {
+            let mut last_error = None;
+            for service in services {
+                match lookup_logged(service, bootstrap_resolver, family, local).await {
+                    Ok(ip) => return Ok(ip),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(last_error.unwrap_or_else(|| AppError::new("No whoami service answered")))
+        }
+        WhoamiStrategy::Race => {
+            let lookups = services.iter().map(|service| {
+                Box::pin(async move {
+                    let ip = lookup_logged(service, bootstrap_resolver, family, local).await?;
+                    race_answer(ip, allow_private_ip).map_err(|e| {
+                        warn!("Whoami service {:?} failed: {}", service, e);
+                        e
+                    })
+                })
+            });
+            let (ip, _pending) = select_ok(lookups).await?;
+            Ok(ip)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ip_of_txt_data, WhoamiService, WhoamiStrategy};
+    use std::net::{IpAddr, Ipv4Addr};
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    #[test]
+    fn whoami_service_parses_ok() {
+        assert_eq!(Ok(WhoamiService::OpenDns), "opendns".parse());
+        assert_eq!(Ok(WhoamiService::Cloudflare), " cloudflare".parse());
+        assert!("foo".parse::<WhoamiService>().is_err());
+        assert_eq!(
+            Ok(WhoamiService::Custom {
+                name: "myip.example.net.".into(),
+                server: "ns1.example.net.".into(),
+                rr_type: Some(RecordType::TXT),
+            }),
+            "myip.example.net@ns1.example.net/txt".parse()
+        );
+        assert_eq!(
+            Ok(WhoamiService::Custom {
+                name: "myip.example.net.".into(),
+                server: "ns1.example.net.".into(),
+                rr_type: None,
+            }),
+            "myip.example.net.@ns1.example.net".parse()
+        );
+        assert!("myip.example.net@".parse::<WhoamiService>().is_err());
+        assert!("myip.example.net@ns1.example.net/MX"
+            .parse::<WhoamiService>()
+            .is_err());
+        assert_eq!(Ok(WhoamiStrategy::Race), "race".parse());
+        assert!("first".parse::<WhoamiStrategy>().is_err());
+    }
+
+    #[test]
+    fn ip_of_txt_data_skips_non_addresses() {
+        let data: Vec<Box<[u8]>> = vec![
+            Box::from(&b"edns0-client-subnet 192.0.2.0/24"[..]),
+            Box::from(&b"198.51.100.7"[..]),
+            Box::from(&b"2001:db8::7"[..]),
+        ];
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))),
+            ip_of_txt_data(&data, RecordType::A)
+        );
+        assert_eq!(
+            Some("2001:db8::7".parse().unwrap()),
+            ip_of_txt_data(&data, RecordType::AAAA)
+        );
+    }
+}
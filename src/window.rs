@@ -0,0 +1,258 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// A time window on a set of week days, e.g. `Mon-Fri 22:00-06:00`.
+// Times are in UTC, windows crossing midnight end on the following day.
+#[derive(Debug, PartialEq)]
+pub struct UpdateWindow {
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+impl UpdateWindow {
+    // Weekday 0 is Monday, minute is the minute of the day.
+    pub fn contains(&self, weekday: usize, minute: u32) -> bool {
+        if self.start < self.end {
+            self.days[weekday] && minute >= self.start && minute < self.end
+        } else {
+            let yesterday = (weekday + 6) % 7;
+            (self.days[weekday] && minute >= self.start)
+                || (self.days[yesterday] && minute < self.end)
+        }
+    }
+}
+
+static WEEKDAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+// A week day by its abbreviation such as `Mon` or its full name.
+fn parse_weekday(s: &str) -> Result<usize, String> {
+    let lower = s.to_lowercase();
+    WEEKDAYS
+        .iter()
+        .zip(WEEKDAY_NAMES.iter())
+        .position(|(short, long)| lower == *short || lower == *long)
+        .ok_or_else(|| format!("Invalid week day '{}'", s))
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    for part in s.split('+') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let from = parse_weekday(from)?;
+                let to = parse_weekday(to)?;
+                let mut day = from;
+                loop {
+                    days[day] = true;
+                    if day == to {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days[parse_weekday(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let err = || format!("Invalid time of day '{}', expected HH:MM", s);
+    let (h, m) = s.split_once(':').ok_or_else(err)?;
+    let h: u32 = h.parse().map_err(|_| err())?;
+    let m: u32 = m.parse().map_err(|_| err())?;
+    if m > 59 || h > 24 || (h == 24 && m != 0) {
+        return Err(err());
+    }
+    Ok(h * 60 + m)
+}
+
+impl FromStr for UpdateWindow {
+    type Err = String;
+
+    // Format: `[DAYS ]HH:MM-HH:MM`, where DAYS is `Mon`, `Mon-Fri` or `Sat+Sun`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (days, times) = match s.rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => ([true; 7], s),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid time range '{}', expected HH:MM-HH:MM", times))?;
+        let start = parse_time(start)?;
+        let end = parse_time(end)?;
+        if start == end {
+            return Err(format!("Empty time range '{}'", times));
+        }
+        Ok(Self { days, start, end })
+    }
+}
+
+impl fmt::Display for UpdateWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days: Vec<&str> = WEEKDAYS
+            .iter()
+            .zip(self.days.iter())
+            .filter_map(|(name, on)| if *on { Some(*name) } else { None })
+            .collect();
+        write!(
+            f,
+            "{} {:02}:{:02}-{:02}:{:02} UTC",
+            days.join("+"),
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WindowMode {
+    Allow,
+    Deny,
+}
+
+impl FromStr for WindowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(WindowMode::Allow),
+            "deny" => Ok(WindowMode::Deny),
+            _ => Err(format!(
+                "Invalid window mode '{}', expected allow or deny",
+                s
+            )),
+        }
+    }
+}
+
+// Decides whether updates may be applied at a given point in time.
+#[derive(Debug)]
+pub struct UpdatePolicy {
+    pub mode: WindowMode,
+    pub windows: Vec<UpdateWindow>,
+}
+
+// Minutes of a week, after which the windows repeat.
+static WEEK_MINUTES: u64 = 7 * 24 * 60;
+
+impl UpdatePolicy {
+    pub fn allows(&self, time: SystemTime) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let days = secs / 86400;
+        // 1970-01-01 was a Thursday.
+        let weekday = ((days + 3) % 7) as usize;
+        let minute = ((secs % 86400) / 60) as u32;
+        let inside = self.windows.iter().any(|w| w.contains(weekday, minute));
+        match self.mode {
+            WindowMode::Allow => inside,
+            WindowMode::Deny => !inside,
+        }
+    }
+
+    // The start of the next minute at or after `time` in which updates are
+    // allowed, none if they never are.
+    pub fn next_allowed(&self, time: SystemTime) -> Option<SystemTime> {
+        if self.allows(time) {
+            return Some(time);
+        }
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let minute = UNIX_EPOCH + Duration::from_secs(secs - secs % 60);
+        (1..=WEEK_MINUTES)
+            .map(|m| minute + Duration::from_secs(m * 60))
+            .find(|t| self.allows(*t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UpdatePolicy, UpdateWindow, WindowMode};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn window_parses_ok() {
+        let actual: UpdateWindow = "Mon-Wed+Sat 22:00-06:30".parse().unwrap();
+        let expected = UpdateWindow {
+            days: [true, true, true, false, false, true, false],
+            start: 22 * 60,
+            end: 6 * 60 + 30,
+        };
+        assert_eq!(expected, actual);
+        assert!("Foo 10:00-11:00".parse::<UpdateWindow>().is_err());
+        assert!("10:00-10:00".parse::<UpdateWindow>().is_err());
+        assert!("25:00-10:00".parse::<UpdateWindow>().is_err());
+
+        let actual: UpdateWindow = "saturday+Sun 08:00-09:00".parse().unwrap();
+        assert_eq!([false, false, false, false, false, true, true], actual.days);
+        assert!("Monster 10:00-11:00".parse::<UpdateWindow>().is_err());
+        assert!("Mo 10:00-11:00".parse::<UpdateWindow>().is_err());
+        assert!("Mon Tue 10:00-11:00".parse::<UpdateWindow>().is_err());
+    }
+
+    #[test]
+    fn window_crossing_midnight_contains_next_morning() {
+        let window: UpdateWindow = "Fri 22:00-06:00".parse().unwrap();
+        assert!(window.contains(4, 23 * 60));
+        assert!(window.contains(5, 5 * 60));
+        assert!(!window.contains(5, 23 * 60));
+        assert!(!window.contains(4, 5 * 60));
+    }
+
+    #[test]
+    fn policy_allows_by_mode() {
+        // 1970-01-05 was a Monday, 03:00 UTC.
+        let monday_3am = UNIX_EPOCH + Duration::from_secs(4 * 86400 + 3 * 3600);
+        let allow = UpdatePolicy {
+            mode: WindowMode::Allow,
+            windows: vec!["Mon 02:00-04:00".parse().unwrap()],
+        };
+        assert!(allow.allows(monday_3am));
+        let deny = UpdatePolicy {
+            mode: WindowMode::Deny,
+            windows: vec!["Mon 02:00-04:00".parse().unwrap()],
+        };
+        assert!(!deny.allows(monday_3am));
+    }
+
+    #[test]
+    fn policy_finds_next_allowed_minute() {
+        // 1970-01-05 was a Monday.
+        let monday = UNIX_EPOCH + Duration::from_secs(4 * 86400);
+        let allow = UpdatePolicy {
+            mode: WindowMode::Allow,
+            windows: vec!["Tue 02:00-04:00".parse().unwrap()],
+        };
+        let at = |secs| monday + Duration::from_secs(secs);
+        assert_eq!(
+            Some(at(86400 + 2 * 3600)),
+            allow.next_allowed(at(3 * 3600 + 30))
+        );
+        assert_eq!(
+            Some(at(86400 + 3 * 3600)),
+            allow.next_allowed(at(86400 + 3 * 3600))
+        );
+        let never = UpdatePolicy {
+            mode: WindowMode::Deny,
+            windows: vec!["00:00-24:00".parse().unwrap()],
+        };
+        assert_eq!(None, never.next_allowed(monday));
+    }
+}
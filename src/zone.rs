@@ -0,0 +1,732 @@
+use log::{debug, error, info, warn};
+use std::boxed::Box;
+use std::error::Error;
+use std::fs;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crate::gandi_client::{GandiClient, GandiRRSet};
+use crate::ipv6_prefix::Ipv6Prefix;
+use crate::state::unix_time;
+use crate::zonefile;
+
+// How the values of address rrsets are updated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateMode {
+    // Replace all values by the public IP address.
+    Replace,
+    // Replace only the previously published address, see
+    // `gandi_client::merge_values`.
+    Merge,
+}
+
+impl FromStr for UpdateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "replace" => Ok(UpdateMode::Replace),
+            "merge" => Ok(UpdateMode::Merge),
+            other => Err(format!(
+                "Unknown update mode '{}', expected replace or merge",
+                other
+            )),
+        }
+    }
+}
+
+// How the current values of an rrset are determined before an update.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckMode {
+    // Query the authoritative name servers.
+    Dns,
+    // Do not check, always update. Gandi accepts unchanged rrsets.
+    None,
+    // Get the rrset from the Gandi API, which reflects an update at once
+    // and also tells the TTL.
+    Api,
+}
+
+impl FromStr for CheckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "dns" => Ok(CheckMode::Dns),
+            "none" => Ok(CheckMode::None),
+            "api" => Ok(CheckMode::Api),
+            other => Err(format!(
+                "Unknown check mode '{}', expected dns, none or api",
+                other
+            )),
+        }
+    }
+}
+
+// Whether an rrset with the sorted `current` values needs an update to the
+// sorted `desired` ones. Unchecked rrsets are always updated.
+pub fn needs_update(
+    check_mode: CheckMode,
+    current: &[String],
+    desired: &[String],
+    ttl_differs: bool,
+) -> bool {
+    check_mode == CheckMode::None || current != desired || ttl_differs
+}
+
+// Whether creating a missing address rrset is refused, as a missing rrset
+// more likely means a typo in the configuration. Unchecked rrsets are not
+// known to be missing.
+pub fn refuses_create(current: &[String], check_mode: CheckMode, allow_create: bool) -> bool {
+    current.is_empty() && check_mode != CheckMode::None && !allow_create
+}
+
+// Whether replacing the `current` values of an address rrset by a single
+// address is refused, as it would drop the values of other hosts.
+pub fn refuses_replace(current: &[String], update_mode: UpdateMode, force_replace: bool) -> bool {
+    update_mode == UpdateMode::Replace && current.len() > 1 && !force_replace
+}
+
+// The rrset with its TTL lowered to `ttl`, keeping its values, while a
+// change of it is pending. None if it is missing or its TTL already is as
+// low.
+pub fn prelowered(rrset: Option<GandiRRSet>, ttl: u64) -> Option<GandiRRSet> {
+    rrset
+        .filter(|rrset| rrset.ttl > ttl)
+        .map(|rrset| GandiRRSet { ttl, ..rrset })
+}
+
+// Lowers the TTL of the rrset while a change of it is pending outside of
+// the update windows, so that resolvers drop the old values soon after the
+// change is applied, which restores `DOMAIN_TTL`.
+pub async fn prelower_ttl(
+    gandi: &GandiClient,
+    domain: &str,
+    name: &str,
+    rr_type: &str,
+    ttl: u64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current = gandi.get_record(domain, name, rr_type).await?;
+    if let Some(rrset) = prelowered(current, ttl) {
+        info!(
+            "Lowering the TTL of record {} {} of domain {} to {} while its change is pending",
+            name, rr_type, domain, ttl
+        );
+        gandi
+            .update_record(
+                domain,
+                name,
+                rr_type,
+                rrset.values,
+                Duration::from_secs(ttl).into(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+// Unix time of a backup file name `<domain>-<unix time>.json`, None for
+// files of other domains or no backups at all.
+fn backup_time(file_name: &str, domain: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(domain)?
+        .strip_prefix('-')?
+        .strip_suffix(".json")?
+        .parse()
+        .ok()
+}
+
+// Writes all rrsets of the domain as JSON to a new file in `dir` and
+// removes the oldest backups of the domain beyond `retention`.
+pub async fn backup(
+    gandi: &GandiClient,
+    domain: &str,
+    dir: &Path,
+    retention: usize,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let rrsets = gandi.list_records(domain).await?;
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}-{}.json", domain, unix_time(SystemTime::now())));
+    fs::write(&path, serde_json::to_string_pretty(&rrsets)?)?;
+    info!(
+        "Saved backup of {} rrsets of domain {} to {}",
+        rrsets.len(),
+        domain,
+        path.display()
+    );
+
+    let mut backups: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let time = backup_time(entry.file_name().to_str()?, domain)?;
+            Some((time, entry.path()))
+        })
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(retention);
+    for (_, old) in backups.into_iter().take(excess) {
+        debug!("Removing old backup {}", old.display());
+        fs::remove_file(old)?;
+    }
+
+    Ok(path)
+}
+
+// Rewrites the values of all AAAA rrsets of the zone within `old` to
+// `new`, keeping host suffixes. A failing rrset does not prevent the
+// remaining ones from being rewritten.
+pub async fn rewrite_prefix(
+    gandi: &GandiClient,
+    domain: &str,
+    old: &Ipv6Prefix,
+    new: &Ipv6Prefix,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rrsets = gandi.list_records(domain).await?;
+    let mut failed = 0;
+
+    for rrset in rrsets
+        .iter()
+        .filter(|r| r.r#type.as_deref() == Some("AAAA"))
+    {
+        let name = match &rrset.name {
+            Some(name) => name,
+            None => continue,
+        };
+        let values: Vec<String> = rrset
+            .values
+            .iter()
+            .map(|v| match v.parse::<Ipv6Addr>() {
+                Ok(addr) if old.contains(&addr) => new.apply(&addr).to_string(),
+                _ => v.clone(),
+            })
+            .collect();
+        if values == rrset.values {
+            continue;
+        }
+
+        info!(
+            "Rewriting AAAA record {} of domain {}: {} -> {}",
+            name,
+            domain,
+            rrset.values.join(" "),
+            values.join(" ")
+        );
+        let result = gandi
+            .update_record(
+                domain,
+                name,
+                "AAAA",
+                values,
+                Duration::from_secs(rrset.ttl).into(),
+            )
+            .await;
+        if let Err(e) = result {
+            error!("Rewriting AAAA record {} failed: {}", name, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(From::from(format!(
+            "Rewriting {} AAAA record(s) failed",
+            failed
+        )));
+    }
+    Ok(())
+}
+
+// Key of an rrset within a zone.
+fn rrset_key(rrset: &GandiRRSet) -> (&str, &str) {
+    (
+        rrset.name.as_deref().unwrap_or_default(),
+        rrset.r#type.as_deref().unwrap_or_default(),
+    )
+}
+
+// Selects rrsets by `NAME` (all types) or `NAME/TYPE`, everything if empty.
+fn is_selected(rrset: &GandiRRSet, selection: &[String]) -> bool {
+    let (name, rr_type) = rrset_key(rrset);
+    selection.is_empty()
+        || selection.iter().any(|s| match s.split_once('/') {
+            Some((n, t)) => n == name && t.eq_ignore_ascii_case(rr_type),
+            None => s == name,
+        })
+}
+
+// Lines of the selected rrsets in zone file syntax, one per value, sorted
+// by name and type.
+pub fn zone_lines(rrsets: &[GandiRRSet], selection: &[String]) -> Vec<String> {
+    let mut selected: Vec<&GandiRRSet> = rrsets
+        .iter()
+        .filter(|r| is_selected(r, selection))
+        .collect();
+    selected.sort_by_key(|r| rrset_key(r));
+    selected
+        .into_iter()
+        .flat_map(|r| {
+            let (name, rr_type) = rrset_key(r);
+            r.values
+                .iter()
+                .map(move |v| format!("{} {} IN {} {}", name, r.ttl, rr_type, v))
+        })
+        .collect()
+}
+
+// The zone with the rrsets of `updates` replaced or added, for updating
+// several rrsets in one request.
+pub fn with_rrsets(current: &[GandiRRSet], updates: &[GandiRRSet]) -> Vec<GandiRRSet> {
+    let mut zone: Vec<GandiRRSet> = current
+        .iter()
+        .filter(|r| !updates.iter().any(|u| rrset_key(u) == rrset_key(r)))
+        .cloned()
+        .collect();
+    zone.extend_from_slice(updates);
+    zone
+}
+
+// A BIND-style zone file of the rrsets, with names relative to the
+// domain.
+pub fn zone_file(domain: &str, rrsets: &[GandiRRSet]) -> String {
+    let mut file = format!("$ORIGIN {}.\n", domain);
+    for line in zone_lines(rrsets, &[]) {
+        file.push_str(&line);
+        file.push('\n');
+    }
+    file
+}
+
+// Writes all rrsets of the domain to a zone file.
+pub async fn export(
+    gandi: &GandiClient,
+    domain: &str,
+    file: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rrsets = gandi.list_records(domain).await?;
+    fs::write(file, zone_file(domain, &rrsets))?;
+    info!(
+        "Exported {} rrsets of domain {} to {}",
+        rrsets.len(),
+        domain,
+        file.display()
+    );
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Create(GandiRRSet),
+    Update { from: GandiRRSet, to: GandiRRSet },
+    Delete(GandiRRSet),
+}
+
+impl Change {
+    fn describe(&self) -> String {
+        let describe = |r: &GandiRRSet| {
+            let (name, rr_type) = rrset_key(r);
+            format!("{} {} {} {}", name, r.ttl, rr_type, r.values.join(" "))
+        };
+        match self {
+            Change::Create(r) => format!("create {}", describe(r)),
+            Change::Update { from, to } => {
+                format!("update {} (was {})", describe(to), describe(from))
+            }
+            Change::Delete(r) => format!("delete {}", describe(r)),
+        }
+    }
+}
+
+// Changes required to turn the `current` zone into the `target` zone,
+// restricted to the selected rrsets.
+pub fn plan(current: &[GandiRRSet], target: &[GandiRRSet], selection: &[String]) -> Vec<Change> {
+    let mut changes = vec![];
+    for to in target.iter().filter(|r| is_selected(r, selection)) {
+        match current.iter().find(|r| rrset_key(r) == rrset_key(to)) {
+            Some(from) if from.ttl == to.ttl && from.values == to.values => {}
+            Some(from) => changes.push(Change::Update {
+                from: from.clone(),
+                to: to.clone(),
+            }),
+            None => changes.push(Change::Create(to.clone())),
+        }
+    }
+    for from in current.iter().filter(|r| is_selected(r, selection)) {
+        if !target.iter().any(|r| rrset_key(r) == rrset_key(from)) {
+            changes.push(Change::Delete(from.clone()));
+        }
+    }
+    changes
+}
+
+// Restores the selected rrsets of a backup file. Without `apply` the
+// planned changes are only shown.
+pub async fn restore(
+    gandi: &GandiClient,
+    domain: &str,
+    file: &Path,
+    selection: &[String],
+    apply: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let file_name = file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    if backup_time(file_name, domain).is_none() {
+        return Err(From::from(format!(
+            "{} is no backup of domain {}",
+            file.display(),
+            domain
+        )));
+    }
+    let target: Vec<GandiRRSet> = serde_json::from_str(&fs::read_to_string(file)?)?;
+    let current = gandi.list_records(domain).await?;
+    let changes = plan(&current, &target, selection);
+
+    if changes.is_empty() {
+        info!("Domain {} already matches {}", domain, file.display());
+        return Ok(());
+    }
+    apply_plan(gandi, domain, &changes, apply).await
+}
+
+// Restores the selected rrsets of a snapshot taken by Gandi, see
+// `SNAPSHOT_BEFORE_UPDATE`. Without `apply` the planned changes are only
+// shown.
+pub async fn restore_snapshot(
+    gandi: &GandiClient,
+    domain: &str,
+    id: &str,
+    selection: &[String],
+    apply: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let snapshot = gandi.get_snapshot(domain, id).await?;
+    let current = gandi.list_records(domain).await?;
+    let changes = plan(&current, &snapshot.zone_data, selection);
+
+    if changes.is_empty() {
+        info!("Domain {} already matches snapshot {}", domain, id);
+        return Ok(());
+    }
+    apply_plan(gandi, domain, &changes, apply).await
+}
+
+// Creates and updates the selected rrsets of a zone file, other rrsets of
+// the zone are kept. The SOA record is managed by Gandi and skipped.
+// Without `apply` the planned changes are only shown.
+pub async fn import(
+    gandi: &GandiClient,
+    domain: &str,
+    file: &Path,
+    selection: &[String],
+    apply: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let text = fs::read_to_string(file)?;
+    let target: Vec<GandiRRSet> = zonefile::parse(&text, domain)
+        .map_err(|e| format!("Invalid zone file {}: {}", file.display(), e))?
+        .into_iter()
+        .filter(|r| {
+            let soa = r.r#type.as_deref() == Some("SOA");
+            if soa {
+                warn!(
+                    "Skipping SOA record of {}, it is managed by Gandi",
+                    file.display()
+                );
+            }
+            !soa
+        })
+        .collect();
+    let current = gandi.list_records(domain).await?;
+    let changes: Vec<Change> = plan(&current, &target, selection)
+        .into_iter()
+        .filter(|c| !matches!(c, Change::Delete(_)))
+        .collect();
+
+    if changes.is_empty() {
+        info!("Domain {} already contains {}", domain, file.display());
+        return Ok(());
+    }
+    apply_plan(gandi, domain, &changes, apply).await
+}
+
+// Changes to set the TTL of the selected rrsets, keeping their values.
+fn ttl_plan(current: &[GandiRRSet], selection: &[String], ttl: u64) -> Vec<Change> {
+    let target: Vec<GandiRRSet> = current
+        .iter()
+        .filter(|r| is_selected(r, selection))
+        .map(|r| GandiRRSet { ttl, ..r.clone() })
+        .collect();
+    plan(current, &target, selection)
+}
+
+// Sets the TTL of the selected rrsets, for example lowered before a planned
+// move. Without `apply` the planned changes are only shown.
+pub async fn set_ttl(
+    gandi: &GandiClient,
+    domain: &str,
+    selection: &[String],
+    ttl: u64,
+    apply: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current = gandi.list_records(domain).await?;
+    let changes = ttl_plan(&current, selection, ttl);
+
+    if changes.is_empty() {
+        info!("TTL of the rrsets of domain {} already is {}", domain, ttl);
+        return Ok(());
+    }
+    apply_plan(gandi, domain, &changes, apply).await
+}
+
+// Deletes the selected rrsets, for example of decommissioned hosts. Without
+// `apply` the rrsets to delete are only shown.
+pub async fn delete(
+    gandi: &GandiClient,
+    domain: &str,
+    selection: &[String],
+    apply: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current = gandi.list_records(domain).await?;
+    let changes = plan(&current, &[], selection);
+
+    if changes.is_empty() {
+        info!("Domain {} has no rrsets of {}", domain, selection.join(" "));
+        return Ok(());
+    }
+    apply_plan(gandi, domain, &changes, apply).await
+}
+
+// Shows the planned changes and applies them with `apply`. A failing
+// change does not prevent the remaining ones from being applied.
+async fn apply_plan(
+    gandi: &GandiClient,
+    domain: &str,
+    changes: &[Change],
+    apply: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for change in changes {
+        info!("Plan for domain {}: {}", domain, change.describe());
+    }
+    if !apply {
+        info!("Nothing changed, run again with --yes to apply the plan");
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for change in changes {
+        let result = match change {
+            Change::Create(r) | Change::Update { to: r, .. } => {
+                let (name, rr_type) = rrset_key(r);
+                gandi
+                    .update_record(
+                        domain,
+                        name,
+                        rr_type,
+                        r.values.clone(),
+                        Duration::from_secs(r.ttl).into(),
+                    )
+                    .await
+            }
+            Change::Delete(r) => {
+                let (name, rr_type) = rrset_key(r);
+                gandi.delete_record(domain, name, rr_type).await
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to {}: {}", change.describe(), e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(From::from(format!("Changing {} rrset(s) failed", failed)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        backup_time, needs_update, plan, prelowered, refuses_create, refuses_replace, ttl_plan,
+        with_rrsets, zone_file, zone_lines, Change, CheckMode, UpdateMode,
+    };
+    use crate::gandi_client::GandiRRSet;
+
+    fn rrset(name: &str, rr_type: &str, value: &str) -> GandiRRSet {
+        GandiRRSet {
+            r#type: Some(rr_type.into()),
+            ttl: 300,
+            name: Some(name.into()),
+            values: vec![value.into()],
+        }
+    }
+
+    #[test]
+    fn unchecked_rrsets_need_update() {
+        let ip = vec!["198.51.100.7".to_string()];
+        assert!(!needs_update(CheckMode::Dns, &ip, &ip, false));
+        assert!(needs_update(CheckMode::Dns, &ip, &ip, true));
+        assert!(needs_update(CheckMode::Api, &[], &ip, false));
+        assert!(needs_update(CheckMode::None, &[], &[], false));
+        assert_eq!(Ok(CheckMode::None), "none".parse());
+        assert_eq!(Ok(CheckMode::Api), " api".parse());
+        assert!("gandi".parse::<CheckMode>().is_err());
+    }
+
+    #[test]
+    fn creation_of_missing_rrsets_is_refused() {
+        let ip = vec!["198.51.100.7".to_string()];
+        assert!(refuses_create(&[], CheckMode::Dns, false));
+        assert!(refuses_create(&[], CheckMode::Api, false));
+        assert!(!refuses_create(&[], CheckMode::Dns, true));
+        assert!(!refuses_create(&[], CheckMode::None, false));
+        assert!(!refuses_create(&ip, CheckMode::Dns, false));
+    }
+
+    #[test]
+    fn pending_rrsets_are_prelowered() {
+        let high = GandiRRSet {
+            ttl: 3600,
+            ..rrset("home", "A", "198.51.100.7")
+        };
+        let lowered = prelowered(Some(high), 300).unwrap();
+        assert_eq!(300, lowered.ttl);
+        assert_eq!(vec!["198.51.100.7".to_string()], lowered.values);
+        assert_eq!(
+            None,
+            prelowered(Some(rrset("home", "A", "198.51.100.7")), 300)
+        );
+        assert_eq!(None, prelowered(None, 300));
+    }
+
+    #[test]
+    fn replace_of_several_values_is_refused() {
+        let one = vec!["198.51.100.7".to_string()];
+        let two = vec!["198.51.100.7".to_string(), "198.51.100.8".to_string()];
+        assert!(refuses_replace(&two, UpdateMode::Replace, false));
+        assert!(!refuses_replace(&two, UpdateMode::Replace, true));
+        assert!(!refuses_replace(&two, UpdateMode::Merge, false));
+        assert!(!refuses_replace(&one, UpdateMode::Replace, false));
+        assert!(!refuses_replace(&[], UpdateMode::Replace, false));
+    }
+
+    #[test]
+    fn plan_restores_zone() {
+        let current = vec![
+            rrset("a", "A", "192.0.2.1"),
+            rrset("b", "A", "192.0.2.2"),
+            rrset("c", "A", "192.0.2.3"),
+        ];
+        let target = vec![
+            rrset("a", "A", "192.0.2.1"),
+            rrset("b", "A", "192.0.2.9"),
+            rrset("d", "A", "192.0.2.4"),
+        ];
+        let expected = vec![
+            Change::Update {
+                from: rrset("b", "A", "192.0.2.2"),
+                to: rrset("b", "A", "192.0.2.9"),
+            },
+            Change::Create(rrset("d", "A", "192.0.2.4")),
+            Change::Delete(rrset("c", "A", "192.0.2.3")),
+        ];
+        assert_eq!(expected, plan(&current, &target, &[]));
+    }
+
+    #[test]
+    fn plan_restores_selection() {
+        let current = vec![rrset("a", "A", "192.0.2.1"), rrset("a", "TXT", "\"x\"")];
+        let target = vec![rrset("a", "A", "192.0.2.9"), rrset("a", "TXT", "\"y\"")];
+        let actual = plan(&current, &target, &["a/txt".to_string()]);
+        let expected = vec![Change::Update {
+            from: rrset("a", "TXT", "\"x\""),
+            to: rrset("a", "TXT", "\"y\""),
+        }];
+        assert_eq!(expected, actual);
+        assert_eq!(2, plan(&current, &target, &["a".to_string()]).len());
+    }
+
+    #[test]
+    fn plan_deletes_selection() {
+        let current = vec![
+            rrset("a", "A", "192.0.2.1"),
+            rrset("a", "TXT", "\"x\""),
+            rrset("b", "A", "192.0.2.2"),
+        ];
+        let actual = plan(&current, &[], &["a".to_string()]);
+        let expected = vec![
+            Change::Delete(rrset("a", "A", "192.0.2.1")),
+            Change::Delete(rrset("a", "TXT", "\"x\"")),
+        ];
+        assert_eq!(expected, actual);
+        assert!(plan(&current, &[], &["c".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn ttl_plan_keeps_values() {
+        let current = vec![rrset("a", "A", "192.0.2.1"), rrset("b", "A", "192.0.2.2")];
+        let mut to = rrset("a", "A", "192.0.2.1");
+        to.ttl = 3600;
+        let expected = vec![Change::Update {
+            from: rrset("a", "A", "192.0.2.1"),
+            to,
+        }];
+        assert_eq!(expected, ttl_plan(&current, &["a/A".to_string()], 3600));
+        assert!(ttl_plan(&current, &["a/A".to_string()], 300).is_empty());
+    }
+
+    #[test]
+    fn with_rrsets_replaces_and_adds() {
+        let current = vec![rrset("a", "A", "192.0.2.1"), rrset("a", "TXT", "\"x\"")];
+        let updates = vec![rrset("a", "A", "192.0.2.9"), rrset("b", "A", "192.0.2.2")];
+        let expected = vec![
+            rrset("a", "TXT", "\"x\""),
+            rrset("a", "A", "192.0.2.9"),
+            rrset("b", "A", "192.0.2.2"),
+        ];
+        assert_eq!(expected, with_rrsets(&current, &updates));
+    }
+
+    #[test]
+    fn zone_lines_are_sorted() {
+        let mut mx = rrset("@", "MX", "10 home");
+        mx.values.push("20 mx.example.net.".into());
+        let rrsets = vec![rrset("home", "A", "192.0.2.1"), mx];
+        let expected = vec![
+            "@ 300 IN MX 10 home",
+            "@ 300 IN MX 20 mx.example.net.",
+            "home 300 IN A 192.0.2.1",
+        ];
+        assert_eq!(expected, zone_lines(&rrsets, &[]));
+        assert_eq!(
+            vec!["home 300 IN A 192.0.2.1"],
+            zone_lines(&rrsets, &["home/a".to_string()])
+        );
+    }
+
+    #[test]
+    fn zone_file_has_origin() {
+        let rrsets = vec![rrset("home", "A", "192.0.2.1"), rrset("@", "TXT", "\"x\"")];
+        assert_eq!(
+            "$ORIGIN example.com.\n@ 300 IN TXT \"x\"\nhome 300 IN A 192.0.2.1\n",
+            zone_file("example.com", &rrsets)
+        );
+    }
+
+    #[test]
+    fn backup_time_of_file_name() {
+        assert_eq!(
+            Some(1600000000),
+            backup_time("example.com-1600000000.json", "example.com")
+        );
+        assert_eq!(
+            None,
+            backup_time("example.org-1600000000.json", "example.com")
+        );
+        assert_eq!(
+            None,
+            backup_time("sub.example.com-1600000000.json", "example.com")
+        );
+        assert_eq!(None, backup_time("example.com-x.json", "example.com"));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::gandi_client::GandiRRSet;
+
+// TTL of records without TTL in a zone file without `$TTL`, Gandi's
+// default.
+static DEFAULT_TTL: u64 = 10800;
+
+// The first whitespace separated token and the rest of the text.
+fn next_token(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    if text.is_empty() {
+        return None;
+    }
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    Some((&text[..end], &text[end..]))
+}
+
+// The line without its comment, which starts at a ';' outside of quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// The name relative to the domain, `@` for the apex. Names without trailing
+// dot are relative to the origin.
+fn relative_name(name: &str, origin: &str, domain: &str) -> Result<String, String> {
+    let absolute = if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') {
+        name.to_lowercase()
+    } else {
+        format!("{}.{}", name, origin).to_lowercase()
+    };
+    let apex = format!("{}.", domain);
+    if absolute == apex {
+        return Ok("@".into());
+    }
+    absolute
+        .strip_suffix(&format!(".{}", apex))
+        .map(|n| n.to_string())
+        .ok_or_else(|| format!("Name {} is not within domain {}", absolute, domain))
+}
+
+// Parses a BIND-style zone file of the domain into rrsets with relative
+// names, in the order of their first record. Supported are `$ORIGIN`,
+// `$TTL`, comments and records of one line each, whose name may be omitted
+// to repeat the previous one.
+pub fn parse(text: &str, domain: &str) -> Result<Vec<GandiRRSet>, String> {
+    let mut origin = format!("{}.", domain);
+    let mut default_ttl = DEFAULT_TTL;
+    let mut previous_name: Option<String> = None;
+    let mut rrsets: Vec<GandiRRSet> = vec![];
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |msg: &str| format!("Line {}: {}", number + 1, msg);
+        let line = strip_comment(line).trim_end();
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.contains('(') {
+            return Err(error("Records spanning several lines are not supported"));
+        }
+        if let Some(directive) = line.strip_prefix('$') {
+            let (key, value) = next_token(directive).unwrap_or_default();
+            let value = value.trim();
+            match key.to_uppercase().as_str() {
+                "ORIGIN" if value.ends_with('.') => origin = value.to_lowercase(),
+                "ORIGIN" => return Err(error("$ORIGIN must end with '.'")),
+                "TTL" => {
+                    default_ttl = value
+                        .parse()
+                        .map_err(|_| error(&format!("Invalid $TTL '{}'", value)))?
+                }
+                _ => return Err(error(&format!("Unsupported directive ${}", directive))),
+            }
+            continue;
+        }
+
+        // A line starting with whitespace continues the previous name.
+        let (name, mut rest) = if line.starts_with(char::is_whitespace) {
+            let name = previous_name
+                .clone()
+                .ok_or_else(|| error("Record without name"))?;
+            (name, line)
+        } else {
+            let (name, rest) = next_token(line).unwrap_or_default();
+            let name = relative_name(name, &origin, domain).map_err(|e| error(&e))?;
+            (name, rest)
+        };
+        previous_name = Some(name.clone());
+
+        let mut ttl = None;
+        let rr_type = loop {
+            let (token, after) = next_token(rest).ok_or_else(|| error("Record without type"))?;
+            rest = after;
+            if token.eq_ignore_ascii_case("IN") {
+                continue;
+            }
+            match token.parse::<u64>() {
+                Ok(secs) => ttl = Some(secs),
+                Err(_) => break token.to_uppercase(),
+            }
+        };
+        if !rr_type.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(error(&format!("Invalid record type '{}'", rr_type)));
+        }
+        let value = rest.trim();
+        if value.is_empty() {
+            return Err(error("Record without value"));
+        }
+        // TXT values keep their quoting and spacing.
+        let value = if rr_type == "TXT" {
+            value.to_string()
+        } else {
+            value.split_whitespace().collect::<Vec<_>>().join(" ")
+        };
+
+        let key = (Some(name.as_str()), Some(rr_type.as_str()));
+        match rrsets
+            .iter_mut()
+            .find(|r| (r.name.as_deref(), r.r#type.as_deref()) == key)
+        {
+            Some(rrset) => rrset.values.push(value),
+            None => rrsets.push(GandiRRSet {
+                r#type: Some(rr_type),
+                ttl: ttl.unwrap_or(default_ttl),
+                name: Some(name),
+                values: vec![value],
+            }),
+        }
+    }
+    Ok(rrsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn zone_file_parses_ok() {
+        let text = r#"
+$ORIGIN example.com.
+$TTL 600
+@ 300 IN MX 10 home ; primary
+      IN MX 20 mx.example.net.
+home 300 IN A 192.0.2.1
+home.example.com. IN TXT "v=spf1 -all; really"
+$ORIGIN office.example.com.
+nas IN AAAA 2001:db8::7
+"#;
+        let rrsets = parse(text, "example.com").unwrap();
+        let summary: Vec<(String, u64, String, Vec<String>)> = rrsets
+            .into_iter()
+            .map(|r| (r.name.unwrap(), r.ttl, r.r#type.unwrap(), r.values))
+            .collect();
+        let expected = vec![
+            (
+                "@".to_string(),
+                300,
+                "MX".to_string(),
+                vec!["10 home".to_string(), "20 mx.example.net.".to_string()],
+            ),
+            ("home".into(), 300, "A".into(), vec!["192.0.2.1".into()]),
+            (
+                "home".into(),
+                600,
+                "TXT".into(),
+                vec!["\"v=spf1 -all; really\"".into()],
+            ),
+            (
+                "nas.office".into(),
+                600,
+                "AAAA".into(),
+                vec!["2001:db8::7".into()],
+            ),
+        ];
+        assert_eq!(expected, summary);
+    }
+
+    #[test]
+    fn zone_file_errors_name_the_line() {
+        assert_eq!(
+            Err("Line 2: Name www.example.org. is not within domain example.com".to_string()),
+            parse(
+                "home A 192.0.2.1\nwww.example.org. A 192.0.2.2",
+                "example.com"
+            )
+        );
+        assert!(parse("@ SOA ns1 hostmaster (\n 1 2 3 4 5 )", "example.com").is_err());
+        assert!(parse("home 300 IN", "example.com").is_err());
+        assert!(parse("  A 192.0.2.1", "example.com").is_err());
+        assert!(parse("$INCLUDE other.zone", "example.com").is_err());
+    }
+}